@@ -0,0 +1,60 @@
+//! Print the persisted state of a raft peer.
+//!
+//! usage: `raft-dump [--json] [--kv] <raft state file> [<snapshot file>]`
+//! - the raft state file holds the bytes of `Persister::raft_state`,
+//!   and the snapshot file holds the bytes of `Persister::snapshot`.
+//! - `--json` prints a JSON object instead of lines.
+//! - `--kv` decodes the entries and snapshot chunks as the commands and snapshots of kvraft.
+//!
+//! The entries of a peer with a `SegmentedLog` are in the segments of its directory, which aren't read.
+use std::fs;
+use std::process;
+
+use raft::kvraft::dump::KvPayload;
+use raft::raft::dump::{dump, Format, Payload, Raw};
+
+const USAGE: &str = "usage: raft-dump [--json] [--kv] <raft state file> [<snapshot file>]
+  --json  print a JSON object instead of lines.
+  --kv    decode the entries and snapshot chunks as the commands and snapshots of kvraft.
+Only the entries saved in the raft state are printed, the segments of a SegmentedLog aren't read.";
+
+fn main() {
+    let mut format = Format::Text;
+    let mut payload: &dyn Payload = &Raw;
+    let mut files = vec![];
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => format = Format::Json,
+            "--kv" => payload = &KvPayload,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            flag if flag.starts_with("--") => {
+                eprintln!("unknown option {}\n{}", flag, USAGE);
+                process::exit(2);
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() || files.len() > 2 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let read = |path: &String| {
+        fs::read(path).unwrap_or_else(|e| {
+            eprintln!("failed to read {}: {}", path, e);
+            process::exit(1);
+        })
+    };
+    let raft_state = read(&files[0]);
+    let snapshot = files.get(1).map(read).unwrap_or_default();
+    match dump(&raft_state, &snapshot, payload, format) {
+        Ok(output) => print!("{}", output),
+        Err(e) => {
+            eprintln!("failed to decode the persisted state: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -1,16 +1,14 @@
 use std::cell::Cell;
 use std::fmt;
-use std::sync::mpsc::{channel, Receiver};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
 use std::time::Duration;
 
 use futures::Future;
-use rayon::{ThreadPool, ThreadPoolBuilder};
 use uuid::Uuid;
 
 use labrpc::Error;
 
 use crate::proto::kvraftpb::*;
-use crate::select_idx;
 
 use super::server::err_codes::KVERR_TIMEOUT;
 
@@ -26,7 +24,6 @@ pub struct Clerk {
     // You will have to modify this struct.
     leader: Cell<Option<usize>>,
     last_leader: Cell<Option<usize>>,
-    rpc_execution_pool: ThreadPool,
 }
 
 impl Op {
@@ -60,16 +57,11 @@ impl Clerk {
     pub fn new(name: String, servers: Vec<KvClient>) -> Clerk {
         // You'll have to add code here.
         // Clerk { name, servers }
-        let pool = ThreadPoolBuilder::new()
-            .num_threads(Ord::max(1, servers.len() / 2))
-            .build()
-            .unwrap();
         Clerk {
             name,
             servers,
             leader: Cell::new(None),
             last_leader: Cell::new(None),
-            rpc_execution_pool: pool,
         }
     }
 
@@ -81,20 +73,21 @@ impl Clerk {
         self.leader.set(None)
     }
 
-    /// Run a future at the thread pool.
+    /// Run the rpc `f` sent to server `i` on the executor of its client,
+    /// the result is sent to `sx` along with `i` once it's done.
     fn run_async<I, E>(
         &self,
+        i: usize,
         f: impl Future<Item = I, Error = E> + Send + 'static,
-    ) -> Receiver<Result<I, E>>
-    where
+        sx: Sender<(usize, Result<I, E>)>,
+    ) where
         I: Send + 'static,
         E: Send + 'static,
     {
-        let (sx, rx) = channel();
-        self.rpc_execution_pool.spawn(move || {
-            let _ = sx.send(f.wait());
-        });
-        rx
+        self.servers[i].spawn(f.then(move |result| {
+            let _ = sx.send((i, result));
+            Ok(())
+        }));
     }
 
     fn new_id() -> Vec<u8> {
@@ -110,20 +103,22 @@ impl Clerk {
     /// if current leader is changed or absent, return `None` and set `self.leader` to `None`.
     fn try_send_to_current_leader<R>(
         &self,
-        send: impl Fn(&KvClient) -> Receiver<R>,
+        send: impl Fn(usize, Sender<(usize, R)>),
         is_leader: impl Fn(&R) -> bool,
         timeout: Duration,
     ) -> Option<R> {
         if let Some(leader) = self.leader.get() {
             debug!("{}: we have leader {}, sending~", self.name, leader);
-            let message = send(&self.servers[leader]).recv_timeout(timeout);
+            let (sx, rx) = channel();
+            send(leader, sx);
+            let message = rx.recv_timeout(timeout);
             if message.is_err() {
                 debug!("{}: leader {} is timeout :(", self.name, leader);
                 self.impeach_leader();
                 return None;
             }
 
-            let message = message.unwrap();
+            let (_, message) = message.unwrap();
             return if !is_leader(&message) {
                 // leadership changed.
                 debug!("{}: leader {} is died :(", self.name, leader);
@@ -145,15 +140,18 @@ impl Clerk {
     /// Once success, return the item provided by `send` function.
     fn check_leader_and_send<R: Send + 'static>(
         &self,
-        send: impl Fn(&KvClient) -> Receiver<R>,
+        send: impl Fn(usize, Sender<(usize, R)>),
         is_leader: impl Fn(&R) -> bool,
         timeout: Duration,
     ) -> R {
         debug!("{}: No leader found, but we are seeking ;)", self.name);
         loop {
-            let sent = self.servers.iter().map(|client| send(client));
-            let send_items = select_idx(sent);
-            use std::sync::mpsc::RecvTimeoutError;
+            let (sx, send_items) = channel();
+            for i in 0..self.servers.len() {
+                send(i, sx.clone());
+            }
+            // the channel is disconnected once all servers replied.
+            drop(sx);
             loop {
                 match send_items.recv_timeout(timeout) {
                     Ok((i, result)) => {
@@ -188,7 +186,7 @@ impl Clerk {
     /// the item provided by `send` function.
     fn request<R: Send + 'static>(
         &self,
-        send: impl Fn(&KvClient) -> Receiver<R>,
+        send: impl Fn(usize, Sender<(usize, R)>),
         is_leader: impl Fn(&R) -> bool,
         timeout: Duration,
     ) -> R {
@@ -222,7 +220,7 @@ impl Clerk {
         let id = Uuid::from_slice(args.id.as_slice()).unwrap();
         info!("{}: {} get({:?})", self.name, id, key);
 
-        let send = |client: &KvClient| self.run_async(client.get(&args));
+        let send = |i, sx| self.run_async(i, self.servers[i].get(&args), sx);
         let is_leader = |reply: &Result<GetReply, Error>| match reply {
             Err(_) => false,
             Ok(message) if message.wrong_leader => false,
@@ -271,7 +269,7 @@ impl Clerk {
         let args: PutAppendRequest = op.clone().into_request(self.name.clone());
         let id = Uuid::from_slice(args.id.as_slice()).unwrap();
         info!("{}: {} put_append({:?})", self.name, id, op);
-        let send = |client: &KvClient| self.run_async(client.put_append(&args));
+        let send = |i, sx| self.run_async(i, self.servers[i].put_append(&args), sx);
         let is_leader = |reply: &Result<PutAppendReply, Error>| match reply {
             Err(_) => false,
             Ok(message) if message.wrong_leader => false,
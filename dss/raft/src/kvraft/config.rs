@@ -8,9 +8,8 @@ use labrpc;
 use crate::kvraft::errors::{Error, Result};
 use crate::kvraft::{client, server};
 use crate::proto::kvraftpb::*;
-use crate::proto::raftpb::*;
-use crate::raft;
 use crate::raft::persister::*;
+use crate::raft::testing::Cluster;
 use rand::Rng;
 
 static ID: AtomicUsize = AtomicUsize::new(300_000);
@@ -19,12 +18,6 @@ fn uniqstring() -> String {
     format!("{}", ID.fetch_add(1, Ordering::Relaxed))
 }
 
-struct Servers {
-    kvservers: Vec<Option<server::Node>>,
-    saved: Vec<Arc<SimplePersister>>,
-    endnames: Vec<Vec<String>>,
-}
-
 fn init_logger() {
     use std::sync::Once;
     static LOGGER_INIT: Once = Once::new();
@@ -34,10 +27,10 @@ fn init_logger() {
 pub struct Config {
     pub net: labrpc::Network,
     pub n: usize,
-    servers: Mutex<Servers>,
+    // the kv servers.
+    cluster: Cluster<server::Node>,
     clerks: Mutex<HashMap<String, Vec<String>>>,
     next_client_id: AtomicUsize,
-    maxraftstate: Option<usize>,
 
     // time at which the Config was created.
     start: Instant,
@@ -55,32 +48,24 @@ impl Config {
     pub fn new(n: usize, unreliable: bool, maxraftstate: Option<usize>) -> Config {
         init_logger();
 
-        let servers = Servers {
-            kvservers: vec![None; n],
-            saved: (0..n).map(|_| Arc::new(SimplePersister::new())).collect(),
-            endnames: vec![vec![String::new(); n]; n],
-        };
+        let net = labrpc::Network::new();
+        // create a full set of KV servers.
+        let cluster = Cluster::new(net.clone(), n, move |i, ends, persister| {
+            server::Node::new(server::KvServer::new(ends, i, persister, maxraftstate))
+        });
         let cfg = Config {
             n,
-            net: labrpc::Network::new(),
-            servers: Mutex::new(servers),
+            net,
+            cluster,
             clerks: Mutex::new(HashMap::new()),
             // client ids start 1000 above the highest serverid,
             next_client_id: AtomicUsize::new(n + 1000),
-            maxraftstate,
             start: Instant::now(),
             t0: Mutex::new(Instant::now()),
             rpcs0: AtomicUsize::new(0),
             ops: AtomicUsize::new(0),
         };
 
-        // create a full set of KV servers.
-        for i in 0..cfg.n {
-            cfg.start_server(i);
-        }
-
-        cfg.connect_all();
-
         cfg.net.set_reliable(!unreliable);
 
         cfg
@@ -101,66 +86,19 @@ impl Config {
         }
     }
 
+    /// the persister of server i, to inject faults into its writes.
+    pub fn persister(&self, i: usize) -> Arc<FaultyPersister> {
+        self.cluster.persister(i)
+    }
+
     /// Maximum log size across all servers
     pub fn log_size(&self) -> usize {
-        let servers = self.servers.lock().unwrap();
-        let mut logsize = 0;
-        for save in &servers.saved {
-            let n = save.raft_state().len();
-            if n > logsize {
-                logsize = n;
-            }
-        }
-        logsize
+        self.cluster.log_size()
     }
 
     /// Maximum snapshot size across all servers
     pub fn snapshot_size(&self) -> usize {
-        let mut snapshotsize = 0;
-        let servers = self.servers.lock().unwrap();
-        for save in &servers.saved {
-            let n = save.snapshot().len();
-            if n > snapshotsize {
-                snapshotsize = n;
-            }
-        }
-        snapshotsize
-    }
-
-    /// Attach server i to servers listed in to
-    fn connect(&self, i: usize, to: &[usize], servers: &Servers) {
-        debug!("connect peer {} to {:?}", i, to);
-        // outgoing socket files
-        for j in to {
-            let endname = &servers.endnames[i][*j];
-            self.net.enable(endname, true);
-        }
-
-        // incoming socket files
-        for j in to {
-            let endname = &servers.endnames[*j][i];
-            self.net.enable(endname, true);
-        }
-    }
-
-    /// Detach server i from the servers listed in from
-    fn disconnect(&self, i: usize, from: &[usize], servers: &Servers) {
-        debug!("disconnect peer {} from {:?}", i, from);
-        // outgoing socket files
-        for j in from {
-            if !servers.endnames[i].is_empty() {
-                let endname = &servers.endnames[i][*j];
-                self.net.enable(endname, false);
-            }
-        }
-
-        // incoming socket files
-        for j in from {
-            if !servers.endnames[*j].is_empty() {
-                let endname = &servers.endnames[*j][i];
-                self.net.enable(endname, false);
-            }
-        }
+        self.cluster.snapshot_size()
     }
 
     pub fn all(&self) -> Vec<usize> {
@@ -168,24 +106,12 @@ impl Config {
     }
 
     pub fn connect_all(&self) {
-        let servers = self.servers.lock().unwrap();
-        for i in 0..self.n {
-            self.connect(i, &self.all(), &*servers);
-        }
+        self.cluster.connect_all();
     }
 
     /// Sets up 2 partitions with connectivity between servers in each  partition.
     pub fn partition(&self, p1: &[usize], p2: &[usize]) {
-        debug!("partition servers into: {:?} {:?}", p1, p2);
-        let servers = self.servers.lock().unwrap();
-        for i in p1 {
-            self.disconnect(*i, p2, &*servers);
-            self.connect(*i, p1, &*servers);
-        }
-        for i in p2 {
-            self.disconnect(*i, p1, &*servers);
-            self.connect(*i, p2, &*servers);
-        }
+        self.cluster.partition(p1, p2);
     }
 
     // Create a clerk with clerk specific server names.
@@ -232,77 +158,22 @@ impl Config {
 
     /// Shutdown a server by isolating it
     pub fn shutdown_server(&self, i: usize) {
-        let mut servers = self.servers.lock().unwrap();
-        self.disconnect(i, &self.all(), &*servers);
-
-        // disable client connections to the server.
-        // it's important to do this before creating
-        // the new Persister in saved[i], to avoid
-        // the possibility of the server returning a
-        // positive reply to an Append but persisting
-        // the result in the superseded Persister.
-        self.net.delete_server(&format!("{}", i));
-
-        // a fresh persister, in case old instance
-        // continues to update the Persister.
-        // but copy old persister's content so that we always
-        // pass Make() the last persisted state.
-        let p = raft::persister::SimplePersister::new();
-        p.save_state_and_snapshot(servers.saved[i].raft_state(), servers.saved[i].snapshot());
-        servers.saved[i] = Arc::new(p);
-
-        if let Some(kv) = servers.kvservers[i].take() {
-            kv.kill();
-        }
+        self.cluster.crash(i);
     }
 
     /// Start a server i.
     /// If restart servers, first call shutdown_server
     pub fn start_server(&self, i: usize) {
-        // a fresh set of outgoing ClientEnd names.
-        let mut servers = self.servers.lock().unwrap();
-        servers.endnames[i] = (0..self.n).map(|_| uniqstring()).collect();
-
-        // a fresh set of ClientEnds.
-        let mut ends = Vec::with_capacity(self.n);
-        for (j, name) in servers.endnames[i].iter().enumerate() {
-            let cli = self.net.create_client(name.clone());
-            ends.push(RaftClient::new(cli));
-            self.net.connect(name, &format!("{}", j));
-        }
+        self.cluster.start(i);
+    }
 
-        // a fresh persister, so old instance doesn't overwrite
-        // new instance's persisted state.
-        // give the fresh persister a copy of the old persister's
-        // state, so that the spec is that we pass StartKVServer()
-        // the last persisted state.
-        let sp = raft::persister::SimplePersister::new();
-        sp.save_state_and_snapshot(servers.saved[i].raft_state(), servers.saved[i].snapshot());
-        let p = Arc::new(sp);
-        servers.saved[i] = p.clone();
-
-        let kv = server::KvServer::new(ends, i, Box::new(p), self.maxraftstate);
-        let rf_node = kv.rf.clone();
-        let kv_node = server::Node::new(kv);
-        servers.kvservers[i] = Some(kv_node.clone());
-
-        let mut builder = labrpc::ServerBuilder::new(format!("{}", i));
-        add_raft_service(rf_node, &mut builder).unwrap();
-        add_kv_service(kv_node, &mut builder).unwrap();
-        let srv = builder.build();
-        self.net.add_server(srv);
+    /// make the clock of the raft of server i jump forward.
+    pub fn jump_clock(&self, i: usize, by: Duration) {
+        self.cluster.jump_clock(i, by);
     }
 
     pub fn leader(&self) -> Result<usize> {
-        let servers = self.servers.lock().unwrap();
-        for (i, kv) in servers.kvservers.iter().enumerate() {
-            if let Some(kv) = kv {
-                if kv.is_leader() {
-                    return Ok(i);
-                }
-            }
-        }
-        Err(Error::NoLeader)
+        self.cluster.leader().ok_or(Error::NoLeader)
     }
 
     /// Partition servers into 2 groups and put current leader in minority
@@ -354,14 +225,3 @@ impl Config {
         info!("  {:?}  {} {} {}", t, npeers, nrpc, nops);
     }
 }
-
-impl Drop for Config {
-    fn drop(&mut self) {
-        let servers = self.servers.lock().unwrap();
-        for s in &servers.kvservers {
-            if let Some(s) = s {
-                s.kill();
-            }
-        }
-    }
-}
@@ -0,0 +1,60 @@
+//! Describe the entries and snapshot chunks of kvraft, for `raft::dump` and the `raft-dump` binary.
+use labcodec::decode;
+
+use crate::proto::kvraftpb::kv_command::Command;
+use crate::proto::kvraftpb::{KvCommand, KvSnapshot, Op};
+use crate::raft::dump::{describe_driver_header, describe_raw, Payload};
+
+/// `KvCommand` entries, and the snapshots taken by `state_machine::Driver` for `KvStore`.
+pub struct KvPayload;
+
+impl Payload for KvPayload {
+    fn describe_command(&self, data: &[u8]) -> String {
+        if data.is_empty() {
+            return describe_raw(data);
+        }
+        match decode::<KvCommand>(data).map(|command| command.command) {
+            Ok(Some(Command::PutAppend(request))) => {
+                let op = match Op::from_i32(request.op) {
+                    Some(Op::Put) => "Put",
+                    Some(Op::Append) => "Append",
+                    _ => "Unknown",
+                };
+                format!(
+                    "{} {:?} {:?} (client {:?}, id {})",
+                    op,
+                    request.key,
+                    request.value,
+                    request.client,
+                    describe_raw(&request.id)
+                )
+            }
+            Ok(Some(Command::Get(request))) => format!(
+                "Get {:?} (client {:?}, id {})",
+                request.key,
+                request.client,
+                describe_raw(&request.id)
+            ),
+            Ok(None) | Err(_) => format!("not a kv command, {}", describe_raw(data)),
+        }
+    }
+
+    /// the first chunk is the header of the driver, and the others are `KvSnapshot`s.
+    fn describe_chunk(&self, i: usize, data: &[u8]) -> String {
+        if i == 0 {
+            return describe_driver_header(data);
+        }
+        match decode::<KvSnapshot>(data) {
+            Ok(chunk) => {
+                let mut kvs = chunk.kvs.into_iter().collect::<Vec<_>>();
+                kvs.sort();
+                let kvs = kvs
+                    .iter()
+                    .map(|(k, v)| format!("{:?}: {:?}", k, v))
+                    .collect::<Vec<_>>();
+                format!("{} keys {{{}}}", kvs.len(), kvs.join(", "))
+            }
+            Err(_) => format!("not a kv snapshot, {}", describe_raw(data)),
+        }
+    }
+}
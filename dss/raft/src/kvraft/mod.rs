@@ -2,36 +2,42 @@
 //!
 //! # kvraft(Lab 3)
 //! ## general
-//! We start a state machine `KvStateMachine` that receive commands from raft.
+//! The key/value store `KvStore` implements `raft::state_machine::StateMachine`,
+//! and a `raft::state_machine::Driver` applies the commands committed by raft to it.
 //!
-//! We can start command parallel, by a `Waker` table named `waiting_channels` in the `KvStateMachine`.
+//! Commands can be started in parallel, the driver wakes up each of them when its index is applied.
 //!
-//! We check duplicated request by a table named `last_command` in the `KvStateMachine`.
-//!
-//! `KvStateMachine` starts by `new`, it spawns a worker thread, which receiving `ApplyMsg` from raft node,
-//! wake up waiting threads, and transform state of itself.
+//! Duplicated `Put`/`Append` are checked by client sessions: see `KvStore::session`.
 //!
 //! `Clerk` starts by `request`, which is a generic method that doing leader check, retry, timeout, etc..
 //!
 //! ## where to find algorithm implementation
 //! ### Key/Value service based on raft (3A)
-//! `KvStateMachine::new` starts all.
+//! `KvServer::new` starts all.
 //!
-//! `Node` handles the rpc, and is the client of `KvStateMachine`.
+//! `Node` handles the rpc, and is the client of the driver.
 //! Code at `do_*` functions shows how it deal with rpc.
 //!
+//! `Get` doesn't go through the raft log: `Driver::read` asks raft for a read index,
+//! and answers once the store has applied to it.
+//!
 //! ### Raft with snapshot (3B)
 //!
-//! `RaftLogWithSnapshot` is the modified log struct for snapshot, which seems like a vector with offset.
+//! `raft::log::RaftLog` keeps the entries after the snapshot, older ones are dropped by `RaftLog::compact`.
 //!
 //! `raft::Node::take_snapshot` interface for client to take a new snapshot.
 //!
-//! `raft::Node::do_install_snapshot` for InstallSnapshot rpc.
+//! `raft::Raft::do_install_snapshot` for InstallSnapshot rpc.
+//!
+//! `KvStore::snapshot_chunk` and `KvStore::restore` save and load the store by chunks of keys,
+//! the driver adds client sessions to them. While a snapshot is being written, `KvStore` keeps the old
+//! values of the keys modified since it began, so the snapshot is still the state when it began.
 //!
 
 pub mod client;
 #[cfg(test)]
 pub mod config;
+pub mod dump;
 pub mod errors;
 pub mod server;
 #[cfg(test)]
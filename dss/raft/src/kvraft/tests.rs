@@ -12,6 +12,14 @@ use rand::Rng;
 
 use crate::kvraft::client::Clerk;
 use crate::kvraft::config::Config;
+use crate::kvraft::dump::KvPayload;
+use crate::kvraft::server::KvStore;
+use crate::proto::kvraftpb::{self, kv_command::Command, KvCommand, KvSnapshot, PutAppendRequest};
+use crate::proto::raftpb::{PersistedStatus, StateMachineSnapshot};
+use crate::raft::dump::{self, Payload};
+use crate::raft::nemesis::{self, Cluster};
+use crate::raft::state_machine::StateMachine;
+use crate::raft::LogEntry;
 use linearizability::check_operations_timeout;
 use linearizability::model::Operation;
 use linearizability::models::{KvInput, KvModel, KvOutput, Op};
@@ -353,6 +361,70 @@ fn generic_test(
     cfg.end();
 }
 
+/// run a random get, put or append on one of `nkeys` keys, as the `j`th write of client `cli`.
+///
+/// # returns
+/// the operation, timed since `begin`, for checking linearizability.
+fn random_operation(
+    cfg: &Config,
+    ck: &Clerk,
+    cli: usize,
+    j: &mut usize,
+    nkeys: usize,
+    begin: Instant,
+) -> Operation<KvInput, KvOutput> {
+    let mut rng = rand::thread_rng();
+    let key = format!("{}", rng.gen::<usize>() % nkeys);
+    let nv = format!("x {} {} y", cli, j);
+
+    let start = begin.elapsed().as_nanos() as i64;
+    let (inp, out) = if rng.gen::<usize>() % 1000 < 500 {
+        append(cfg, ck, &key, &nv);
+        *j += 1;
+        (
+            KvInput {
+                op: Op::APPEND,
+                key,
+                value: nv,
+            },
+            KvOutput {
+                value: "".to_string(),
+            },
+        )
+    } else if rng.gen::<usize>() % 1000 < 100 {
+        put(cfg, ck, &key, &nv);
+        *j += 1;
+        (
+            KvInput {
+                op: Op::PUT,
+                key,
+                value: nv,
+            },
+            KvOutput {
+                value: "".to_string(),
+            },
+        )
+    } else {
+        let v = get(cfg, ck, &key);
+        (
+            KvInput {
+                op: Op::GET,
+                key,
+                value: "".to_string(),
+            },
+            KvOutput { value: v },
+        )
+    };
+
+    let end = begin.elapsed().as_nanos() as i64;
+    Operation {
+        input: inp,
+        call: start,
+        output: out,
+        finish: end,
+    }
+}
+
 fn generic_test_linearizability(
     part: &str,
     nclients: usize,
@@ -419,57 +491,8 @@ fn generic_test_linearizability(
                 move |cli, myck| {
                     // TODO: change the closure to a future.
                     let mut j = 0;
-                    let mut rng = rand::thread_rng();
                     while done_clients1.load(Ordering::Relaxed) == 0 {
-                        let key = format!("{}", rng.gen::<usize>() % nclients);
-                        let nv = format!("x {} {} y", cli, j);
-
-                        let start = begin.elapsed().as_nanos() as i64;
-                        let (inp, out) = if rng.gen::<usize>() % 1000 < 500 {
-                            append(&cfg1, myck, &key, &nv);
-                            j += 1;
-                            (
-                                KvInput {
-                                    op: Op::APPEND,
-                                    key,
-                                    value: nv,
-                                },
-                                KvOutput {
-                                    value: "".to_string(),
-                                },
-                            )
-                        } else if rng.gen::<usize>() % 1000 < 100 {
-                            put(&cfg1, myck, &key, &nv);
-                            j += 1;
-                            (
-                                KvInput {
-                                    op: Op::PUT,
-                                    key,
-                                    value: nv,
-                                },
-                                KvOutput {
-                                    value: "".to_string(),
-                                },
-                            )
-                        } else {
-                            let v = get(&cfg1, myck, &key);
-                            (
-                                KvInput {
-                                    op: Op::GET,
-                                    key,
-                                    value: "".to_string(),
-                                },
-                                KvOutput { value: v },
-                            )
-                        };
-
-                        let end = begin.elapsed().as_nanos() as i64;
-                        let op = Operation {
-                            input: inp,
-                            call: start,
-                            output: out,
-                            finish: end,
-                        };
+                        let op = random_operation(&cfg1, myck, cli, &mut j, nclients, begin);
                         let mut data = operations1.lock().unwrap();
                         data.push(op);
                     }
@@ -901,6 +924,152 @@ fn test_snapshot_unreliable_recover_3b() {
     generic_test("3B", 5, true, true, false, Some(1000))
 }
 
+#[test]
+fn test_crash_during_persist_3b() {
+    let nservers = 3;
+    let cfg = Config::new(nservers, false, Some(1000));
+    for i in 0..nservers {
+        cfg.persister(i).set_write_delay(Duration::from_millis(10));
+    }
+
+    let all = cfg.all();
+    let ck = cfg.make_client(&all);
+
+    cfg.begin("Test: crashes during writes, snapshots (3B)");
+
+    let mut expected = String::new();
+    for round in 0..5 {
+        for i in 0..10 {
+            let value = format!("x {} {} y", round, i);
+            append(&cfg, &ck, "k", &value);
+            expected.push_str(&value);
+        }
+        // crash all servers while they may be saving entries or snapshots,
+        // the acknowledged appends survive lost and torn writes.
+        for i in 0..nservers {
+            cfg.shutdown_server(i);
+        }
+        for i in 0..nservers {
+            cfg.start_server(i);
+        }
+        cfg.connect_all();
+        check(&cfg, &ck, "k", &expected);
+    }
+
+    cfg.check_timeout();
+    cfg.end();
+}
+
+#[test]
+fn test_streaming_snapshot_3b() {
+    fn put(store: &mut KvStore, key: usize, value: &str) {
+        store.apply(KvCommand {
+            command: Some(Command::PutAppend(PutAppendRequest {
+                key: format!("key-{:04}", key),
+                value: value.to_owned(),
+                op: kvraftpb::Op::Put as i32,
+                ..Default::default()
+            })),
+        });
+    }
+
+    let keys = 1000;
+    let mut store = KvStore::default();
+    for i in 0..keys {
+        put(&mut store, i, &format!("value-{}", i));
+    }
+    store.begin_snapshot();
+    let mut chunks = vec![];
+    assert!(store.snapshot_chunk(&mut chunks));
+    // modify the written keys, the keys not written yet and new keys during the snapshot.
+    for i in (0..keys + 100).step_by(7) {
+        put(&mut store, i, "modified");
+    }
+    // the old values of the keys not written yet are kept.
+    let backlog = store.snapshot_backlog();
+    assert!(backlog > 0);
+    let mut round = 0;
+    while store.snapshot_chunk(&mut chunks) {
+        round += 1;
+        put(&mut store, round * 131 % (keys + 100), "modified again");
+    }
+    assert!(chunks.len() > 2, "the snapshot isn't written by chunks");
+    assert!(!store.snapshot_chunk(&mut chunks));
+    assert_eq!(store.snapshot_backlog(), 0);
+
+    let mut restored = KvStore::default();
+    restored.restore(&mut chunks.into_iter());
+    for i in 0..keys + 100 {
+        let expected = if i < keys {
+            format!("value-{}", i)
+        } else {
+            String::new()
+        };
+        assert_eq!(restored.get(&format!("key-{:04}", i)), expected);
+    }
+    assert_eq!(store.get("key-0007"), "modified");
+}
+
+#[test]
+fn test_dump_kv_3b() {
+    let put = KvCommand {
+        command: Some(Command::PutAppend(PutAppendRequest {
+            key: "k".to_owned(),
+            value: "v\"1".to_owned(),
+            op: kvraftpb::Op::Put as i32,
+            client: "c".to_owned(),
+            id: vec![1],
+        })),
+    };
+    let mut data = vec![];
+    labcodec::encode(&put, &mut data).unwrap();
+    let state = PersistedStatus {
+        current_term: 2,
+        voted_for: vec![],
+        logs: vec![data, vec![0xff; 3]]
+            .into_iter()
+            .map(|data| {
+                let entry = LogEntry {
+                    data,
+                    term: 2,
+                    no_op: false,
+                };
+                entry.into()
+            })
+            .collect(),
+        last_included_index: 10,
+        last_included_term: 1,
+    };
+    let mut state_buf = vec![];
+    labcodec::encode(&state, &mut state_buf).unwrap();
+    let text = dump::dump(&state_buf, &[], &KvPayload, dump::Format::Text).unwrap();
+    assert!(
+        text.contains(r#"11 (term 2): Put "k" "v\"1" (client "c", id 1 bytes: 01)"#),
+        "{}",
+        text
+    );
+    assert!(text.contains("12 (term 2): not a kv command"), "{}", text);
+
+    // the first chunk of a snapshot is the header of the driver, the others are `KvSnapshot`s.
+    let header = StateMachineSnapshot {
+        last_applied: 10,
+        sessions: vec![("c".to_owned(), vec![1])].into_iter().collect(),
+    };
+    let mut chunk = KvSnapshot::default();
+    chunk.kvs.insert("k".to_owned(), "v".to_owned());
+    let (mut header_buf, mut chunk_buf) = (vec![], vec![]);
+    labcodec::encode(&header, &mut header_buf).unwrap();
+    labcodec::encode(&chunk, &mut chunk_buf).unwrap();
+    assert_eq!(
+        KvPayload.describe_chunk(0, &header_buf),
+        r#"header: last applied 10, sessions of ["c"]"#
+    );
+    assert_eq!(
+        KvPayload.describe_chunk(1, &chunk_buf),
+        r#"1 keys {"k": "v"}"#
+    );
+}
+
 #[test]
 fn test_snapshot_unreliable_recover_concurrent_partition_3b() {
     // Test: unreliable net, restarts, partitions, snapshots, many clients (3B) ...
@@ -912,3 +1081,94 @@ fn test_snapshot_unreliable_recover_concurrent_partition_linearizable_3b() {
     // Test: unreliable net, restarts, partitions, snapshots, linearizability checks (3B) ...
     generic_test_linearizability("3B", 15, 7, true, true, true, Some(1000))
 }
+
+/// The kv cluster under the nemesis, its workload is random operations of some clients,
+/// checked by linearizability.
+struct KvCluster {
+    cfg: Arc<Config>,
+    done: Arc<AtomicUsize>,
+    clients: Vec<thread::JoinHandle<()>>,
+    operations: Arc<Mutex<Vec<Operation<KvInput, KvOutput>>>>,
+}
+
+impl KvCluster {
+    fn new(nservers: usize, nclients: usize, maxraftstate: Option<usize>) -> KvCluster {
+        let cfg = Arc::new(Config::new(nservers, false, maxraftstate));
+        cfg.begin("Test: nemesis, linearizability checks (3B)");
+        let begin = Instant::now();
+        let done = Arc::new(AtomicUsize::new(0));
+        let operations = Arc::new(Mutex::new(vec![]));
+        let clients = (0..nclients)
+            .map(|cli| {
+                let cfg = cfg.clone();
+                let done = done.clone();
+                let operations = operations.clone();
+                thread::spawn(move || {
+                    let ck = cfg.make_client(&cfg.all());
+                    let mut j = 0;
+                    while done.load(Ordering::Relaxed) == 0 {
+                        let op = random_operation(&cfg, &ck, cli, &mut j, nclients, begin);
+                        operations.lock().unwrap().push(op);
+                    }
+                    cfg.delete_client(&ck);
+                })
+            })
+            .collect();
+        KvCluster {
+            cfg,
+            done,
+            clients,
+            operations,
+        }
+    }
+}
+
+impl Cluster for KvCluster {
+    fn servers(&self) -> usize {
+        self.cfg.n
+    }
+
+    fn crash(&mut self, server: usize) {
+        self.cfg.shutdown_server(server);
+    }
+
+    fn restart(&mut self, server: usize) {
+        self.cfg.start_server(server);
+        self.cfg.connect_all();
+    }
+
+    fn partition(&mut self, p1: &[usize], p2: &[usize]) {
+        self.cfg.partition(p1, p2);
+    }
+
+    fn heal(&mut self) {
+        self.cfg.connect_all();
+    }
+
+    fn set_unreliable(&mut self, unreliable: bool) {
+        self.cfg.net.set_reliable(!unreliable);
+    }
+
+    fn jump_clock(&mut self, server: usize, by: Duration) {
+        self.cfg.jump_clock(server, by);
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.done.store(1, Ordering::Relaxed);
+        for client in self.clients.drain(..) {
+            client.join().map_err(|_| "a client panicked.".to_owned())?;
+        }
+        let operations = std::mem::replace(&mut *self.operations.lock().unwrap(), vec![]);
+        if !check_operations_timeout(KvModel {}, operations, LINEARIZABILITY_CHECK_TIMEOUT) {
+            return Err("history is not linearizable".to_owned());
+        }
+        self.cfg.end();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_nemesis_linearizable_3b() {
+    // Test: nemesis, linearizability checks (3B) ...
+    nemesis::check(Duration::from_secs(10), || KvCluster::new(5, 5, Some(1000)));
+}
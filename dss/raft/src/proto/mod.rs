@@ -1,4 +1,8 @@
 pub mod raftpb {
+    pub use self::multi_raft::{
+        add_service as add_multi_raft_service, Client as MultiRaftClient,
+        Service as MultiRaftService,
+    };
     pub use self::raft::{
         add_service as add_raft_service, Client as RaftClient, Service as RaftService,
     };
@@ -15,6 +19,15 @@ pub mod raftpb {
             rpc install_snapshot(InstallSnapshotArgs) returns (InstallSnapshotReply);
         }
     }
+
+    labrpc::service! {
+        service multi_raft {
+            rpc request_vote(GroupRequestVoteArgs) returns (RequestVoteReply);
+            rpc append_entries(GroupAppendEntriesArgs) returns (AppendEntriesReply);
+            rpc install_snapshot(GroupInstallSnapshotArgs) returns (InstallSnapshotReply);
+            rpc heartbeats(HeartbeatBatch) returns (HeartbeatBatchReply);
+        }
+    }
 }
 
 pub mod kvraftpb {
@@ -0,0 +1,123 @@
+//! Saving the persisted state of a peer in a background thread,
+//! so the event loop of the peer goes on while its writes are in flight.
+use std::io;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::raft::persister::Persister;
+
+type SharedPersister = Arc<Mutex<Box<dyn Persister>>>;
+
+enum Job {
+    /// save the raft state to `persister`, and the snapshot if it's given,
+    /// then call `done` with the result.
+    Save {
+        persister: SharedPersister,
+        state: Vec<u8>,
+        snapshot: Option<Vec<u8>>,
+        done: Box<dyn FnOnce(io::Result<()>) + Send>,
+    },
+    /// notify the sender after all earlier jobs are done.
+    Flush(Sender<()>),
+}
+
+/// The background thread saving the writes of `AsyncPersister`s, in the order they are issued.
+///
+/// It can be shared by many peers, e.g. the groups of a `multi::Host`, see
+/// `AsyncPersister::share_writer`. The thread exits after the pending writes once all handles
+/// to it are dropped.
+#[derive(Clone)]
+pub struct PersistWriter {
+    jobs: Sender<Job>,
+}
+
+impl PersistWriter {
+    pub fn spawn() -> PersistWriter {
+        let (jobs, rx) = channel();
+        thread::spawn(move || {
+            for job in rx {
+                match job {
+                    Job::Save {
+                        persister,
+                        state,
+                        snapshot,
+                        done,
+                    } => {
+                        let result = persister.lock().unwrap().try_save(state, snapshot);
+                        done(result);
+                    }
+                    Job::Flush(sender) => {
+                        let _ = sender.send(());
+                    }
+                }
+            }
+        });
+        PersistWriter { jobs }
+    }
+
+    fn send(&self, job: Job) {
+        self.jobs
+            .send(job)
+            .expect("fatal: the persister thread has panicked.");
+    }
+
+    /// block until all writes issued are saved.
+    fn flush(&self) {
+        let (sx, rx) = channel();
+        self.send(Job::Flush(sx));
+        rx.recv()
+            .expect("fatal: the persister thread has panicked.");
+    }
+}
+
+/// A `Persister` written by a `PersistWriter` in the background.
+///
+/// The writes are saved in the order they are issued, so an earlier write never overwrites
+/// a later one. Unless a writer is shared by `share_writer`, one of its own is spawned
+/// on the first write.
+pub struct AsyncPersister {
+    persister: SharedPersister,
+    writer: Option<PersistWriter>,
+}
+
+impl AsyncPersister {
+    pub fn new(persister: Box<dyn Persister>) -> AsyncPersister {
+        AsyncPersister {
+            persister: Arc::new(Mutex::new(persister)),
+            writer: None,
+        }
+    }
+
+    /// save the later writes by `writer`, after the ones issued before are saved.
+    pub fn share_writer(&mut self, writer: PersistWriter) {
+        self.flush();
+        self.writer = Some(writer);
+    }
+
+    /// save the state in the background, `done` is called in the background thread with the result
+    /// of the write. The saved snapshot is kept if `snapshot` is `None`.
+    pub fn save(
+        &mut self,
+        state: Vec<u8>,
+        snapshot: Option<Vec<u8>>,
+        done: impl FnOnce(io::Result<()>) + Send + 'static,
+    ) {
+        let job = Job::Save {
+            persister: self.persister.clone(),
+            state,
+            snapshot,
+            done: Box::new(done),
+        };
+        self.writer
+            .get_or_insert_with(PersistWriter::spawn)
+            .send(job);
+    }
+
+    /// block until all writes issued are saved.
+    pub fn flush(&self) {
+        if let Some(writer) = self.writer.as_ref() {
+            writer.flush();
+        }
+    }
+}
@@ -1,23 +1,19 @@
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use futures::{sync::mpsc::unbounded, Future, Stream};
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures::{Future, Stream};
 use labrpc;
 
-use crate::proto::raftpb::*;
 use crate::raft;
+use crate::raft::invariants::InvariantChecker;
+use crate::raft::nemesis::Cluster;
 use crate::raft::persister::*;
+use crate::raft::testing;
 use rand::Rng;
 
-static ID: AtomicUsize = AtomicUsize::new(0);
-
-fn uniqstring() -> String {
-    format!("{}", ID.fetch_add(1, Ordering::Relaxed))
-}
-
 /// A log entry.
 #[derive(Clone, PartialEq, Message)]
 pub struct Entry {
@@ -28,6 +24,8 @@ pub struct Entry {
 pub struct Storage {
     // copy of each server's committed entries
     logs: Vec<HashMap<u64, Entry>>,
+    // the indices of no-op entries each server has applied
+    no_ops: Vec<HashSet<u64>>,
     max_index: u64,
     max_index0: u64,
 }
@@ -52,6 +50,11 @@ impl Storage {
         }
         (count, cmd)
     }
+
+    /// has server `i` applied the entry at `index`, a command or a no-op?
+    fn has_applied(&self, i: usize, index: u64) -> bool {
+        self.logs[i].contains_key(&index) || self.no_ops[i].contains(&index)
+    }
 }
 
 fn init_logger() {
@@ -63,16 +66,16 @@ fn init_logger() {
 pub struct Config {
     pub net: labrpc::Network,
     n: usize,
-    // use boxed slice to prohibit grow capacity.
-    pub rafts: Arc<Mutex<Box<[Option<raft::Node>]>>>,
+    // the raft servers.
+    cluster: Arc<testing::Cluster<raft::Node>>,
     // whether each server is on the net
     pub connected: Box<[bool]>,
-    saved: Box<[Arc<SimplePersister>]>,
-    // the port file names each sends to
-    endnames: Box<[Box<[String]>]>,
 
     pub storage: Arc<Mutex<Storage>>,
 
+    // checks the safety properties by the events of all rafts.
+    pub checker: Arc<Mutex<InvariantChecker>>,
+
     // time at which make_config() was called
     start: Instant,
 
@@ -88,46 +91,79 @@ pub struct Config {
 
 impl Config {
     pub fn new(n: usize, unreliable: bool) -> Config {
+        Config::with_options(n, unreliable, None, vec![])
+    }
+
+    /// like `new`, but all rafts enable the lease read mode with `read_lease`.
+    pub fn new_with_read_lease(n: usize, unreliable: bool, read_lease: Option<Duration>) -> Config {
+        Config::with_options(n, unreliable, read_lease, vec![])
+    }
+
+    /// like `new`, but the rafts are given the election priorities.
+    pub fn new_with_priorities(n: usize, unreliable: bool, priorities: Vec<u64>) -> Config {
+        Config::with_options(n, unreliable, None, priorities)
+    }
+
+    fn with_options(
+        n: usize,
+        unreliable: bool,
+        read_lease: Option<Duration>,
+        priorities: Vec<u64>,
+    ) -> Config {
         init_logger();
 
         let net = labrpc::Network::new();
         net.set_reliable(!unreliable);
         net.set_long_delays(true);
-        let storage = Storage {
+        let storage = Arc::new(Mutex::new(Storage {
             logs: vec![HashMap::new(); n],
+            no_ops: vec![HashSet::new(); n],
             max_index: 0,
             max_index0: 0,
+        }));
+        let checker = Arc::new(Mutex::new(InvariantChecker::new(n)));
+        let cluster = {
+            let (net, storage, checker) = (net.clone(), storage.clone(), checker.clone());
+            testing::Cluster::new(net.clone(), n, move |i, clients, persister| {
+                let mut config = raft::RaftConfig::builder();
+                if let Some(lease) = read_lease {
+                    config = config.read_lease(lease);
+                }
+                config = config.priorities(priorities.clone());
+                let mut rf = raft::Raft::with_config(
+                    clients,
+                    i,
+                    persister,
+                    apply_to(&net, &storage, i),
+                    config.build().unwrap(),
+                );
+                rf.set_observer(InvariantChecker::observer(&checker));
+                raft::Node::new(rf)
+            })
         };
-        let mut saved = vec![];
-        let mut endnames = vec![];
-        for _ in 0..n {
-            endnames.push(vec![String::new(); n].into_boxed_slice());
-            saved.push(Arc::new(SimplePersister::new()));
-        }
-        let mut cfg = Config {
+        Config {
             net,
             n,
-            rafts: Arc::new(Mutex::new(vec![None; n].into_boxed_slice())),
+            cluster: Arc::new(cluster),
             connected: vec![true; n].into_boxed_slice(),
-            saved: saved.into_boxed_slice(),
-            endnames: endnames.into_boxed_slice(),
-            storage: Arc::new(Mutex::new(storage)),
+            storage,
+            checker,
 
             start: Instant::now(),
             t0: Instant::now(),
             rpcs0: 0,
             cmds0: 0,
-        };
-
-        for i in 0..n {
-            cfg.start1(i);
         }
+    }
 
-        for i in 0..n {
-            cfg.connect(i);
-        }
+    /// the raft of server i, `None` if it has crashed.
+    pub fn raft(&self, i: usize) -> Option<raft::Node> {
+        self.cluster.raft(i)
+    }
 
-        cfg
+    /// the servers, shared with the threads of a test.
+    pub fn cluster(&self) -> Arc<testing::Cluster<raft::Node>> {
+        self.cluster.clone()
     }
 
     pub fn rpc_count(&self, server: usize) -> usize {
@@ -149,11 +185,7 @@ impl Config {
 
             for (i, connected) in self.connected.iter().enumerate() {
                 if *connected {
-                    let state = self.rafts.lock().unwrap()[i]
-                        .as_ref()
-                        .unwrap()
-                        .get_state()
-                        .clone();
+                    let state = self.raft(i).unwrap().get_state();
                     let term = state.term();
                     let is_leader = state.is_leader();
                     if is_leader {
@@ -185,7 +217,7 @@ impl Config {
         let mut term = 0;
         for (i, connected) in self.connected.iter().enumerate() {
             if *connected {
-                let xterm = self.rafts.lock().unwrap()[i].as_ref().unwrap().term();
+                let xterm = self.raft(i).unwrap().term();
                 if term == 0 {
                     term = xterm;
                 } else if term != xterm {
@@ -196,11 +228,27 @@ impl Config {
         term
     }
 
+    /// split the running servers into two parts,
+    /// only the servers in the same part can talk with each other.
+    pub fn partition(&mut self, p1: &[usize], p2: &[usize]) {
+        self.cluster.partition(p1, p2);
+        for i in 0..self.n {
+            self.connected[i] = self.raft(i).is_some();
+        }
+    }
+
+    /// check that no raft has violated the safety properties so far.
+    pub fn check_invariants(&self) {
+        if let Some(violation) = self.checker.lock().unwrap().violation() {
+            panic!("{}", violation);
+        }
+    }
+
     /// check that there's no leader
     pub fn check_no_leader(&self) {
         for (i, connected) in self.connected.iter().enumerate() {
             if *connected {
-                let is_leader = self.rafts.lock().unwrap()[i].as_ref().unwrap().is_leader();
+                let is_leader = self.raft(i).unwrap().is_leader();
                 if is_leader {
                     panic!("expected no leader, but {} claims to be leader", i);
                 }
@@ -235,15 +283,12 @@ impl Config {
                 to *= 2;
             }
             if let Some(start_term) = start_term {
-                let rafts = self.rafts.lock().unwrap();
-                for r in rafts.iter() {
-                    if let Some(rf) = r {
-                        let term = rf.term();
-                        if term > start_term {
-                            // someone has moved on
-                            // can no longer guarantee that we'll "win"
-                            return None;
-                        }
+                for rf in (0..self.n).filter_map(|i| self.raft(i)) {
+                    let term = rf.term();
+                    if term > start_term {
+                        // someone has moved on
+                        // can no longer guarantee that we'll "win"
+                        return None;
                     }
                 }
             }
@@ -268,6 +313,7 @@ impl Config {
     /// if retry==false, calls start() only once, in order
     /// to simplify the early Lab 2B tests.
     pub fn one(&self, cmd: Entry, expected_servers: usize, retry: bool) -> u64 {
+        self.check_invariants();
         let t0 = Instant::now();
         let mut starts = 0;
         while t0.elapsed() < Duration::from_secs(10) {
@@ -276,8 +322,7 @@ impl Config {
             for _ in 0..self.n {
                 starts = (starts + 1) % self.n;
                 if self.connected[starts] {
-                    let rafts = self.rafts.lock().unwrap();
-                    if let Some(ref rf) = &rafts[starts] {
+                    if let Some(rf) = self.raft(starts) {
                         match rf.start(&cmd) {
                             Ok((index1, _)) => {
                                 index = Some(index1);
@@ -288,7 +333,6 @@ impl Config {
                     }
                 }
             }
-
             if let Some(index) = index {
                 // somebody claimed to be the leader and to have
                 // submitted our command; wait a while for agreement.
@@ -334,6 +378,7 @@ impl Config {
     /// print the Passed message, and some performance numbers.
     pub fn end(&self) {
         self.check_timeout();
+        self.check_invariants();
 
         // real time
         let t = self.t0.elapsed();
@@ -352,96 +397,23 @@ impl Config {
 
     /// start or re-start a Raft.
     /// if one already exists, "kill" it first.
-    /// allocate new outgoing port file names, and a new
-    /// state persister, to isolate previous instance of
-    /// this server. since we cannot really kill it.
+    /// the restarted one holds what the old one has persisted,
+    /// and can't talk with any server until `connect`ed.
     pub fn start1(&mut self, i: usize) {
-        self.crash1(i);
-
-        // a fresh set of outgoing ClientEnd names.
-        // so that old crashed instance's ClientEnds can't send.
-        self.endnames[i] = vec![String::new(); self.n].into_boxed_slice();
-        for j in 0..self.n {
-            self.endnames[i][j] = uniqstring();
-        }
-
-        // a fresh set of ClientEnds.
-        let mut clients = Vec::with_capacity(self.n);
-        for (j, name) in self.endnames[i].iter().enumerate() {
-            let cli = self.net.create_client(name.to_string());
-            let client = RaftClient::new(cli);
-            clients.push(client);
-            self.net.connect(name, &format!("{}", j));
-        }
-
-        // listen to messages from Raft indicating newly committed messages.
-        let (tx, apply_ch) = unbounded();
-        let storage = self.storage.clone();
-        let apply = apply_ch
-            .for_each(move |cmd: raft::ApplyMsg| {
-                if !cmd.command_valid {
-                    // ignore other types of ApplyMsg
-                    return Ok(());
-                }
-                match labcodec::decode(&cmd.command) {
-                    Ok(entry) => {
-                        let mut s = storage.lock().unwrap();
-                        for (j, log) in s.logs.iter().enumerate() {
-                            if let Some(old) = log.get(&cmd.command_index) {
-                                if *old != entry {
-                                    // some server has already committed a different value for this entry!
-                                    panic!(
-                                        "commit index={:?} server={:?} {:?} != server={:?} {:?}",
-                                        cmd.command_index, i, entry, j, old
-                                    );
-                                }
-                            }
-                        }
-                        let log = &mut s.logs[i];
-                        if cmd.command_index > 1 && log.get(&(cmd.command_index - 1)).is_none() {
-                            panic!("server {} apply out of order {}", i, cmd.command_index);
-                        }
-                        log.insert(cmd.command_index, entry);
-                        if cmd.command_index > s.max_index {
-                            s.max_index = cmd.command_index;
-                        }
-                    }
-                    Err(e) => {
-                        panic!("committed command is not an entry {:?}", e);
-                    }
-                }
-                Ok(())
-            })
-            .map_err(move |e| debug!("raft {} apply stopped: {:?}", i, e));
-        self.net.spawn_poller(apply);
-
-        let rf = raft::Raft::new(clients, i, Box::new(self.saved[i].clone()), tx);
-        let node = raft::Node::new(rf);
-        self.rafts.lock().unwrap()[i] = Some(node.clone());
+        self.connected[i] = false;
+        self.cluster.start(i);
+    }
 
-        let mut builder = labrpc::ServerBuilder::new(format!("{}", i));
-        raft::add_raft_service(node, &mut builder).unwrap();
-        let srv = builder.build();
-        self.net.add_server(srv.clone());
+    /// the persister of server i, to inject faults into its writes.
+    pub fn persister(&self, i: usize) -> Arc<FaultyPersister> {
+        self.cluster.persister(i)
     }
 
     /// shut down a Raft server but save its persistent state.
     pub fn crash1(&mut self, i: usize) {
-        self.disconnect(i);
-        // disable client connections to the server.
-        self.net.delete_server(&format!("{}", i));
-
-        // a fresh persister, in case old instance
-        // continues to update the Persister.
-        // but copy old persister's content so that we always
-        // pass Make() the last persisted state.
-        let p = SimplePersister::new();
-        p.save_raft_state(self.saved[i].raft_state());
-        self.saved[i] = Arc::new(p);
-
-        if let Some(rf) = self.rafts.lock().unwrap()[i].take() {
-            rf.kill();
-        }
+        debug!("crash({})", i);
+        self.connected[i] = false;
+        self.cluster.crash(i);
     }
 
     /// detach server i from the net.
@@ -449,17 +421,7 @@ impl Config {
         debug!("disconnect({})", i);
 
         self.connected[i] = false;
-
-        // outgoing ClientEnds
-        for endname in &*self.endnames[i] {
-            self.net.enable(endname, false);
-        }
-
-        // incoming ClientEnds
-        for names in &*self.endnames {
-            let endname = &names[i];
-            self.net.enable(endname, false);
-        }
+        self.cluster.disconnect(i, &self.cluster.all());
     }
 
     /// attach server i to the net.
@@ -467,36 +429,126 @@ impl Config {
         debug!("connect({})", i);
 
         self.connected[i] = true;
+        let connected = (0..self.n)
+            .filter(|j| self.connected[*j])
+            .collect::<Vec<_>>();
+        self.cluster.connect(i, &connected);
+    }
+}
 
-        // outgoing ClientEnds
-        for (j, connected) in self.connected.iter().enumerate() {
-            if *connected {
-                let endname = &*self.endnames[i][j];
-                self.net.enable(endname, true);
+/// listen to messages from the raft of server i indicating newly committed messages.
+fn apply_to(
+    net: &labrpc::Network,
+    storage: &Arc<Mutex<Storage>>,
+    i: usize,
+) -> UnboundedSender<raft::ApplyMsg> {
+    let (tx, apply_ch) = unbounded();
+    let storage = storage.clone();
+    let apply = apply_ch
+        .for_each(move |msg: raft::ApplyMsg| {
+            let (command_index, command) = match msg {
+                raft::ApplyMsg::Command { index, data, .. } => (index, data),
+                raft::ApplyMsg::NoOp { index, .. } => {
+                    let mut s = storage.lock().unwrap();
+                    if index > 1 && !s.has_applied(i, index - 1) {
+                        panic!("server {} apply out of order {}", i, index);
+                    }
+                    s.no_ops[i].insert(index);
+                    return Ok(());
+                }
+                // ignore other types of ApplyMsg
+                _ => return Ok(()),
+            };
+            match labcodec::decode(&command) {
+                Ok(entry) => {
+                    let mut s = storage.lock().unwrap();
+                    for (j, log) in s.logs.iter().enumerate() {
+                        if let Some(old) = log.get(&command_index) {
+                            if *old != entry {
+                                // some server has already committed a different value for this entry!
+                                panic!(
+                                    "commit index={:?} server={:?} {:?} != server={:?} {:?}",
+                                    command_index, i, entry, j, old
+                                );
+                            }
+                        }
+                    }
+                    if command_index > 1 && !s.has_applied(i, command_index - 1) {
+                        panic!("server {} apply out of order {}", i, command_index);
+                    }
+                    s.logs[i].insert(command_index, entry);
+                    if command_index > s.max_index {
+                        s.max_index = command_index;
+                    }
+                }
+                Err(e) => {
+                    panic!("committed command is not an entry {:?}", e);
+                }
             }
-        }
+            Ok(())
+        })
+        .map_err(move |e| debug!("raft {} apply stopped: {:?}", i, e));
+    net.spawn_poller(apply);
+    tx
+}
 
-        // incoming ClientEnds
-        for (j, connected) in self.connected.iter().enumerate() {
-            if *connected {
-                let endname = &*self.endnames[j][i];
-                self.net.enable(endname, true);
+impl Drop for Config {
+    fn drop(&mut self) {
+        // the servers are killed by the cluster.
+        // FIXME: we should not panic in a drop method.
+        self.check_timeout();
+    }
+}
+
+/// The raft cluster under the nemesis, its workload is proposing random entries.
+impl Cluster for Config {
+    fn servers(&self) -> usize {
+        self.n
+    }
+
+    fn crash(&mut self, server: usize) {
+        self.crash1(server);
+    }
+
+    fn restart(&mut self, server: usize) {
+        self.start1(server);
+        self.connect(server);
+    }
+
+    fn partition(&mut self, p1: &[usize], p2: &[usize]) {
+        Config::partition(self, p1, p2);
+    }
+
+    fn heal(&mut self) {
+        for i in 0..self.n {
+            if self.raft(i).is_some() {
+                self.connect(i);
             }
         }
     }
-}
 
-impl Drop for Config {
-    fn drop(&mut self) {
-        if let Ok(rafts) = self.rafts.try_lock() {
-            for r in rafts.iter() {
-                if let Some(rf) = r {
-                    rf.kill();
-                }
+    fn set_unreliable(&mut self, unreliable: bool) {
+        self.net.set_reliable(!unreliable);
+    }
+
+    fn jump_clock(&mut self, server: usize, by: Duration) {
+        self.cluster.jump_clock(server, by);
+    }
+
+    fn work(&mut self) {
+        let x = rand::thread_rng().gen::<u64>();
+        for rf in (0..self.n).filter_map(|i| self.raft(i)) {
+            if rf.start(&Entry { x }).is_ok() {
+                break;
             }
         }
+    }
 
-        // FIXME: we should not panic in a drop method.
-        self.check_timeout();
+    fn finish(&mut self) -> Result<(), String> {
+        // all servers agree on a new entry after the faults.
+        let x = rand::thread_rng().gen::<u64>();
+        self.one(Entry { x }, self.n, true);
+        self.check_invariants();
+        Ok(())
     }
 }
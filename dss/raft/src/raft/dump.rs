@@ -0,0 +1,280 @@
+//! Decode the persisted state of a peer for inspection, used by the `raft-dump` binary.
+//!
+//! The input is the bytes of `Persister::raft_state` (a `PersistedStatus`) and of
+//! `Persister::snapshot` (a `Snapshot`). The entries and snapshot chunks are opaque to raft,
+//! they are shown as bytes (`Raw`), or described by the `Payload` of the service,
+//! e.g. `kvraft::dump::KvPayload`.
+//!
+//! A peer with a `SegmentedLog` keeps its entries in the segments of its directory, which aren't
+//! read here, so only the entries saved in the raft state are shown.
+use std::fmt::Write;
+
+use labcodec::decode;
+
+use crate::proto::raftpb::{PersistedStatus, Snapshot, StateMachineSnapshot};
+use crate::raft::errors::{Error, Result};
+
+/// How many bytes of a raw payload are shown.
+const RAW_PREVIEW_BYTES: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// lines for humans.
+    Text,
+    /// a JSON object, see `dump`.
+    Json,
+}
+
+/// How the entries and snapshot chunks of a service are described.
+pub trait Payload {
+    /// describe the command of an entry.
+    fn describe_command(&self, data: &[u8]) -> String;
+
+    /// describe the `i`th chunk of the snapshot.
+    fn describe_chunk(&self, i: usize, data: &[u8]) -> String;
+}
+
+/// Describes the entries and snapshot chunks by their sizes and leading bytes.
+pub struct Raw;
+
+impl Payload for Raw {
+    fn describe_command(&self, data: &[u8]) -> String {
+        describe_raw(data)
+    }
+
+    fn describe_chunk(&self, _i: usize, data: &[u8]) -> String {
+        describe_raw(data)
+    }
+}
+
+struct EntryDump {
+    index: u64,
+    term: u64,
+    intact: bool,
+    payload: String,
+}
+
+struct StateDump {
+    term: u64,
+    voted_for: Option<u64>,
+    entries: Vec<EntryDump>,
+}
+
+struct SnapshotDump {
+    last_included_index: u64,
+    last_included_term: u64,
+    intact: bool,
+    chunks: Vec<String>,
+}
+
+/// decode the persisted raft state and snapshot, either of them may be empty.
+///
+/// The JSON form is
+/// `{"raft_state": {"term", "voted_for", "entries": [{"index", "term", "checksum_ok", "payload"}]},
+/// "snapshot": {"last_included_index", "last_included_term", "checksum_ok", "chunks": [...]}}`,
+/// where an absent part is `null`.
+///
+/// # returns
+/// `Error::Decode` if any part cannot be decoded.
+pub fn dump(
+    raft_state: &[u8],
+    snapshot: &[u8],
+    payload: &dyn Payload,
+    format: Format,
+) -> Result<String> {
+    let snapshot = if snapshot.is_empty() {
+        None
+    } else {
+        let snapshot = decode::<Snapshot>(snapshot).map_err(Error::Decode)?;
+        Some(SnapshotDump {
+            last_included_index: snapshot.last_index_of_snapshot,
+            last_included_term: snapshot.last_term_of_snapshot,
+            intact: snapshot.is_intact(),
+            chunks: snapshot
+                .state_machine_state
+                .iter()
+                .enumerate()
+                .map(|(i, chunk)| payload.describe_chunk(i, chunk))
+                .collect(),
+        })
+    };
+    let state = if raft_state.is_empty() {
+        None
+    } else {
+        let state = decode::<PersistedStatus>(raft_state).map_err(Error::Decode)?;
+        // the persisted entries follow the snapshot they were saved with.
+        let first_index = state.last_included_index + 1;
+        Some(StateDump {
+            term: state.current_term,
+            voted_for: state.voted_for.first().cloned(),
+            entries: state
+                .logs
+                .iter()
+                .zip(first_index..)
+                .map(|(entry, index)| EntryDump {
+                    index,
+                    term: entry.term,
+                    intact: entry.is_intact(),
+                    payload: if entry.no_op {
+                        "no-op".to_owned()
+                    } else {
+                        payload.describe_command(&entry.command)
+                    },
+                })
+                .collect(),
+        })
+    };
+    Ok(match format {
+        Format::Text => to_text(state.as_ref(), snapshot.as_ref()),
+        Format::Json => to_json(state.as_ref(), snapshot.as_ref()),
+    })
+}
+
+/// the size and the leading bytes of `data`.
+pub fn describe_raw(data: &[u8]) -> String {
+    let mut s = format!("{} bytes", data.len());
+    if !data.is_empty() {
+        s.push(':');
+        for byte in data.iter().take(RAW_PREVIEW_BYTES) {
+            write!(s, " {:02x}", byte).unwrap();
+        }
+        if data.len() > RAW_PREVIEW_BYTES {
+            s.push_str(" ..");
+        }
+    }
+    s
+}
+
+/// describe the first chunk of a snapshot taken by `state_machine::Driver`, which is its header.
+pub fn describe_driver_header(data: &[u8]) -> String {
+    match decode::<StateMachineSnapshot>(data) {
+        Ok(header) => {
+            let mut clients = header.sessions.keys().collect::<Vec<_>>();
+            clients.sort();
+            format!(
+                "header: last applied {}, sessions of {:?}",
+                header.last_applied, clients
+            )
+        }
+        Err(_) => format!("not a snapshot header, {}", describe_raw(data)),
+    }
+}
+
+fn checksum_mark(intact: bool) -> &'static str {
+    if intact {
+        ""
+    } else {
+        " [CHECKSUM MISMATCH]"
+    }
+}
+
+fn to_text(state: Option<&StateDump>, snapshot: Option<&SnapshotDump>) -> String {
+    let mut s = String::new();
+    match state {
+        Some(state) => {
+            writeln!(s, "term: {}", state.term).unwrap();
+            match state.voted_for {
+                Some(peer) => writeln!(s, "voted for: {}", peer).unwrap(),
+                None => writeln!(s, "voted for: none").unwrap(),
+            }
+            writeln!(s, "entries: {}", state.entries.len()).unwrap();
+            for entry in &state.entries {
+                writeln!(
+                    s,
+                    "  {} (term {}): {}{}",
+                    entry.index,
+                    entry.term,
+                    entry.payload,
+                    checksum_mark(entry.intact)
+                )
+                .unwrap();
+            }
+        }
+        None => writeln!(s, "raft state: none").unwrap(),
+    }
+    match snapshot {
+        Some(snapshot) => {
+            writeln!(
+                s,
+                "snapshot: to index {} (term {}), {} chunks{}",
+                snapshot.last_included_index,
+                snapshot.last_included_term,
+                snapshot.chunks.len(),
+                checksum_mark(snapshot.intact)
+            )
+            .unwrap();
+            for (i, chunk) in snapshot.chunks.iter().enumerate() {
+                writeln!(s, "  chunk {}: {}", i, chunk).unwrap();
+            }
+        }
+        None => writeln!(s, "snapshot: none").unwrap(),
+    }
+    s
+}
+
+/// quote `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn to_json(state: Option<&StateDump>, snapshot: Option<&SnapshotDump>) -> String {
+    let state = state.map_or_else(
+        || "null".to_owned(),
+        |state| {
+            let entries = state
+                .entries
+                .iter()
+                .map(|entry| {
+                    format!(
+                        r#"{{"index":{},"term":{},"checksum_ok":{},"payload":{}}}"#,
+                        entry.index,
+                        entry.term,
+                        entry.intact,
+                        json_string(&entry.payload)
+                    )
+                })
+                .collect::<Vec<_>>();
+            format!(
+                r#"{{"term":{},"voted_for":{},"entries":[{}]}}"#,
+                state.term,
+                state
+                    .voted_for
+                    .map_or_else(|| "null".to_owned(), |peer| peer.to_string()),
+                entries.join(",")
+            )
+        },
+    );
+    let snapshot = snapshot.map_or_else(
+        || "null".to_owned(),
+        |snapshot| {
+            let chunks = snapshot
+                .chunks
+                .iter()
+                .map(String::as_str)
+                .map(json_string)
+                .collect::<Vec<_>>();
+            format!(
+                r#"{{"last_included_index":{},"last_included_term":{},"checksum_ok":{},"chunks":[{}]}}"#,
+                snapshot.last_included_index,
+                snapshot.last_included_term,
+                snapshot.intact,
+                chunks.join(",")
+            )
+        },
+    );
+    format!(r#"{{"raft_state":{},"snapshot":{}}}"#, state, snapshot)
+}
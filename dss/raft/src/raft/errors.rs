@@ -8,6 +8,19 @@ pub enum Error {
     Decode(labcodec::DecodeError),
     Rpc(labrpc::Error),
     NotLeader,
+    /// The leader hasn't committed any entry in its term yet,
+    /// so its `commit_index` cannot be used for serving reads.
+    NoCommittedEntryInTerm,
+    /// The leader failed to contact a majority in time.
+    LeadershipUnconfirmed,
+    /// The proposal may not be applied, e.g. another entry is committed at its index.
+    NotCommitted,
+    /// The raft peer, or the state machine driver has stopped.
+    Stopped,
+    /// The `RaftConfig` is invalid, for the reason.
+    InvalidConfig(String),
+    /// The persisted state is damaged, for the reason.
+    Corrupted(String),
 }
 
 impl fmt::Display for Error {
@@ -0,0 +1,258 @@
+//! A checker of the safety properties of raft (figure 3 of the paper), for tests.
+//!
+//! It mirrors the log of each peer by the `RaftEvent`s they report, and checks on every event:
+//! - Election Safety: at most one leader can be elected in a given term.
+//! - Log Matching: if two logs contain an entry with the same index and term,
+//!   then the logs are identical in all entries up through the given index.
+//! - Leader Completeness: if a log entry is committed in a given term,
+//!   then that entry will be present in the logs of the leaders for all higher-numbered terms.
+//! - State Machine Safety: if a server has applied a log entry at a given index to its state machine,
+//!   no other server will ever apply a different log entry for the same index.
+//!
+//! The events of different peers are serialized by the lock of the checker, which may be later
+//! than the transitions themselves, so every check is made in a way that such delays cannot fail it.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use crate::raft::{RaftEvent, RaftObserver};
+
+/// how many recent events are reported along with a violation.
+const HISTORY_SIZE: usize = 32;
+
+/// An entry in the mirror of a log.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Mirrored {
+    term: u64,
+    /// the hash of this entry and all entries before it,
+    /// so two entries are equal only if the logs are identical up through them.
+    chain: u64,
+}
+
+impl Mirrored {
+    fn next(&self, term: u64, data: &[u8]) -> Mirrored {
+        let mut hasher = DefaultHasher::new();
+        (self.chain, term, data).hash(&mut hasher);
+        Mirrored {
+            term,
+            chain: hasher.finish(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct PeerLog {
+    /// `entries[i]` is the entry at index `i + 1`, including those compacted into the snapshot.
+    entries: Vec<Mirrored>,
+    commit_index: u64,
+    last_applied: u64,
+}
+
+/// The safety checker of a raft cluster, fed by the observers made by `InvariantChecker::observer`.
+#[derive(Default)]
+pub struct InvariantChecker {
+    peers: Vec<PeerLog>,
+    /// the leader elected in each term.
+    leaders: HashMap<u64, usize>,
+    /// the longest committed prefix reported by any peer.
+    committed: Vec<Mirrored>,
+    /// the term of the peer that first reported each committed entry,
+    /// the entry is committed in this term or an earlier one.
+    committed_terms: Vec<u64>,
+    /// the known entries by `(index, term)`.
+    known: HashMap<(u64, u64), Mirrored>,
+    /// the recent events, as `(peer, event)`.
+    history: VecDeque<(usize, String)>,
+    violation: Option<String>,
+}
+
+impl InvariantChecker {
+    pub fn new(n: usize) -> InvariantChecker {
+        InvariantChecker {
+            peers: (0..n).map(|_| PeerLog::default()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// make an observer that feeds `checker`, to be set by `Raft::set_observer`.
+    pub fn observer(checker: &Arc<Mutex<InvariantChecker>>) -> RaftObserver {
+        let checker = checker.clone();
+        Arc::new(move |peer, event| checker.lock().unwrap().observe(peer, event))
+    }
+
+    /// the first violation found, with the violating event and the events before it.
+    pub fn violation(&self) -> Option<&str> {
+        self.violation.as_ref().map(String::as_str)
+    }
+
+    /// check an event reported by `peer`.
+    /// Only the first violation is recorded, the events after it are ignored.
+    pub fn observe(&mut self, peer: usize, event: &RaftEvent) {
+        if self.violation.is_some() {
+            return;
+        }
+        let description = match event {
+            // the data of entries is too noisy.
+            RaftEvent::Appended { index, entries } => format!(
+                "Appended {{ index: {}, terms: {:?} }}",
+                index,
+                entries.iter().map(|e| e.term).collect::<Vec<_>>()
+            ),
+            event => format!("{:?}", event),
+        };
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back((peer, description));
+        if let Err(reason) = self.check(peer, event) {
+            let (_, description) = self.history.back().unwrap();
+            let mut report = format!(
+                "NO{} {} violates {}\nrecent events:",
+                peer, description, reason
+            );
+            for (peer, event) in &self.history {
+                report.push_str(&format!("\n  NO{}: {}", peer, event));
+            }
+            error!("{}", report);
+            self.violation = Some(report);
+        }
+    }
+
+    fn check(&mut self, peer: usize, event: &RaftEvent) -> Result<(), String> {
+        match *event {
+            RaftEvent::Elected { term } => self.check_elected(peer, term),
+            RaftEvent::Appended { index, ref entries } => {
+                let log = &mut self.peers[peer];
+                if index != log.entries.len() as u64 + 1 {
+                    return Err(format!(
+                        "Log Matching: appending at {}, but the log ends at {}.",
+                        index,
+                        log.entries.len()
+                    ));
+                }
+                for (entry, index) in entries.iter().zip(index..) {
+                    let prev = log
+                        .entries
+                        .last()
+                        .cloned()
+                        .unwrap_or(Mirrored { term: 0, chain: 0 });
+                    let mirrored = prev.next(entry.term, &entry.data);
+                    let known = *self.known.entry((index, entry.term)).or_insert(mirrored);
+                    if known != mirrored {
+                        return Err(format!(
+                            "Log Matching: the entry at {} of term {} differs from another log, or the logs before it differ.",
+                            index, entry.term
+                        ));
+                    }
+                    log.entries.push(mirrored);
+                }
+                Ok(())
+            }
+            RaftEvent::Truncated { index } => {
+                let log = &mut self.peers[peer];
+                if index <= log.commit_index {
+                    return Err(format!(
+                        "State Machine Safety: truncating from {}, but the entries up to {} are committed.",
+                        index, log.commit_index
+                    ));
+                }
+                log.entries.truncate(index as usize - 1);
+                Ok(())
+            }
+            RaftEvent::LogReset { index, term } => {
+                // a snapshot includes only committed entries.
+                let snapshot_term = match index {
+                    0 => Some(0),
+                    _ => self.committed.get(index as usize - 1).map(|e| e.term),
+                };
+                if snapshot_term != Some(term) {
+                    return Err(format!(
+                        "State Machine Safety: the snapshot to index {} of term {} isn't committed.",
+                        index, term
+                    ));
+                }
+                let log = &mut self.peers[peer];
+                log.entries = self.committed[..index as usize].to_vec();
+                log.commit_index = index;
+                log.last_applied = index;
+                Ok(())
+            }
+            RaftEvent::Committed { index, term } => self.check_committed(peer, index, term),
+            RaftEvent::Applied { index } => {
+                let log = &mut self.peers[peer];
+                if index > log.commit_index || index < log.last_applied {
+                    return Err(format!(
+                        "State Machine Safety: applying to {}, but commit index = {}, last applied = {}.",
+                        index, log.commit_index, log.last_applied
+                    ));
+                }
+                log.last_applied = index;
+                Ok(())
+            }
+        }
+    }
+
+    fn check_elected(&mut self, peer: usize, term: u64) -> Result<(), String> {
+        if let Some(&leader) = self.leaders.get(&term) {
+            if leader != peer {
+                return Err(format!(
+                    "Election Safety: NO{} has been elected in term {}.",
+                    leader, term
+                ));
+            }
+        }
+        self.leaders.insert(term, peer);
+
+        let log = &self.peers[peer];
+        // only the entries committed in earlier terms must be present,
+        // the leader may be reported late.
+        for (i, (entry, committed_term)) in self
+            .committed
+            .iter()
+            .zip(self.committed_terms.iter())
+            .enumerate()
+        {
+            if *committed_term < term && log.entries.get(i) != Some(entry) {
+                return Err(format!(
+                    "Leader Completeness: the entry at {} committed in term {} is missing.",
+                    i + 1,
+                    committed_term
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_committed(&mut self, peer: usize, index: u64, term: u64) -> Result<(), String> {
+        let log = &mut self.peers[peer];
+        if index as usize > log.entries.len() {
+            return Err(format!(
+                "State Machine Safety: committing to {}, but the log ends at {}.",
+                index,
+                log.entries.len()
+            ));
+        }
+        // the entries up to the old commit index have been checked.
+        for i in log.commit_index as usize..index as usize {
+            let entry = log.entries[i];
+            match self.committed.get(i) {
+                Some(committed) if *committed != entry => {
+                    return Err(format!(
+                        "State Machine Safety: the entry at {} of term {} differs from the committed one of term {}.",
+                        i + 1,
+                        entry.term,
+                        committed.term
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    self.committed.push(entry);
+                    self.committed_terms.push(term);
+                }
+            }
+        }
+        log.commit_index = Ord::max(log.commit_index, index);
+        Ok(())
+    }
+}
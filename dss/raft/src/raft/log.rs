@@ -0,0 +1,177 @@
+//! The storage of raft log.
+use crate::raft::LogEntry;
+
+/// The storage of raft log entries.
+///
+/// Entries are indexed from 1. The entries up to `last_included_index` have been compacted
+/// into the snapshot, only the index and term of the last of them are kept.
+pub trait RaftLog: Send {
+    /// the index of the last entry included in the snapshot, `0` if there is no snapshot.
+    fn last_included_index(&self) -> u64;
+
+    /// the term of the last entry included in the snapshot.
+    fn last_included_term(&self) -> u64;
+
+    /// the index of the last entry, which is the same as the log length.
+    fn last_index(&self) -> u64;
+
+    /// the term of the entry at `index`.
+    /// returns `last_included_term` if the entry is in the snapshot, and `0` if there is no such entry.
+    fn term_at(&self, index: u64) -> u64;
+
+    /// get the entry at `index`, `None` if it is in the snapshot or there is no such entry.
+    fn entry(&self, index: u64) -> Option<LogEntry>;
+
+    /// get the entries from `start`, at most `max_entries` of them and `max_bytes` of data,
+    /// but at least one entry if there is any.
+    ///
+    /// # panics
+    /// if `start` is in the snapshot.
+    fn entries(&self, start: u64, max_entries: usize, max_bytes: usize) -> Vec<LogEntry>;
+
+    /// append entries at the end of the log.
+    fn append(&mut self, entries: Vec<LogEntry>);
+
+    /// remove the entries from `index` on.
+    fn truncate(&mut self, index: u64);
+
+    /// compact the entries up to `index`, whose term is `term`, into the snapshot.
+    /// The following entries are kept.
+    fn compact(&mut self, index: u64, term: u64);
+
+    /// the log is restored after a snapshot that includes entries up to `index`, whose term is `term`,
+    /// and keeps the following entries. Unlike `compact`, it takes the term even if the log
+    /// starts at `index` already, since a log storing its entries may not know the term of the snapshot.
+    fn restore_snapshot(&mut self, index: u64, term: u64) {
+        self.compact(index, term);
+    }
+
+    /// discard all entries, the log restarts after a snapshot that includes entries up to `index`.
+    fn reset(&mut self, index: u64, term: u64);
+
+    /// whether the log stores its entries by itself.
+    /// If not, raft saves them along with the raft state by `Persister`.
+    fn is_durable(&self) -> bool {
+        false
+    }
+
+    /// the byte size of the entries stored by the log itself, `0` if it isn't durable.
+    fn stored_bytes(&self) -> usize {
+        0
+    }
+
+    /// make the changes durable at once.
+    fn sync(&mut self) {}
+
+    /// like `sync`, but returns the slow part of it as a job, which raft runs in the background
+    /// after saving its state, and before it counts the entries durable.
+    /// The job makes the changes before this call durable, the log may change meanwhile.
+    fn sync_job(&mut self) -> Option<Box<dyn FnOnce() + Send>> {
+        self.sync();
+        None
+    }
+
+    /// the term of the last entry.
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index())
+    }
+
+    /// whether the entry at `index` has been compacted into the snapshot.
+    fn is_in_snapshot(&self, index: u64) -> bool {
+        index <= self.last_included_index()
+    }
+}
+
+/// A vector with offset, keeps all entries after the snapshot in memory.
+#[derive(Default)]
+pub struct MemoryLog {
+    last_included_index: u64,
+    last_included_term: u64,
+    entries: Vec<LogEntry>,
+}
+
+impl MemoryLog {
+    /// Try to get the position of `index` in `entries`.
+    /// If the index is in snapshot, return `None`.
+    fn checked_offset_index(&self, index: u64) -> Option<usize> {
+        index
+            .checked_sub(self.last_included_index + 1)
+            .map(|offset| offset as usize)
+    }
+
+    fn offset_index(&self, index: u64) -> usize {
+        self.checked_offset_index(index).unwrap_or_else(||
+            panic!("Trying to access a entry that is in the snapshot: snapshot last index = {}, accessing = {}",
+                   self.last_included_index,
+                   index, )
+        )
+    }
+}
+
+impl RaftLog for MemoryLog {
+    fn last_included_index(&self) -> u64 {
+        self.last_included_index
+    }
+
+    fn last_included_term(&self) -> u64 {
+        self.last_included_term
+    }
+
+    fn last_index(&self) -> u64 {
+        self.last_included_index + self.entries.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        self.checked_offset_index(index)
+            .map(|offset| self.entries.get(offset).map(|e| e.term).unwrap_or(0))
+            .unwrap_or(self.last_included_term)
+    }
+
+    fn entry(&self, index: u64) -> Option<LogEntry> {
+        self.checked_offset_index(index)
+            .and_then(|offset| self.entries.get(offset))
+            .cloned()
+    }
+
+    fn entries(&self, start: u64, max_entries: usize, max_bytes: usize) -> Vec<LogEntry> {
+        let offset = self.offset_index(start);
+        let mut bytes = 0;
+        self.entries
+            .get(offset..)
+            .unwrap_or_default()
+            .iter()
+            .take(max_entries)
+            .enumerate()
+            .take_while(|(i, entry)| {
+                bytes += entry.data.len();
+                *i == 0 || bytes <= max_bytes
+            })
+            .map(|(_, entry)| entry.clone())
+            .collect()
+    }
+
+    fn append(&mut self, entries: Vec<LogEntry>) {
+        self.entries.extend(entries)
+    }
+
+    fn truncate(&mut self, index: u64) {
+        let offset = self.offset_index(index);
+        self.entries.truncate(offset)
+    }
+
+    fn compact(&mut self, index: u64, term: u64) {
+        if index <= self.last_included_index {
+            return;
+        }
+        let remained = Ord::min(self.offset_index(index) + 1, self.entries.len());
+        self.entries.drain(..remained);
+        self.last_included_index = index;
+        self.last_included_term = term;
+    }
+
+    fn reset(&mut self, index: u64, term: u64) {
+        self.entries.clear();
+        self.last_included_index = index;
+        self.last_included_term = term;
+    }
+}
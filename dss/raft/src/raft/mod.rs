@@ -2,69 +2,170 @@
 //!
 //! # Raft(lab 2)
 //! ## general
-//! Generally, it starts at `transform_to_follower`, and transform states by the
-//! `transform_*` family, all of which accepts `Arc<Mutex<Raft>>`.
+//! Each peer is driven by an event loop (`Raft::run`) in its own thread, which owns the `Raft`.
+//! `Node` talks with it by sending `Event`s: rpc requests and responses (`Message`), proposals,
+//! reads, and queries of the service (`Event::Call`).
+//! After each event, the loop `tick`s the timers (election, heartbeat and proposal batching),
+//! and it never waits for the next event longer than the nearest deadline.
 //!
-//! All rpc handlers are blocking(!), so `async_rpc!` macro simply start new thread for handler, incase of
-//! them blocks the coroutine runtime.
+//! The timeouts and limits are set by a `RaftConfig` (see `RaftConfig::builder`), passed to `Raft::with_config`.
+//!
+//! Nothing but `persist` blocks in the event loop: rpc requests are handed to it and replied by a
+//! oneshot channel, and the responses of rpcs sent by this peer come back to it as `Message`s.
+//! So a peer needs only two threads, the event loop and the writer of its `AsyncPersister`,
+//! besides the threads of `labrpc`. The groups of a `multi::Host` share both of them.
 //!
-//! This implementation uses thread model, which will bear more context switch and inter-core sync overhead.
-//! But for the coroutine approach, `future 0.1`, which is really tricky for me to adapt it with `async/.await`,
-//! I don't think I can do it well, or at least, be happy when facing chaotic type and compile error information.
 //! All tests just have run on Windows and macOS... I wish it won't fail on Linux...
 //!
 //! ## where to find algorithm implementation
 //! ### election(2A)
-//! election starts from `election_timer` fires, and call to `transform_to_candidate`.
+//! election starts when `election_deadline` passes, and calls `campaign`.
 //!
 //! handler of `RequestVotes` is `do_request_votes`.
 //!
+//! With `RaftConfigBuilder::priorities`, the peers of lower priorities delay their campaigns
+//! while a peer of higher priority is reachable and up-to-date (`campaign_delay`), as the leader
+//! tells by `AppendEntriesArgs::up_to_date_peers`, and an up-to-date peer declines the candidates
+//! of lower priorities (`declines_for_priority`). The leader hands off to a caught-up peer of higher
+//! priority by `transfer_leadership`: the follower campaigns at once on an `AppendEntries` with
+//! `timeout_now`. The hand-off is given up if it doesn't finish in an election timeout.
+//!
 //! ### log replication(2B)
-//! leader sending rpc starts from `transform_to_leader`, but most of logic is in `modify_state_by_append_entries`.
+//! leader sending rpc starts from `tick`, but most of logic is in `modify_state_by_append_entries`.
+//! Each follower has a `Progress`, and `replicate_to` sends what it needs:
+//! in `Replicate` state, `AppendEntries` are pipelined, bounded by `max_inflight_append_entries`.
+//! Proposals by `start` are batched: the leader persists (`flush_proposals`) and replicates
+//! the proposals arriving in `proposal_batch_window` at once.
+//!
+//! `Node::propose` is like `start`, but the leader keeps the proposal in `waiting_proposals`,
+//! and resolves it after applying its index (`resolve_proposals`), or fails it when stepping down.
+//!
+//! A new leader appends a no-op entry in `become_leader` (unless `RaftConfigBuilder::leader_no_op`
+//! turns it off), so the entries of earlier terms get committed (and reads served) without waiting
+//! for a proposal. The service receives it as `ApplyMsg::NoOp`.
 //!
 //! follower handles rpc starts from `do_append_entries`, but most of logic is in `do_append_entries_judge`.
 //!
+//! Every heartbeat, the leader checks whether it has heard from a majority recently (`quorum_active`),
+//! and steps down if not, so an isolated leader won't keep accepting proposals.
+//!
+//! Every transition that matters to safety (election, changes of the log, commit and apply) is
+//! reported as a `RaftEvent` to the observer set by `Raft::set_observer`. The tester feeds them to
+//! `invariants::InvariantChecker`, which checks the safety properties across the cluster.
+//! The service, instead, subscribes the changes of role, term and leader, and the installed snapshots
+//! by `Node::subscribe`, which sends them as `NodeEvent`s.
+//!
 //! ### persist(2C)
 //! Persisting logic is in `persist` and `restore` function.
 //!
+//! The state is saved by an `AsyncPersister`, in a background thread, so the event loop never waits
+//! for the store. `persist` returns at once, and an `Event::Persisted` arrives after the write is saved.
+//! Until then, the rpc replies that promise it (e.g. a vote, or accepted entries) are held
+//! (`reply_persisted`), and a candidate won't become the leader before its own vote is saved.
+//! The leader sends its entries to followers while its own write is in flight, and counts itself
+//! in the quorum of these entries only after the write is saved, so it may commit them with
+//! the writes of followers alone.
+//! Writes go through `Persister::try_save`: a failed one stops the peer (`on_write_failed`)
+//! without replying the requests that depend on it, instead of panicking in the writer.
+//!
+//! Log entries are kept by a `log::RaftLog`. The default `log::MemoryLog` keeps them in memory,
+//! and `persist` saves them along with the raft state; `segmented_log::SegmentedLog` stores them
+//! in segment files by itself, so `persist` only saves the raft state. Pick one by `Raft::with_log`.
+//! `persist` syncs such a log in the background as well (`RaftLog::sync_job`), after the
+//! raft state is saved and before `Event::Persisted`.
+//!
+//! Every entry carries a checksum (`entry_checksum`), and so does the snapshot (`Snapshot::compute_checksum`).
+//! They are verified on `restore`, where a peer refuses to start with a damaged state
+//! (check it first by `Raft::verify_persisted`), and on receipt of `AppendEntries` and `InstallSnapshot`,
+//! where the damaged entries or snapshot are rejected, and the leader sends them again.
+//!
+//! The `raft-dump` binary prints the persisted state and snapshot, see `dump`.
+//!
 //! The optimization that needed for passing `unreliable_figure8_2c` logic is in `do_append_entries`(follower site),
 //! and `modify_state_by_append_entries`(leader site).
 //!
+//! ### snapshot(lab 3B)
+//! When a follower needs entries that are in the snapshot, its `Progress` turns to `Snapshot` state,
+//! and the leader sends the encoded snapshot by `InstallSnapshot`, one chunk of at most `snapshot_chunk_bytes`
+//! at a time (`make_install_snapshot_args`).
+//! The follower assembles chunks in `pending_snapshot`, replies the offset it expects next,
+//! and installs the snapshot after the last chunk arrived and the checksum matched.
+//!
+//! ### state machine(lab 3)
+//! A service implements `state_machine::StateMachine`, and `state_machine::Driver` applies committed
+//! entries to it, de-duplicates retried commands by client sessions, takes snapshots when the raft
+//! state grows too large, and wakes up the proposals and reads waiting on it.
+//!
+//! Snapshots are streamed by chunks: the driver takes a snapshot in the background, locking the
+//! state machine only for a chunk at a time, so applying goes on meanwhile; the chunks are encoded
+//! as they're written (`SnapshotEncoder`), and restored one by one. Raft keeps only the encoded
+//! snapshot, and saves it once after it changes.
+//!
+//! With the `testing` feature, a service is tested by `testing::Cluster`, which runs its servers
+//! over a `labrpc::Network`, crashes, restarts and partitions them, and finds the leader.
+//!
+//! ### read index
+//! `Node::read_index` confirms leadership by a round of heartbeats (see `pending_reads`), and returns
+//! the `commit_index` recorded before the heartbeats, so reads don't need to write anything to the log.
+//!
+//! With `RaftConfigBuilder::read_lease`, a leader that holds a lease (see `has_lease`) skips the heartbeats.
+//! The lease is counted from the `last_contact` of followers, and followers won't vote
+//! while they have heard from the leader recently (`in_leader_lease`).
+//!
+//! ### multi-raft
+//! A peer talks with other peers by a `Transport`, a `RaftClient` for each of them by default.
+//! `multi::Host` runs many raft groups in one event loop: the groups share its transport, whose
+//! messages carry the group id, and its ticks, so the heartbeats of all groups between two stores
+//! are sent together in one rpc. Add a group by `Raft::with_transport` and `multi::Host::add_group`.
+//!
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fmt::Debug;
-use std::ops::Deref;
-use std::ops::{Index, RangeFrom};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use futures::sync::mpsc::UnboundedSender;
-use futures::Future;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
 use rand::Rng;
-use rayon::ThreadPoolBuilder;
 
 use labcodec::{decode, encode};
 use labrpc::RpcFuture;
 
-use crate::proto::raftpb::raft::Client;
 use crate::proto::raftpb::*;
 use crate::raft::RaftRole::{Candidate, Follower, Leader};
-use crate::{
-    select, ThreadPoolWithDrop, Timer,
-    TimerMsg::{self, *},
-};
-
-use super::async_rpc;
 
+use self::async_persister::AsyncPersister;
 use self::errors::*;
+use self::log::{MemoryLog, RaftLog};
 use self::persister::*;
+pub use self::raft_config::{RaftConfig, RaftConfigBuilder};
+use self::state_machine::SnapshotWriter;
+pub use self::transport::Transport;
 
+mod async_persister;
 #[cfg(test)]
 pub mod config;
+pub mod dump;
 pub mod errors;
+#[cfg(any(test, feature = "testing"))]
+pub mod invariants;
+pub mod log;
+pub mod multi;
+#[cfg(any(test, feature = "testing"))]
+pub mod nemesis;
 pub mod persister;
+pub mod raft_config;
+pub mod segmented_log;
+pub mod state_machine;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 #[cfg(test)]
 mod tests;
+pub mod transport;
 
 /// the snapshot of raft state.
 pub struct SnapshotFile {
@@ -77,157 +178,174 @@ impl Default for SnapshotFile {
     }
 }
 
-/// A vector with offset.
-struct RaftLogWithSnapShot {
+/// A `Snapshot` encoded as it's saved, and sent by chunks of `InstallSnapshot`.
+struct EncodedSnapshot {
+    data: Vec<u8>,
+    /// `fnv1a(data)`, which `InstallSnapshot` carries.
+    checksum: u64,
+}
+
+/// A `Snapshot` encoded chunk by chunk as the service writes them, so raft never holds the chunks
+/// and their encoding at the same time. It's handed to raft by `Node::take_encoded_snapshot`.
+pub struct SnapshotEncoder {
     last_included_index: u64,
     last_included_term: u64,
-    state_machine_state: SnapshotFile,
-    commands: Vec<LogEntry>,
+    data: Vec<u8>,
+    /// `Snapshot::compute_checksum` of the chunks written so far.
+    checksum: u64,
 }
 
-impl Default for RaftLogWithSnapShot {
-    fn default() -> Self {
-        RaftLogWithSnapShot {
-            last_included_index: 0,
-            last_included_term: 0,
-            state_machine_state: SnapshotFile::default(),
-            commands: vec![],
+impl SnapshotEncoder {
+    /// begin the snapshot of the entries up to `last_included_index`, whose term is `last_included_term`.
+    pub fn new(last_included_index: u64, last_included_term: u64) -> SnapshotEncoder {
+        let hash = fnv1a(&last_included_index.to_le_bytes());
+        SnapshotEncoder {
+            last_included_index,
+            last_included_term,
+            data: vec![],
+            checksum: fnv1a_extend(hash, &last_included_term.to_le_bytes()),
         }
     }
-}
 
-impl Into<RaftLogWithSnapShot> for InstallSnapshotArgs {
-    fn into(self) -> RaftLogWithSnapShot {
-        RaftLogWithSnapShot {
-            last_included_index: self.last_included_index,
-            last_included_term: self.last_included_term,
-            state_machine_state: SnapshotFile {
-                commands: self.data,
-            },
-            commands: vec![],
+    /// encode the last included entry and the checksum after the chunks.
+    fn finish(mut self) -> EncodedSnapshot {
+        // concatenated messages are merged when decoded, the chunks are appended in order.
+        let tail = Snapshot {
+            state_machine_state: vec![],
+            last_index_of_snapshot: self.last_included_index,
+            last_term_of_snapshot: self.last_included_term,
+            checksum: self.checksum,
+        };
+        encode(&tail, &mut self.data).unwrap();
+        EncodedSnapshot {
+            checksum: fnv1a(&self.data),
+            data: self.data,
         }
     }
 }
 
-impl RaftLogWithSnapShot {
-    fn is_in_snapshot(&self, index: usize) -> bool {
-        self.checked_offset_index(index).is_none()
-    }
-
-    fn offset_index(&self, origin: usize) -> usize {
-        self.checked_offset_index(origin).unwrap_or_else(||
-            panic!("Trying to access a entry that is in the snapshot: snapshot last index = {}, accessing = {}",
-                   self.last_included_index,
-                   origin, )
-        )
-    }
-
-    /// Try to get the origin index in the log.
-    /// If the index is in snapshot, return `None`.
-    fn checked_offset_index(&self, origin: usize) -> Option<usize> {
-        origin.checked_sub((self.last_included_index + 1) as usize)
-    }
-
-    /// Get length of the logs.
-    fn len(&self) -> usize {
-        self.last_included_index as usize + self.commands.len()
-    }
-
-    /// Push a new log entry into log.
-    fn push(&mut self, log: LogEntry) {
-        self.commands.push(log)
-    }
-
-    /// Get a log entry at the exact place.
-    /// like `log[n]`
-    fn get(&self, n: usize) -> Option<&LogEntry> {
-        if n == 0 {
-            return None;
-        }
-        self.commands.get(self.offset_index(n))
-    }
-
-    /// Get all entries after the nth place of log entry.
-    /// like `log[n..]`
-    fn after(&self, n: usize) -> &[LogEntry] {
-        let offset_idx = self.offset_index(n);
-        &self.commands[offset_idx..]
-    }
-
-    /// Remove all entries after `target_size`.
-    /// i.e. shrink the vector length to `target_size`.
-    fn truncate(&mut self, target_size: usize) {
-        self.commands.truncate(self.offset_index(target_size))
-    }
-
-    /// Get the term of the last entry.
-    /// When here isn't any entry, returns `0`.
-    fn last_term(&self) -> u64 {
-        self.commands
-            .last()
-            .map(|e| e.term)
-            .unwrap_or(self.last_included_term)
+impl SnapshotWriter for SnapshotEncoder {
+    fn write(&mut self, chunk: Vec<u8>) {
+        self.checksum = fnv1a_extend(self.checksum, &(chunk.len() as u64).to_le_bytes());
+        self.checksum = fnv1a_extend(self.checksum, &chunk);
+        let message = Snapshot {
+            state_machine_state: vec![chunk],
+            ..Snapshot::default()
+        };
+        encode(&message, &mut self.data).unwrap();
     }
+}
 
-    fn term_at(&self, n: usize) -> u64 {
-        self.checked_offset_index(n)
-            .map(|after_offset| self.commands.get(after_offset).map(|e| e.term).unwrap_or(0))
-            .unwrap_or(self.last_included_term)
-    }
+/// The chunks of a `Snapshot` that the follower has received.
+struct PendingSnapshot {
+    last_included_index: u64,
+    last_included_term: u64,
+    checksum: u64,
+    data: Vec<u8>,
+}
 
-    /// Return the iter of current log vector.
-    /// [Excludes the snapshot!]
-    fn iter(&self) -> impl Iterator<Item = &LogEntry> {
-        self.commands.iter()
+impl PendingSnapshot {
+    /// whether `args` is a chunk of this snapshot.
+    fn accepts(&self, args: &InstallSnapshotArgs) -> bool {
+        self.last_included_index == args.last_included_index
+            && self.last_included_term == args.last_included_term
+            && self.checksum == args.checksum
     }
 }
 
-impl Index<usize> for RaftLogWithSnapShot {
-    type Output = LogEntry;
-
-    fn index(&self, index: usize) -> &Self::Output {
-        self.get(index).expect("Index out of bound.")
-    }
+/// the 64-bit FNV-1a hash of `data`.
+fn fnv1a(data: &[u8]) -> u64 {
+    fnv1a_extend(0xcbf2_9ce4_8422_2325, data)
 }
 
-impl Index<RangeFrom<usize>> for RaftLogWithSnapShot {
-    type Output = [LogEntry];
+/// continue the FNV-1a hash `hash` with `data`.
+fn fnv1a_extend(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
 
-    fn index(&self, index: RangeFrom<usize>) -> &Self::Output {
-        self.after(index.start)
-    }
+/// the digest of the election priorities, which the peers compare to find different ones.
+fn priorities_digest(priorities: &[u64]) -> u64 {
+    priorities
+        .iter()
+        .fold(fnv1a(&[]), |hash, p| fnv1a_extend(hash, &p.to_le_bytes()))
 }
 
-/// Some additional configuration options of Raft.
-struct RaftConfig {
-    /// Depends how often the leader sends append_entries during idle periods.
-    leader_append_entries_delay: Duration,
-    /// Tolerance for high latency.
-    /// The higher this value, the higher the probability of receiving an append_entries response in a high-latency network.
-    /// However, in order to prevent IO from blocking new requests, more threads will be started.
-    latency_tolerance_factor: f64,
+/// the checksum of a log entry, which is carried by `ProtoEntry` and stored by `SegmentedLog`.
+pub(crate) fn entry_checksum(term: u64, no_op: bool, data: &[u8]) -> u64 {
+    let hash = fnv1a_extend(fnv1a(&term.to_le_bytes()), &[no_op as u8]);
+    fnv1a_extend(hash, data)
 }
 
-impl RaftConfig {
-    fn get_timeout(&self) -> Duration {
-        self.leader_append_entries_delay
-            .mul_f64(self.latency_tolerance_factor)
-    }
+/// The message raft sends to the service, in the order of the log.
+#[derive(Clone, Debug)]
+pub enum ApplyMsg {
+    /// A committed command proposed by `Node::start`.
+    Command {
+        index: u64,
+        term: u64,
+        data: Vec<u8>,
+    },
+    /// The service should replace its state by the snapshot, which includes
+    /// all entries up to `last_included_index`.
+    InstallSnapshot {
+        last_included_index: u64,
+        last_included_term: u64,
+        commands: Vec<Vec<u8>>,
+    },
+    /// A committed no-op entry, appended by a leader of `term`.
+    NoOp { index: u64, term: u64 },
 }
 
-impl Default for RaftConfig {
-    fn default() -> Self {
-        RaftConfig {
-            leader_append_entries_delay: Duration::from_millis(40),
-            latency_tolerance_factor: 1.5,
+impl ApplyMsg {
+    /// the index of the last entry this message covers.
+    pub fn index(&self) -> u64 {
+        match *self {
+            ApplyMsg::Command { index, .. } | ApplyMsg::NoOp { index, .. } => index,
+            ApplyMsg::InstallSnapshot {
+                last_included_index,
+                ..
+            } => last_included_index,
         }
     }
 }
 
-pub struct ApplyMsg {
-    pub command_valid: bool,
-    pub command: Vec<u8>,
-    pub command_index: u64,
+/// The transitions of a raft peer, reported to the observer set by `Raft::set_observer`.
+#[derive(Clone, Debug)]
+pub enum RaftEvent {
+    /// The peer becomes the leader of `term`.
+    Elected { term: u64 },
+    /// `entries` are appended to the log, the first of them at `index`.
+    Appended { index: u64, entries: Vec<LogEntry> },
+    /// The entries from `index` on are removed.
+    Truncated { index: u64 },
+    /// The log restarts after a snapshot that includes the entries up to `index`,
+    /// when installing a snapshot or restarting.
+    LogReset { index: u64, term: u64 },
+    /// `commit_index` advances to `index`, when the peer is in `term`.
+    Committed { index: u64, term: u64 },
+    /// The entries up to `index` have been sent to the apply channel.
+    Applied { index: u64 },
+}
+
+/// The observer of `RaftEvent`s, called with the id of the peer in its event loop.
+pub type RaftObserver = Arc<dyn Fn(usize, &RaftEvent) + Send + Sync>;
+
+/// The changes of a peer that the service may react to, see `Node::subscribe`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeEvent {
+    /// The peer turns to `role` in `term`, it turns to a follower when it's killed.
+    RoleChanged { role: Role, term: u64 },
+    /// The peer enters `term`.
+    TermChanged { term: u64 },
+    /// The peer learns the leader of `term`, `None` when it enters a term without knowing the leader.
+    LeaderChanged { leader: Option<u64>, term: u64 },
+    /// The peer installs a snapshot from the leader, which includes the entries up to `last_included_index`.
+    SnapshotInstalled {
+        last_included_index: u64,
+        last_included_term: u64,
+    },
 }
 
 /// State of a raft peer.
@@ -248,29 +366,142 @@ impl State {
     }
 }
 
-#[allow(dead_code)]
-struct SentAppendEntriesRequest {
+/// The response of a rpc that this peer sent to `follower`.
+struct Response<Req, Res> {
     follower: usize,
-    request: AppendEntriesArgs,
-    response: Receiver<Result<AppendEntriesReply>>,
+    request: Req,
+    reply: Res,
+    /// when the request was sent.
+    sent_at: Instant,
 }
 
-struct SentRequest<Req, Res> {
-    follower: usize,
-    request: Req,
-    response: Receiver<Result<Res>>,
+/// The messages between peers:
+/// rpc requests from other peers (with the channel to reply), and responses of rpcs sent by this peer.
+enum Message {
+    RequestVote(RequestVoteArgs, oneshot::Sender<RequestVoteReply>),
+    AppendEntries(AppendEntriesArgs, oneshot::Sender<AppendEntriesReply>),
+    InstallSnapshot(InstallSnapshotArgs, oneshot::Sender<InstallSnapshotReply>),
+    RequestVoteResponse(Response<RequestVoteArgs, RequestVoteReply>),
+    AppendEntriesResponse(Response<AppendEntriesArgs, AppendEntriesReply>),
+    InstallSnapshotResponse(Response<InstallSnapshotArgs, InstallSnapshotReply>),
+}
+
+/// The events that drive the event loop of a peer, see `Raft::run`.
+enum Event {
+    Message(Message),
+    /// an encoded command from `Node::start`,
+    /// or from `Node::propose` with the channel to notify after the entry is applied.
+    Propose(
+        Vec<u8>,
+        Sender<Result<(u64, u64)>>,
+        Option<oneshot::Sender<Result<(u64, u64)>>>,
+    ),
+    /// a read from `Node::read_index`.
+    ReadIndex(Sender<Result<u64>>),
+    /// run a function on the raft, for the queries of `Node`.
+    Call(Box<dyn FnOnce(&mut Raft) + Send>),
+    /// the `write`th write of `persist` has been saved, with the log up to `index` in `term`.
+    Persisted {
+        write: u64,
+        term: u64,
+        index: u64,
+    },
+    /// a write of `persist` has failed.
+    WriteFailed(io::Error),
+    /// stop the event loop, and notify the sender after stopped.
+    Kill(Sender<()>),
+}
+
+/// A `read_index` waiting for a majority to confirm the leadership.
+struct PendingRead {
+    index: u64,
+    /// only the requests sent after this can confirm the read.
+    started_at: Instant,
+    reply: Sender<Result<u64>>,
+}
+
+/// A proposal by `Node::propose`, waiting for its entry to be applied.
+struct WaitingProposal {
+    term: u64,
+    reply: oneshot::Sender<Result<(u64, u64)>>,
+}
+
+/// The future of a proposal by `Node::propose`.
+///
+/// It resolves with the `(index, term)` of the entry after the entry has been committed
+/// and sent to the apply channel, and fails with:
+/// - `NotCommitted` if another entry is committed at its index.
+/// - `NotLeader` if the leader has lost its leadership before the entry is applied.
+///   The entry may still be committed by the next leader.
+/// - `Stopped` if the raft has been killed.
+pub struct Proposal {
+    index: u64,
+    term: u64,
+    applied: oneshot::Receiver<Result<(u64, u64)>>,
+}
+
+impl Proposal {
+    /// the index that the command will appear at if it's ever committed.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// the term of the leader that accepted the proposal.
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+}
+
+impl Future for Proposal {
+    type Item = (u64, u64);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(u64, u64), Error> {
+        match self.applied.poll() {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(Error::Stopped),
+        }
+    }
 }
 
-impl<Arg, Rep> From<(usize, Arg, Receiver<Result<Rep>>)> for SentRequest<Arg, Rep> {
-    fn from(origin: (usize, Arg, Receiver<Result<Rep>>)) -> Self {
-        SentRequest {
-            follower: origin.0,
-            request: origin.1,
-            response: origin.2,
+/// Where the events of a raft go: its own event loop, or the `multi::Host` of its group.
+#[derive(Clone)]
+enum Mailbox {
+    Peer(Sender<Event>),
+    Group(u64, Sender<multi::HostMessage>),
+}
+
+impl Mailbox {
+    /// # returns
+    /// `false` if the event loop has stopped.
+    fn send(&self, event: Event) -> bool {
+        match self {
+            Mailbox::Peer(sender) => sender.send(event).is_ok(),
+            Mailbox::Group(group, sender) => sender
+                .send(multi::HostMessage::Event(*group, event))
+                .is_ok(),
         }
     }
 }
 
+/// send the result of an event back, the receiver may have gone.
+fn reply<T>(sender: &Sender<T>, value: T) {
+    if sender.send(value).is_err() {
+        debug!("the receiver of reply has gone.");
+    }
+}
+
+/// reply a rpc request handled by the event loop.
+fn reply_rpc<T: Debug>(sender: oneshot::Sender<T>, value: T) {
+    sender.send(value).unwrap_or_else(|value| {
+        warn!(
+            "RPC channel exception, RPC reply {:?} won't be sent.",
+            value
+        )
+    });
+}
+
 #[derive(Clone, Eq, PartialEq, Copy, Debug)]
 enum RaftRole {
     Leader = 0,
@@ -281,38 +512,83 @@ enum RaftRole {
 // A single Raft peer.
 pub struct Raft {
     // RPC end points of all peers
-    peers: Vec<RaftClient>,
-    // Object to hold this peer's persisted state
-    persister: Box<dyn Persister>,
+    peers: Box<dyn Transport>,
+    // Object to hold this peer's persisted state, written in the background.
+    persister: AsyncPersister,
     // this peer's index into peers[]
     me: usize,
-    state: Arc<State>,
+    /// the state published to `Node`, updated after each event.
+    state: Arc<Mutex<State>>,
+    /// the sender of events to the event loop of this peer.
+    events: Mailbox,
+    /// the receiver of events, taken by `Node::new`, or dropped by `multi::Host::add_group`.
+    event_rx: Option<Receiver<Event>>,
 
     // state a Raft server must maintain.
     apply_ch: UnboundedSender<ApplyMsg>,
     current_role: RaftRole,
-    election_timer: Option<Timer>,
+    /// when the election timer fires, `None` if it is stopped.
+    election_deadline: Option<Instant>,
 
     // stored state.
     term: u64,
     voted_for: Option<usize>,
-    // TODO: Generify Log by `RaftLog` trait.
-    log: RaftLogWithSnapShot,
+    /// the leader of current term this peer knows, `None` if unknown.
+    leader_id: Option<usize>,
+    log: Box<dyn RaftLog>,
+    /// the latest snapshot, which includes entries up to `log.last_included_index()`.
+    snapshot: EncodedSnapshot,
+    /// whether `snapshot` has been handed to the persister, so the raft state is saved alone.
+    snapshot_saved: bool,
 
     // in-memory state
     commit_index: u64,
     last_applied: u64,
 
+    // candidate state
+    /// how many votes the candidate has got in current term.
+    votes: usize,
+    /// the count of writes issued once the candidate has voted for itself.
+    /// It wins the election only after they're saved, see `check_votes`.
+    campaign_write: u64,
+    /// whether the next campaign is started by the leader handing off to this peer,
+    /// see `AppendEntriesArgs::timeout_now`.
+    leader_transfer: bool,
+    /// when each peer was last known to be reachable and up-to-date, by the leader's
+    /// `AppendEntriesArgs::up_to_date_peers`, or its own `RequestVote`. See `campaign_delay`.
+    up_to_date_since: Vec<Option<Instant>>,
+
     // leader state
     leader_state: Option<LeaderState>,
-    /// the thread pool to execute leader rpc.
-    leader_execution_pool: ThreadPoolWithDrop,
+    /// the reads waiting for the confirmation of leadership.
+    pending_reads: Vec<PendingRead>,
+    /// the proposals waiting for their entries to be applied, by index.
+    waiting_proposals: BTreeMap<u64, WaitingProposal>,
 
     // misc
-    /// config, including timeout and thread pool size.
+    /// whether a write of the persisted state has failed, then the peer stops after
+    /// the current event, without replying the requests that depend on the write.
+    write_failed: bool,
+    /// the count of writes of the persisted state issued by `persist`, and the count saved.
+    writes_issued: u64,
+    writes_saved: u64,
+    /// the rpc replies waiting for the writes issued before them, by `writes_issued` at the time.
+    held_replies: VecDeque<(u64, Box<dyn FnOnce() + Send>)>,
+    /// config, including timeouts and the limits of replication.
     extra: RaftConfig,
-    /// the byte size of log of last snapshot.
-    log_size: usize,
+    /// the byte size of the raft state last saved, with the log stored by itself.
+    /// It's shared with `Node`, so the service checks it without a call into the event loop.
+    log_size: Arc<AtomicUsize>,
+    /// when this peer heard from a valid leader lastly.
+    last_leader_contact: Option<Instant>,
+    /// the follower's incomplete snapshot received by `InstallSnapshot`.
+    pending_snapshot: Option<PendingSnapshot>,
+    /// the observer of transitions, see `set_observer`.
+    observer: Option<RaftObserver>,
+    /// the subscribers of `NodeEvent`s, see `Node::subscribe`.
+    subscribers: Vec<UnboundedSender<NodeEvent>>,
+    /// how far the clock of this peer has jumped forward, see `now`.
+    clock_offset: Duration,
 }
 
 /// A raft log entry.
@@ -320,27 +596,177 @@ pub struct Raft {
 pub struct LogEntry {
     pub data: Vec<u8>,
     pub term: u64,
+    /// whether it's the no-op entry of a new leader, which isn't a command of the service.
+    pub no_op: bool,
 }
 
 impl LogEntry {
     fn new(data: Vec<u8>, term: u64) -> Self {
-        LogEntry { data, term }
+        LogEntry {
+            data,
+            term,
+            no_op: false,
+        }
+    }
+
+    /// the no-op entry a leader of `term` appends when it's elected.
+    fn no_op(term: u64) -> Self {
+        LogEntry {
+            data: vec![],
+            term,
+            no_op: true,
+        }
+    }
+}
+
+/// The replication state of a follower, see `Progress`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ProgressState {
+    /// The leader doesn't know where the log of follower matches,
+    /// so it sends at most one `AppendEntries` each heartbeat to find it out.
+    Probe,
+    /// The leader sends `AppendEntries` as soon as new entries arrive, without waiting
+    /// for responses, at most `max_inflight_append_entries` of them are in flight.
+    Replicate,
+    /// The follower is so far behind that the leader sends it the snapshot,
+    /// one `InstallSnapshot` chunk in flight at a time.
+    Snapshot,
+}
+
+/// The leader's view of a follower.
+#[derive(Clone, Debug)]
+struct Progress {
+    state: ProgressState,
+    match_index: u64,
+    next_index: u64,
+    /// the last index of each in-flight `AppendEntries`, in the order they were sent.
+    inflight: VecDeque<u64>,
+    /// in `Probe` or `Snapshot` state, whether the request is in flight.
+    paused: bool,
+    /// in `Snapshot` state, the last included index of the snapshot being sent.
+    snapshot_index: u64,
+    /// in `Snapshot` state, the offset of the next chunk to send.
+    snapshot_offset: u64,
+    /// when the latest request acknowledged by the follower was sent.
+    last_contact: Option<Instant>,
+}
+
+impl Progress {
+    fn new(next_index: u64) -> Self {
+        Progress {
+            state: ProgressState::Probe,
+            match_index: 0,
+            next_index,
+            inflight: VecDeque::new(),
+            paused: false,
+            snapshot_index: 0,
+            snapshot_offset: 0,
+            last_contact: None,
+        }
+    }
+
+    fn become_probe(&mut self) {
+        self.state = ProgressState::Probe;
+        self.inflight.clear();
+        self.paused = false;
+    }
+
+    /// become `Replicate`, and (re)send entries since `match_index`.
+    fn become_replicate(&mut self) {
+        self.state = ProgressState::Replicate;
+        self.inflight.clear();
+        self.next_index = self.match_index + 1;
+    }
+
+    /// become `Snapshot`, and send the snapshot at `snapshot_index` from the beginning.
+    fn become_snapshot(&mut self, snapshot_index: u64) {
+        self.state = ProgressState::Snapshot;
+        self.inflight.clear();
+        self.paused = false;
+        self.snapshot_index = snapshot_index;
+        self.snapshot_offset = 0;
+    }
+
+    /// whether the leader can send one more `AppendEntries` to the follower now.
+    fn can_send(&self, max_inflight: usize) -> bool {
+        match self.state {
+            ProgressState::Probe => !self.paused,
+            ProgressState::Replicate => self.inflight.len() < max_inflight,
+            ProgressState::Snapshot => false,
+        }
+    }
+
+    /// update the progress after sending entries until `last`.
+    /// In `Replicate` state, `next_index` is advanced optimistically.
+    fn on_sent(&mut self, last: u64) {
+        match self.state {
+            ProgressState::Probe => self.paused = true,
+            ProgressState::Replicate => {
+                self.next_index = last + 1;
+                self.inflight.push_back(last);
+            }
+            ProgressState::Snapshot => {}
+        }
+    }
+
+    /// update the progress by an accepted `AppendEntries`, which matches until `matching`.
+    ///
+    /// # returns
+    /// whether `match_index` is advanced.
+    fn on_accepted(&mut self, matching: u64) -> bool {
+        while self
+            .inflight
+            .front()
+            .map_or(false, |last| *last <= matching)
+        {
+            self.inflight.pop_front();
+        }
+        // 防止返回乱序……
+        let advanced = matching > self.match_index;
+        if advanced {
+            self.match_index = matching;
+        }
+        if self.next_index <= matching {
+            self.next_index = matching + 1;
+        }
+        if self.state == ProgressState::Probe {
+            self.become_replicate();
+        }
+        advanced
     }
 }
 
 #[derive(Debug, Clone)]
 struct LeaderState {
-    next_index: Vec<u64>,
-    match_index: Vec<u64>,
-    new_request: Sender<()>,
+    progress: Vec<Progress>,
+    /// when to send the next heartbeat.
+    next_heartbeat: Instant,
+    /// when to flush the batched proposals, `None` if there is no proposal pending.
+    flush_at: Option<Instant>,
+    /// count of the proposals that haven't been flushed by `flush_proposals`.
+    pending_proposals: usize,
+    /// byte size of the proposals that haven't been flushed by `flush_proposals`.
+    pending_bytes: usize,
+    /// when this peer became the leader.
+    elected_at: Instant,
+    /// the peer this leader hands off to, and when it began, see `transfer_leadership`.
+    transferee: Option<(usize, Instant)>,
+    /// when the lease resumes after a hand-off is given up, since the follower may still
+    /// campaign by a delayed `timeout_now`.
+    lease_paused_until: Option<Instant>,
 }
 
 impl LeaderState {
-    fn by_raft(raft: &Raft, sx: Sender<()>) -> Self {
+    fn by_raft(raft: &Raft) -> Self {
         LeaderState {
-            next_index: vec![raft.last_log_index() + 1; raft.peers.len()],
-            match_index: vec![0; raft.peers.len()],
-            new_request: sx,
+            progress: vec![Progress::new(raft.last_log_index() + 1); raft.peers.peer_count()],
+            next_heartbeat: raft.now(),
+            flush_at: None,
+            pending_proposals: 0,
+            pending_bytes: 0,
+            elected_at: raft.now(),
+            transferee: None,
+            lease_paused_until: None,
         }
     }
 }
@@ -373,26 +799,84 @@ impl PersistedStatus {
         // Finally, what did I do?
         // Just take a snapshot at `kvraft::server::Node::kill`,
         // which is tricky, but maybe effective :).
-        let logs = raft.log.iter().cloned().map(Into::into).collect();
+        // a durable log stores entries by itself.
+        let logs = if raft.log.is_durable() {
+            vec![]
+        } else {
+            raft.log
+                .entries(
+                    raft.log.last_included_index() + 1,
+                    usize::max_value(),
+                    usize::max_value(),
+                )
+                .into_iter()
+                .map(Into::into)
+                .collect()
+        };
 
         PersistedStatus {
             current_term: raft.term,
             voted_for: raft.voted_for.iter().map(|x| *x as u64).collect(),
             logs,
+            last_included_index: raft.log.last_included_index(),
+            last_included_term: raft.log.last_included_term(),
         }
     }
 }
 
 impl Snapshot {
-    fn by_raft(raft: &Raft) -> Self {
-        Snapshot {
-            state_machine_state: raft.log.state_machine_state.commands.clone(),
-            last_index_of_snapshot: raft.log.last_included_index,
-            last_term_of_snapshot: raft.log.last_included_term,
+    /// the checksum of the last included entry and the chunks.
+    fn compute_checksum(&self) -> u64 {
+        let mut hash = fnv1a(&self.last_index_of_snapshot.to_le_bytes());
+        hash = fnv1a_extend(hash, &self.last_term_of_snapshot.to_le_bytes());
+        for chunk in &self.state_machine_state {
+            hash = fnv1a_extend(hash, &(chunk.len() as u64).to_le_bytes());
+            hash = fnv1a_extend(hash, chunk);
         }
+        hash
+    }
+
+    fn is_intact(&self) -> bool {
+        self.checksum == self.compute_checksum()
+    }
+}
+
+impl ProtoEntry {
+    fn is_intact(&self) -> bool {
+        self.checksum == entry_checksum(self.term, self.no_op, &self.command)
     }
 }
 
+/// decode and verify the persisted state.
+///
+/// # returns
+/// `Error::Decode` or `Error::Corrupted` if the state is damaged,
+/// e.g. the raft state follows a snapshot newer than the saved one.
+fn decode_persisted(log: &[u8], snapshot: &[u8]) -> Result<(PersistedStatus, Snapshot)> {
+    let state = decode::<PersistedStatus>(log).map_err(Error::Decode)?;
+    let snapshot = decode::<Snapshot>(snapshot).map_err(Error::Decode)?;
+    if !snapshot.is_intact() {
+        return Err(Error::Corrupted(format!(
+            "the snapshot to index {} mismatches its checksum",
+            snapshot.last_index_of_snapshot
+        )));
+    }
+    if let Some(i) = state.logs.iter().position(|entry| !entry.is_intact()) {
+        return Err(Error::Corrupted(format!(
+            "the persisted entry at {} mismatches its checksum",
+            state.last_included_index + 1 + i as u64
+        )));
+    }
+    // the snapshot is saved before the raft state, so a torn write leaves an older raft state.
+    if state.last_included_index > snapshot.last_index_of_snapshot {
+        return Err(Error::Corrupted(format!(
+            "the raft state follows the snapshot to index {}, but the saved one is to index {}",
+            state.last_included_index, snapshot.last_index_of_snapshot
+        )));
+    }
+    Ok((state, snapshot))
+}
+
 impl Raft {
     /// the service or tester wants to create a Raft server. the ports
     /// of all the Raft servers (including this one) are in peers. this
@@ -408,140 +892,402 @@ impl Raft {
         persister: Box<dyn Persister>,
         apply_ch: UnboundedSender<ApplyMsg>,
     ) -> Raft {
+        Raft::with_config(peers, me, persister, apply_ch, RaftConfig::default())
+    }
+
+    /// like `new`, but with a `RaftConfig` built by `RaftConfig::builder`.
+    pub fn with_config(
+        peers: Vec<RaftClient>,
+        me: usize,
+        persister: Box<dyn Persister>,
+        apply_ch: UnboundedSender<ApplyMsg>,
+        config: RaftConfig,
+    ) -> Raft {
+        let log = Box::new(MemoryLog::default());
+        Raft::with_log(peers, me, persister, apply_ch, config, log)
+    }
+
+    /// like `with_config`, but stores the log entries in `log`, e.g. a `SegmentedLog`.
+    ///
+    /// A durable log must be opened from the storage along with `persister`.
+    pub fn with_log(
+        peers: Vec<RaftClient>,
+        me: usize,
+        persister: Box<dyn Persister>,
+        apply_ch: UnboundedSender<ApplyMsg>,
+        config: RaftConfig,
+        log: Box<dyn RaftLog>,
+    ) -> Raft {
+        Raft::with_transport(Box::new(peers), me, persister, apply_ch, config, log)
+    }
+
+    /// like `with_log`, but talks with the other peers by `transport`,
+    /// e.g. the one of a group hosted by `multi::Host`.
+    ///
+    /// # panics
+    /// if `config` doesn't fit the peers, see `RaftConfig::check_peers`,
+    /// or the persisted state is damaged, see `verify_persisted`.
+    pub fn with_transport(
+        peers: Box<dyn Transport>,
+        me: usize,
+        persister: Box<dyn Persister>,
+        apply_ch: UnboundedSender<ApplyMsg>,
+        config: RaftConfig,
+        log: Box<dyn RaftLog>,
+    ) -> Raft {
+        let peer_count = peers.peer_count();
+        config
+            .check_peers(peer_count)
+            .unwrap_or_else(|e| panic!("NO{} refuses to start: {}", me, e));
         let raft_state = persister.raft_state();
         let snapshot = persister.snapshot();
         // Your initialization code here (2A, 2B, 2C).
-        let peer_count = peers.len();
-        let config = RaftConfig::default();
+        let (events, event_rx) = channel();
         let mut rf = Raft {
             peers,
-            persister,
+            persister: AsyncPersister::new(persister),
             me,
             state: Arc::default(),
+            events: Mailbox::Peer(events),
+            event_rx: Some(event_rx),
             apply_ch,
             current_role: Follower,
             term: 0,
             voted_for: None,
-            election_timer: None,
-            log: RaftLogWithSnapShot::default(),
+            election_deadline: None,
+            log,
+            snapshot: SnapshotEncoder::new(0, 0).finish(),
+            snapshot_saved: false,
             commit_index: 0,
             last_applied: 0,
+            votes: 0,
+            campaign_write: 0,
+            leader_transfer: false,
+            up_to_date_since: vec![None; peer_count],
             leader_state: None,
-            leader_execution_pool: ThreadPoolBuilder::new()
-                .thread_name(move |n| format!("[`{}`] leader execution worker ({})", me, n))
-                .num_threads(((peer_count as f64) * config.latency_tolerance_factor) as usize)
-                .build()
-                .unwrap()
-                .into(),
+            pending_reads: vec![],
+            waiting_proposals: BTreeMap::new(),
             extra: config,
-            log_size: 0,
+            log_size: Arc::default(),
+            last_leader_contact: None,
+            leader_id: None,
+            pending_snapshot: None,
+            observer: None,
+            subscribers: vec![],
+            clock_offset: Duration::default(),
+            write_failed: false,
+            writes_issued: 0,
+            writes_saved: 0,
+            held_replies: VecDeque::new(),
         };
 
         // initialize from state persisted before a crash
-        rf.restore(&raft_state, &snapshot);
+        rf.restore(&raft_state, snapshot)
+            .unwrap_or_else(|e| panic!("NO{} refuses to start: {}", me, e));
 
         rf
     }
 
+    /// check the state in `persister` before starting a peer with it,
+    /// the constructors panic if the state is damaged.
+    ///
+    /// # returns
+    /// `Error::Decode` or `Error::Corrupted` if the state is damaged.
+    pub fn verify_persisted(persister: &dyn Persister) -> Result<()> {
+        let raft_state = persister.raft_state();
+        let snapshot = persister.snapshot();
+        if raft_state.is_empty() && snapshot.is_empty() {
+            return Ok(());
+        }
+        decode_persisted(&raft_state, &snapshot).map(|_| ())
+    }
+
     /// save Raft's persistent state to stable storage,
     /// where it can later be retrieved after a crash and restart.
     /// see paper's Figure 2 for a description of what should be persistent.
+    ///
+    /// It returns before the write is saved, so the event loop never blocks on the store.
+    /// Once it's saved, and the log is synced, an `Event::Persisted` tells the event loop that the
+    /// log up to the current last index is durable. Meanwhile, the replies that depend on the write
+    /// are held (`reply_persisted`). The writes are saved in the order they are issued.
+    /// A failed write stops this peer, see `on_write_failed`.
     fn persist(&mut self) {
+        let (log_buf, snapshot_buf) = self.encode_persisted();
+        let sync_log = self.log.sync_job();
+        let events = self.events.clone();
+        let me = self.me;
+        self.writes_issued += 1;
+        let write = self.writes_issued;
+        let term = self.term;
+        let index = self.log.last_index();
+        self.persister.save(log_buf, snapshot_buf, move |result| {
+            let event = match result {
+                Ok(()) => {
+                    if let Some(sync_log) = sync_log {
+                        sync_log();
+                    }
+                    Event::Persisted { write, term, index }
+                }
+                Err(e) => Event::WriteFailed(e),
+            };
+            if !events.send(event) {
+                debug!("persist: the event loop of NO{} has stopped.", me);
+            }
+        });
+    }
+
+    /// a write of the persisted state has failed, like a store returning an I/O error.
+    /// This peer steps down, and stops after the current event, since it can't keep its promises
+    /// (e.g. a vote) any more. It may restart from what has been saved.
+    fn on_write_failed(&mut self, e: io::Error) {
+        error!("{} failed to persist, stops: {}", self.self_info(), e);
+        self.write_failed = true;
+        // the requesters time out instead.
+        self.held_replies.clear();
+        self.become_follower();
+        self.stop_election_timer();
+    }
+
+    /// encode the raft state to save, along with the snapshot if it hasn't been saved.
+    fn encode_persisted(&mut self) -> (Vec<u8>, Option<Vec<u8>>) {
         let persisted = PersistedStatus::by_raft(self);
-        let snapshot = Snapshot::by_raft(self);
         let mut log_buf = vec![];
         encode(&persisted, &mut log_buf).unwrap();
-        self.log_size = log_buf.len();
-        let mut snapshot_buf = vec![];
-        encode(&snapshot, &mut snapshot_buf).unwrap();
-        self.persister
-            .save_state_and_snapshot(log_buf, snapshot_buf);
+        self.log_size.store(
+            log_buf.len() + self.log.stored_bytes(),
+            AtomicOrdering::Release,
+        );
+        let snapshot_buf = if self.snapshot_saved {
+            None
+        } else {
+            self.snapshot_saved = true;
+            Some(self.snapshot.data.clone())
+        };
+        (log_buf, snapshot_buf)
+    }
+
+    /// the `write`th write has been saved, with the log up to `index` in `term`.
+    /// The replies held for it are sent, a candidate may win the election,
+    /// and the leader counts itself in the quorum of these entries now.
+    fn on_persisted(&mut self, write: u64, term: u64, index: u64) {
+        self.writes_saved = Ord::max(self.writes_saved, write);
+        while self
+            .held_replies
+            .front()
+            .map_or(false, |(write, _)| *write <= self.writes_saved)
+        {
+            let (_, reply) = self.held_replies.pop_front().unwrap();
+            reply();
+        }
+        if self.current_role == Candidate {
+            self.check_votes();
+        }
+        if !self.is_leader() || self.term != term {
+            return;
+        }
+        let me = self.me;
+        let progress = &mut self.leader_state.as_mut().unwrap().progress[me];
+        progress.match_index = Ord::max(progress.match_index, index);
+        self.leader_commit_logs();
     }
 
     /// restore previously persisted state.
-    fn restore(&mut self, log: &[u8], snapshot: &[u8]) {
+    ///
+    /// # returns
+    /// `Error::Decode` or `Error::Corrupted` if the state is damaged, nothing is restored then.
+    fn restore(&mut self, log: &[u8], snapshot: Vec<u8>) -> Result<()> {
         if log.is_empty() && snapshot.is_empty() {
             info!("{} bootstrap without any state!", self.self_info());
+            return Ok(());
+        }
+        let (state, ss) = decode_persisted(log, &snapshot)?;
+        self.log_size
+            .store(log.len() + self.log.stored_bytes(), AtomicOrdering::Release);
+        self.term = state.current_term;
+        // the entries follow the snapshot they were saved with,
+        // which may be older than `ss` if the write of the raft state was torn.
+        self.restore_log(state.last_included_index, state.last_included_term);
+        self.log
+            .append(state.logs.into_iter().map(Into::into).collect());
+        self.restore_log(ss.last_index_of_snapshot, ss.last_term_of_snapshot);
+        self.snapshot = EncodedSnapshot {
+            checksum: fnv1a(&snapshot),
+            data: snapshot,
+        };
+        self.snapshot_saved = true;
+        self.voted_for = state.voted_for.first().map(|x| *x as usize);
+        // let apply the snapshots message to state machine firstly...
+        // We can assert that snapshot are committed.
+        self.commit_index = self.log.last_included_index();
+        self.apply_snapshot(ss.state_machine_state);
+        info!(
+            "{} bootstrap with log length {}!",
+            self.self_info(),
+            self.log.last_index()
+        );
+        Ok(())
+    }
+
+    /// make the log start after the snapshot to `index`,
+    /// keeping the entries following it if the log contains the snapshot.
+    fn restore_log(&mut self, index: u64, term: u64) {
+        let contains_snapshot = index >= self.log.last_included_index()
+            && index <= self.log.last_index()
+            && (index == self.log.last_included_index() || self.log.term_at(index) == term);
+        if contains_snapshot {
+            self.log.restore_snapshot(index, term);
+        } else {
+            self.log.reset(index, term);
+        }
+    }
+
+    /// report the transitions of this peer to `observer`, e.g. for checking invariants in tests.
+    ///
+    /// The restored log is reported at once, by a `LogReset` and an `Appended`.
+    pub fn set_observer(&mut self, observer: RaftObserver) {
+        let index = self.log.last_included_index();
+        observer(
+            self.me,
+            &RaftEvent::LogReset {
+                index,
+                term: self.log.last_included_term(),
+            },
+        );
+        if self.log.last_index() > index {
+            let entries = self
+                .log
+                .entries(index + 1, usize::max_value(), usize::max_value());
+            observer(
+                self.me,
+                &RaftEvent::Appended {
+                    index: index + 1,
+                    entries,
+                },
+            );
+        }
+        self.observer = Some(observer);
+    }
+
+    /// report an event to the observer, `event` is made only if there is one.
+    fn emit(&self, event: impl FnOnce() -> RaftEvent) {
+        if let Some(observer) = self.observer.as_ref() {
+            observer(self.me, &event());
+        }
+    }
+
+    /// send an event to the subscribers, dropping those who have gone.
+    fn notify(&mut self, event: NodeEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// change the role, and notify the subscribers if it changes.
+    fn set_role(&mut self, role: RaftRole) {
+        if self.current_role != role {
+            self.current_role = role;
+            let term = self.term;
+            self.notify(NodeEvent::RoleChanged {
+                role: role.into(),
+                term,
+            });
+        }
+    }
+
+    /// change the leader of current term this peer knows, and notify the subscribers if it changes.
+    fn set_leader(&mut self, leader: Option<usize>) {
+        if self.leader_id != leader {
+            self.leader_id = leader;
+            let term = self.term;
+            self.notify(NodeEvent::LeaderChanged {
+                leader: leader.map(|id| id as u64),
+                term,
+            });
+        }
+    }
+
+    /// append entries to the log.
+    fn append_log(&mut self, entries: Vec<LogEntry>) {
+        if entries.is_empty() {
             return;
         }
-        self.log_size = log.len();
-        decode::<PersistedStatus>(log)
-            .and_then(|state| {
-                decode::<Snapshot>(snapshot).map(|ss| {
-                    self.term = state.current_term;
-                    self.log = RaftLogWithSnapShot {
-                        last_included_index: ss.last_index_of_snapshot,
-                        last_included_term: ss.last_term_of_snapshot,
-                        state_machine_state: SnapshotFile {
-                            commands: ss.state_machine_state,
-                        },
-                        commands: state.logs.into_iter().map(Into::into).collect(),
-                    };
-                    self.voted_for = state.voted_for.first().map(|x| *x as usize);
-                    // let apply the snapshots message to state machine firstly...
-                    // We can assert that snapshot are committed.
-                    self.commit_index = self.log.last_included_index;
-                    self.apply_snapshot();
-                    info!(
-                        "{} bootstrap with log length {}!",
-                        self.self_info(),
-                        self.log.len()
-                    )
-                })
-            })
-            .expect("failed to decode persisted status.");
+        let index = self.log.last_index() + 1;
+        self.emit(|| RaftEvent::Appended {
+            index,
+            entries: entries.clone(),
+        });
+        self.log.append(entries);
     }
 
-    /// apply snapshot to state machine.
+    /// advance `commit_index` to `index`.
+    fn commit_to(&mut self, index: u64) {
+        self.commit_index = index;
+        let term = self.term;
+        self.emit(|| RaftEvent::Committed { index, term });
+    }
+
+    /// apply snapshot, whose state machine state is `commands`, to state machine.
     /// This updates `last_applied`.
-    fn apply_snapshot(&mut self) {
-        for e in self.log.state_machine_state.commands.iter() {
-            let msg = ApplyMsg {
-                command_valid: false,
-                command: e.clone(),
-                command_index: 0,
-            };
-            if self.apply_ch.unbounded_send(msg).is_err() {
-                error!(
-                    "{} failed to send snapshot state, which probably cause unexpected behavior.",
-                    self.self_info()
-                );
-            }
+    fn apply_snapshot(&mut self, commands: Vec<Vec<u8>>) {
+        let msg = ApplyMsg::InstallSnapshot {
+            last_included_index: self.log.last_included_index(),
+            last_included_term: self.log.last_included_term(),
+            commands,
+        };
+        if self.apply_ch.unbounded_send(msg).is_err() {
+            error!(
+                "{} failed to send snapshot state, which probably cause unexpected behavior.",
+                self.self_info()
+            );
         }
 
-        self.last_applied = self.log.last_included_index;
+        self.last_applied = self.log.last_included_index();
         info!(
             "{} applied (by snapshot) to {}",
             self.self_info(),
             self.last_applied
         );
+        self.resolve_proposals();
     }
 
     /// send a rpc request to a peer.
+    /// The response will be sent back to the event loop as a `Message`.
     ///
     /// # arguments
     /// - server: the rpc endpoint index.
     /// - args: the rpc args.
     /// - rpc: the code segment that uses client and args to send rpc.
-    ///
-    /// # returns
-    /// a `SentRequest` struct.
-    fn send_request<Arg, Rep: Send + 'static>(
+    /// - message: wraps the response into a `Message`.
+    fn send_request<Arg: Debug + Send + 'static, Rep: Send + 'static>(
         &self,
         server: usize,
         args: Arg,
-        rpc: impl Fn(&Client, &Arg) -> RpcFuture<Rep>,
-    ) -> SentRequest<Arg, Rep> {
-        let peer = &self.peers[server];
-        let (tx, rx) = channel::<Result<Rep>>();
-        peer.spawn(rpc(peer, &args).map_err(Error::Rpc).then(move |res| {
-            let result = tx.send(res);
-            if let Err(e) = result {
-                debug!("send_request: result of RPC is unused. since: {:?}", e);
+        rpc: impl Fn(&dyn Transport, usize, &Arg) -> RpcFuture<Rep>,
+        message: fn(Response<Arg, Rep>) -> Message,
+    ) {
+        let events = self.events.clone();
+        let me = self.me;
+        let sent_at = self.now();
+        let request = rpc(&*self.peers, server, &args);
+        self.peers.spawn(Box::new(request.then(move |res| {
+            match res {
+                Ok(reply) => {
+                    let response = Response {
+                        follower: server,
+                        request: args,
+                        reply,
+                        sent_at,
+                    };
+                    if !events.send(Event::Message(message(response))) {
+                        debug!("send_request: the event loop of NO{} has stopped.", me);
+                    }
+                }
+                Err(e) => debug!(
+                    "NO{} failed to get result of {:?}, because: {}",
+                    me, args, e
+                ),
             }
             Ok(())
-        }));
-        SentRequest::from((server, args, rx))
+        })));
     }
 
     /// get the current state string of this raft.
@@ -552,8 +1298,8 @@ impl Raft {
             self.term,
             self.commit_index,
             self.last_applied,
-            self.log.len(),
-            self.log.last_included_index,
+            self.log.last_index(),
+            self.log.last_included_index(),
             self.current_role,
         )
     }
@@ -567,44 +1313,51 @@ impl Raft {
     fn log_info(&self) -> String {
         format!(
             "({} snapshots omitted.) {:?}",
-            self.log.last_included_index,
-            self.log.iter().map(|e| e.term).collect::<Vec<_>>()
+            self.log.last_included_index(),
+            (self.log.last_included_index() + 1..=self.log.last_index())
+                .map(|i| self.log.term_at(i))
+                .collect::<Vec<_>>()
         )
     }
 
-    /// add a new command to the raft cluster.
+    /// add a new (encoded) command to the raft cluster.
     ///
     /// # returns
     /// when success, return the (index, term) 2-tuple of the command.
     /// (this doesn't means that this command will eventually appears at there,
     ///  before committed, this command can be lost.)
     /// if this raft isn't leader, return `NotLeader`.
-    fn start<M>(&mut self, command: &M) -> Result<(u64, u64)>
-    where
-        M: labcodec::Message,
-    {
+    fn start(&mut self, command: Vec<u8>) -> Result<(u64, u64)> {
         let is_leader = self.current_role == Leader;
         if !is_leader {
             return Err(Error::NotLeader);
         }
 
-        let mut buf = vec![];
         debug!(
             "{} get command: {:?}(logs = {:?})",
             self.self_info(),
             command,
             self.log_info()
         );
-        labcodec::encode(command, &mut buf).map_err(Error::Encode)?;
-        let entry = self.make_log(buf);
-        self.log.push(entry);
+        let size = command.len();
+        let entry = self.make_log(command);
+        self.append_log(vec![entry]);
 
         let index = self.last_log_index();
         let term = self.term;
-        let ls = self.leader_state.as_ref().unwrap();
-        ls.new_request
-            .send(())
-            .unwrap_or_else(|_| error!("leader is died when try send to new_request_channel."));
+        let budget = self.extra.proposal_batch_bytes;
+        let window = self.extra.proposal_batch_window;
+        let now = self.now();
+        let ls = self.leader_state.as_mut().unwrap();
+        // flush the batch after `window` since it begins, or at once when it reaches the byte budget.
+        if ls.pending_proposals == 0 {
+            ls.flush_at = Some(now + window);
+        }
+        ls.pending_proposals += 1;
+        ls.pending_bytes += size;
+        if ls.pending_bytes >= budget {
+            ls.flush_at = Some(now);
+        }
         Ok((index, term))
     }
 }
@@ -613,7 +1366,7 @@ impl Raft {
     /// Only for suppressing deadcode warnings.
     #[doc(hidden)]
     pub fn __suppress_deadcode(&mut self) {
-        let _ = self.start(&0);
+        let _ = self.start(vec![]);
         let _ = &self.state;
         let _ = &self.me;
         let _ = &self.persister;
@@ -639,20 +1392,43 @@ impl Raft {
 // ```
 #[derive(Clone)]
 pub struct Node {
-    raft: Arc<Mutex<Raft>>,
+    /// the sender of events to the event loop of the raft.
+    events: Arc<Mutex<Mailbox>>,
+    /// the state published by the event loop.
+    state: Arc<Mutex<State>>,
+    /// the byte size of the raft state, published by the event loop, see `needs_snapshot`.
+    log_size: Arc<AtomicUsize>,
+    /// `RaftConfig::snapshot_threshold` of the raft.
+    snapshot_threshold: Option<usize>,
 }
 
 impl Into<LogEntry> for ProtoEntry {
     fn into(self) -> LogEntry {
-        LogEntry::new(self.command, self.term)
+        LogEntry {
+            data: self.command,
+            term: self.term,
+            no_op: self.no_op,
+        }
     }
 }
 
 impl Into<ProtoEntry> for LogEntry {
     fn into(self) -> ProtoEntry {
         ProtoEntry {
+            checksum: entry_checksum(self.term, self.no_op, &self.data),
             command: self.data,
             term: self.term,
+            no_op: self.no_op,
+        }
+    }
+}
+
+impl Into<Role> for RaftRole {
+    fn into(self) -> Role {
+        match self {
+            Leader => Role::Leader,
+            Candidate => Role::Candidate,
+            Follower => Role::Follower,
         }
     }
 }
@@ -661,13 +1437,11 @@ fn reverse_order<T: Ord>(a: &T, b: &T) -> Ordering {
     b.cmp(a)
 }
 
-/// Find the mid number of the items array.
-/// This function assumes that the `items` array contains a 'supreme' element,
-/// which is the `match_index` of the current node itself.
+/// Find the value that a majority of `items` have reached,
+/// i.e. the `items.len() / 2`th largest one, where `items` has an item for each peer.
 fn mid<T: Ord>(items: &mut [T]) -> &T {
     items.sort_by(reverse_order);
-    let mid = (items.len() - 1) / 2;
-    &items[mid]
+    &items[items.len() / 2]
 }
 
 impl Raft {
@@ -678,7 +1452,7 @@ impl Raft {
 
     /// find a index in the log where contains the first log entry of the
     /// specified term.
-    fn get_term_starts_at(&self, term: u64, from: usize) -> usize {
+    fn get_term_starts_at(&self, term: u64, from: u64) -> u64 {
         let mut n = from;
         while self.log.term_at(n) == term && !self.log.is_in_snapshot(n) {
             n -= 1;
@@ -699,34 +1473,79 @@ impl Raft {
             .leader_state
             .as_ref()
             .expect("fetal: leader node does'nt have leader state.");
-        let mut valid_state = leader_state.match_index.clone();
-        valid_state.remove(self.me);
-        *mid(valid_state.as_mut_slice())
-    }
-
-    /// make a `ApplyMessage` with the log entry at `index`.
-    fn make_apply_message(&self, index: u64) -> ApplyMsg {
-        let log = &self.log[index as usize];
-        ApplyMsg {
-            command_valid: true,
-            command: log.data.clone(),
-            command_index: index,
+        // the `match_index` of the leader itself is what it has saved, see `on_persisted`.
+        let mut match_indices = leader_state
+            .progress
+            .iter()
+            .map(|p| p.match_index)
+            .collect::<Vec<_>>();
+        *mid(match_indices.as_mut_slice())
+    }
+
+    /// make `ApplyMessage`s with the log entries in `[start, end]`.
+    fn make_apply_messages(&self, start: u64, end: u64) -> Vec<ApplyMsg> {
+        if start > end {
+            return vec![];
         }
+        let count = (end - start + 1) as usize;
+        self.log
+            .entries(start, count, usize::max_value())
+            .into_iter()
+            .zip(start..)
+            .map(|(entry, index)| {
+                if entry.no_op {
+                    ApplyMsg::NoOp {
+                        index,
+                        term: entry.term,
+                    }
+                } else {
+                    ApplyMsg::Command {
+                        index,
+                        term: entry.term,
+                        data: entry.data,
+                    }
+                }
+            })
+            .collect()
     }
 
     /// Apply logs by current `commit_index` to state machine.
     fn apply_logs(&mut self) {
-        for i in (self.last_applied + 1)..=(self.commit_index) {
-            self.apply_ch
-                .unbounded_send(self.make_apply_message(i))
-                .unwrap_or_else(|e| error!("{} failed to send to apply ch. because: {}. the client of raft may shutdown.", self.self_info(), e));
+        for msg in self.make_apply_messages(self.last_applied + 1, self.commit_index) {
+            self.apply_ch.unbounded_send(msg).unwrap_or_else(|e| {
+                error!(
+                    "{} failed to send to apply ch. because: {}. the client of raft may shutdown.",
+                    self.self_info(),
+                    e
+                )
+            });
         }
         self.last_applied = self.commit_index;
+        let index = self.last_applied;
+        self.emit(|| RaftEvent::Applied { index });
         info!(
             "{} applied to index {}.",
             self.self_info(),
             self.last_applied,
         );
+        self.resolve_proposals();
+    }
+
+    /// notify the waiting proposals whose indices have been applied.
+    fn resolve_proposals(&mut self) {
+        let pending = self.waiting_proposals.split_off(&(self.last_applied + 1));
+        for (index, proposal) in std::mem::replace(&mut self.waiting_proposals, pending) {
+            // the entry may be replaced by one of another term,
+            // or included in an installed snapshot, we cannot tell what it is then.
+            let applied =
+                !self.log.is_in_snapshot(index) && self.log.term_at(index) == proposal.term;
+            let result = if applied {
+                Ok((index, proposal.term))
+            } else {
+                Err(Error::NotCommitted)
+            };
+            let _ = proposal.reply.send(result);
+        }
     }
 
     /// Leader commit its indices by this function.
@@ -735,13 +1554,12 @@ impl Raft {
     /// from last term by counting replicas.
     fn leader_commit_logs(&mut self) {
         let next = self.next_commit_index();
-        assert!((next as usize) <= self.log.len(),
-                "match_index grater than self log length... next = {} and match_index = {:?} and self.log.len() = {}",
-                next, self.leader_state.as_ref().map(|s| s.match_index.clone()), self.log.len());
+        assert!(next <= self.log.last_index(),
+                "match_index grater than self log length... next = {} and match_index = {:?} and self.log.last_index() = {}",
+                next, self.leader_state.as_ref().map(|s| s.progress.iter().map(|p| p.match_index).collect::<Vec<_>>()), self.log.last_index());
         // 5.4.2: NEVER commit log entries from previous terms by counting replicas.
-        if next > self.commit_index && self.log[next as usize].term == self.term {
-            self.commit_index = next;
-            self.persist();
+        if next > self.commit_index && self.log.term_at(next) == self.term {
+            self.commit_to(next);
             self.apply_logs();
         }
     }
@@ -749,7 +1567,7 @@ impl Raft {
     /// Get the index of last log.
     /// which is the same as log size.
     fn last_log_index(&self) -> u64 {
-        self.log.len() as u64
+        self.log.last_index()
     }
 
     /// Get the term of last log.
@@ -758,89 +1576,111 @@ impl Raft {
         self.log.last_term()
     }
 
+    /// collect the `RaftStatus` of this peer.
+    fn status(&self) -> RaftStatus {
+        let role: Role = self.current_role.into();
+        let now = self.now();
+        let followers = self
+            .leader_state
+            .iter()
+            .flat_map(|state| state.progress.iter().enumerate())
+            .filter(|(i, _)| *i != self.me)
+            .map(|(i, progress)| FollowerStatus {
+                id: i as u64,
+                match_index: progress.match_index,
+                next_index: progress.next_index,
+                last_contact_millis: progress
+                    .last_contact
+                    .iter()
+                    .map(|&at| (now - at).as_millis() as u64)
+                    .collect(),
+            })
+            .collect();
+        RaftStatus {
+            id: self.me as u64,
+            role: role as i32,
+            term: self.term,
+            leader_id: self.leader_id.iter().map(|&id| id as u64).collect(),
+            voted_for: self.voted_for.iter().map(|&id| id as u64).collect(),
+            last_log_index: self.last_log_index(),
+            last_log_term: self.last_log_term(),
+            snapshot_index: self.log.last_included_index(),
+            commit_index: self.commit_index,
+            last_applied: self.last_applied,
+            followers,
+        }
+    }
+
     /// make `RequestVoteArgs` rpc argument by current state of self.
-    fn make_request_vote_args(&self) -> RequestVoteArgs {
+    fn make_request_vote_args(&self, leader_transfer: bool) -> RequestVoteArgs {
         RequestVoteArgs {
             term: self.term,
             candidate_id: self.me as u64,
             last_log_index: self.last_log_index(),
             last_log_term: self.last_log_term(),
+            leader_transfer,
+            priorities_digest: priorities_digest(&self.extra.priorities),
         }
     }
 
-    /// transform the raft node to candidate.
-    fn transform_to_candidate(raft: Arc<Mutex<Self>>) {
-        std::thread::spawn(move || {
-            // some basic state transform, and save some cloneable information.
-            let mut guard = raft.lock().unwrap();
-            guard.current_role = Candidate;
-            // make the borrow checker happy.
-            let old_term = guard.term;
-            guard.update_term(old_term + 1);
-            let term_at_start = guard.term;
-            let me = guard.me;
+    /// transform the raft node to candidate, and start a new election.
+    fn campaign(&mut self) {
+        let leader_transfer = self.leader_transfer;
+        // make the borrow checker happy.
+        let old_term = self.term;
+        self.update_term(old_term + 1);
+        self.set_role(Candidate);
+        let me = self.me;
 
-            // vote for self, then send `RequestVote` RPCs.
-            info!(
-                "{} started a new election of term {}.",
-                guard.self_info(),
-                guard.term
-            );
-            guard.vote_for(me);
-            let peer_count = guard.peers.len();
-            let send_result = (0..peer_count)
-                .filter(|i| *i != me)
-                .map(|i| {
-                    guard.send_request(i, guard.make_request_vote_args(), |client, arg| {
-                        client.request_vote(arg)
-                    })
-                })
-                .map(|req| req.response)
-                .collect::<Vec<Receiver<_>>>();
-            drop(guard);
-
-            // let's poll on the requests...
-            let data_channel = select(send_result.into_iter());
-            let mut vote_count = 1;
-            while let Ok(result) = data_channel.recv_timeout(Duration::from_millis(300)) {
-                if result.is_err() {
-                    continue;
-                }
-                let vote_result = result.unwrap();
-
-                // 自身已然不再是候选人之时……（从投票节点处得知）
-                if vote_result.term > term_at_start {
-                    Raft::check_term(raft.clone(), vote_result.term);
-                    return;
-                }
-
-                vote_count += if vote_result.vote_granted { 1 } else { 0 };
-
-                // Bingo! we get enough votes.
-                if vote_count > peer_count / 2 {
-                    info!("{} has enough votes at term {}!", me, term_at_start);
-                    break;
-                }
-            }
-
-            // 做一些登基前的准备工作，同时测试自身是否已经被弹劾了。
-            let guard = raft.lock().unwrap();
-            if vote_count > peer_count / 2
-                // ensure that we didn't start another term of election...
-                && guard.term == term_at_start
-                // ensure that there isn't a leader...
-                && guard.current_role == Candidate
-            {
-                guard.stop_election_timer();
-                info!(
-                    "{} is now the leader of term {}.",
-                    guard.self_info(),
-                    term_at_start
+        // vote for self, then send `RequestVote` RPCs.
+        info!(
+            "{} started a new election of term {}.",
+            self.self_info(),
+            self.term
+        );
+        self.vote_for(me);
+        self.votes = 1;
+        self.campaign_write = self.writes_issued;
+        // if the election fails, start another one after a timeout.
+        self.reset_election_timer();
+        for i in 0..self.peers.peer_count() {
+            if i != me {
+                self.send_request(
+                    i,
+                    self.make_request_vote_args(leader_transfer),
+                    |peers, to, args| peers.request_vote(to, args),
+                    Message::RequestVoteResponse,
                 );
-                drop(guard);
-                Raft::transform_to_leader(raft);
             }
-        });
+        }
+        self.check_votes();
+    }
+
+    /// candidate `RequestVote` response handler.
+    fn handle_request_vote(&mut self, response: Response<RequestVoteArgs, RequestVoteReply>) {
+        let Response { request, reply, .. } = response;
+        // 自身已然不再是候选人之时……（从投票节点处得知）
+        if reply.term > self.term {
+            self.check_term(reply.term);
+            return;
+        }
+        // ensure that we didn't start another term of election, and there isn't a leader...
+        if self.current_role != Candidate || request.term != self.term {
+            return;
+        }
+        if reply.vote_granted {
+            self.votes += 1;
+            self.check_votes();
+        }
+    }
+
+    /// become the leader if the candidate has got enough votes, and its own vote has been saved.
+    fn check_votes(&mut self) {
+        // Bingo! we get enough votes.
+        if self.votes > self.peers.peer_count() / 2 && self.writes_saved >= self.campaign_write {
+            info!("{} has enough votes at term {}!", self.me, self.term);
+            self.become_leader();
+        }
     }
 
     /// modify leader state by a response of `AppendEntries`.
@@ -865,15 +1705,18 @@ impl Raft {
                 self_info
             )
         });
+        let progress = &mut leader_state.progress[follower];
         if response.success {
             let matching = request.prev_log_index + request.entries.len() as u64;
-            // 防止返回乱序……
-            let new_match_index = Ord::max(matching, leader_state.match_index[follower]);
-            leader_state.match_index[follower] = new_match_index;
-            leader_state.next_index[follower] = new_match_index + 1;
-            self.leader_commit_logs();
+            if progress.on_accepted(matching) {
+                self.leader_commit_logs();
+            }
         } else {
-            let next_index = response.conflicted_term_starts_at as usize;
+            // the follower has matched after `prev_log_index` -- this is a stale response.
+            if request.prev_log_index < progress.match_index {
+                return;
+            }
+            let next_index = response.conflicted_term_starts_at;
             if next_index == 0xcafe_babe {
                 panic!("A debug magic number appears, which might means InvalidLeader message has handled by incorrect way.\n\
                 Debug info: ({:?}) => {:?} self = {}", request, response, self_info);
@@ -885,36 +1728,41 @@ impl Raft {
                 next_index
             } else {
                 next_index - 1
-            } as u64;
+            };
             // don't send placeholder!
-            leader_state.next_index[follower] = Ord::max(1, real_next);
+            progress.next_index = Ord::max(progress.match_index + 1, real_next);
+            progress.become_probe();
         }
     }
 
     /// leader `AppendEntries` response handler.
     /// This do some basic state transform, and check term, authorship,
     /// then delegate tasks to `modify_state_by_append_entries`.
-    fn handle_append_entries(
-        raft_lock: Arc<Mutex<Self>>,
-        request: &AppendEntriesArgs,
-        response: &AppendEntriesReply,
-        follower: usize,
-    ) {
-        let mut raft = raft_lock.lock().unwrap();
-        let raft_info = raft.self_info();
+    fn handle_append_entries(&mut self, response: Response<AppendEntriesArgs, AppendEntriesReply>) {
+        let Response {
+            follower,
+            request,
+            reply,
+            sent_at,
+        } = response;
         debug!(
             "[{}] => {} : append_entries({:?}) => {:?}",
-            raft_info, follower, request, response
+            self.self_info(),
+            follower,
+            request,
+            reply
         );
-        if !raft.is_leader() {
+        if !self.is_leader() {
             return;
         }
-        if raft.term < response.term {
-            drop(raft);
-            Raft::check_term(raft_lock.clone(), response.term);
+        if self.term < reply.term {
+            self.check_term(reply.term);
             return;
         }
-        raft.modify_state_by_append_entries(request, response, follower);
+        self.record_contact(request.term, follower, sent_at);
+        self.modify_state_by_append_entries(&request, &reply, follower);
+        // the window may have space for more entries now.
+        self.replicate_to(follower, false);
     }
 
     /// make `AppendEntriesArgs` by current state and target follower.
@@ -923,19 +1771,277 @@ impl Raft {
             .leader_state
             .as_ref()
             .expect("fetal: try to issue AppendEntries from non-leader node.");
-        let next_index = leader_state.next_index[server] as usize;
+        let next_index = leader_state.progress[server].next_index;
+        // at least one entry is sent, even if it exceeds `max_append_bytes`.
+        let entries: Vec<ProtoEntry> = self
+            .log
+            .entries(
+                next_index,
+                self.extra.max_append_entries,
+                self.extra.max_append_bytes,
+            )
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        // the follower campaigns once it has got all entries of the leader.
+        let handing_off = leader_state.transferee.map_or(false, |(to, since)| {
+            to == server && self.now() - since < self.extra.max_election_timeout
+        });
+        let caught_up = next_index - 1 + entries.len() as u64 == self.last_log_index();
+        let now = self.now();
+        let timeout = self.extra.max_election_timeout;
+        let up_to_date_peers = if self.extra.priorities.is_empty() {
+            vec![]
+        } else {
+            leader_state
+                .progress
+                .iter()
+                .enumerate()
+                .filter(|(i, p)| {
+                    *i != self.me
+                        && *i != server
+                        && p.match_index >= self.commit_index
+                        && p.last_contact.map_or(false, |t| now - t < timeout)
+                })
+                .map(|(i, _)| i as u64)
+                .collect()
+        };
         AppendEntriesArgs {
             term: self.term,
             leader_id: self.me as u64,
-            prev_log_index: (next_index - 1) as u64,
+            prev_log_index: next_index - 1,
             prev_log_term: self.log.term_at(next_index - 1),
-            entries: self.log[next_index..]
-                .iter()
-                .cloned()
-                .map(Into::into)
-                .collect(),
+            entries,
             leader_commit: self.commit_index,
+            timeout_now: handing_off && caught_up,
+            up_to_date_peers,
+        }
+    }
+
+    /// persist the proposals batched since last flush at once.
+    fn flush_proposals(&mut self) {
+        let ls = self
+            .leader_state
+            .as_mut()
+            .expect("fetal: try to flush proposals on non-leader node.");
+        ls.flush_at = None;
+        if ls.pending_proposals == 0 {
+            return;
+        }
+        debug!(
+            "NO{} flushes {} proposals ({} bytes).",
+            self.me, ls.pending_proposals, ls.pending_bytes
+        );
+        ls.pending_proposals = 0;
+        ls.pending_bytes = 0;
+        self.persist();
+    }
+
+    /// ReadIndex handler, see `Node::read_index`.
+    /// Unless the leader holds the lease, the read waits in `pending_reads`,
+    /// until a majority acknowledges a heartbeat sent after it arrived.
+    fn read_index(&mut self, sender: Sender<Result<u64>>) {
+        if !self.is_leader() {
+            reply(&sender, Err(Error::NotLeader));
+            return;
+        }
+        let read_index = self.commit_index;
+        if self.log.term_at(read_index) != self.term {
+            reply(&sender, Err(Error::NoCommittedEntryInTerm));
+            return;
+        }
+        if self.has_lease() {
+            reply(&sender, Ok(read_index));
+            return;
+        }
+        self.pending_reads.push(PendingRead {
+            index: read_index,
+            started_at: self.now(),
+            reply: sender,
+        });
+        // send the heartbeats at once.
+        let now = self.now();
+        self.leader_state.as_mut().unwrap().next_heartbeat = now;
+        self.confirm_reads();
+    }
+
+    /// reply the pending reads confirmed by a majority.
+    fn confirm_reads(&mut self) {
+        let ls = match self.leader_state.as_ref() {
+            Some(ls) => ls,
+            None => return,
+        };
+        let me = self.me;
+        let peer_count = self.peers.peer_count();
+        self.pending_reads.retain(|read| {
+            // the leader itself is one of the majority.
+            let acks = ls
+                .progress
+                .iter()
+                .enumerate()
+                .filter(|(i, p)| *i == me || p.last_contact.map_or(false, |t| t >= read.started_at))
+                .count();
+            if acks <= peer_count / 2 {
+                return true;
+            }
+            reply(&read.reply, Ok(read.index));
+            false
+        });
+    }
+
+    /// fail the pending reads that a majority doesn't confirm in an election timeout.
+    fn expire_reads(&mut self) {
+        let now = self.now();
+        let timeout = self.extra.min_election_timeout;
+        self.pending_reads.retain(|read| {
+            let expired = now - read.started_at >= timeout;
+            if expired {
+                reply(&read.reply, Err(Error::LeadershipUnconfirmed));
+            }
+            !expired
+        });
+    }
+
+    /// send what a follower needs by its `Progress`:
+    /// `InstallSnapshot` if it is too far behind, otherwise `AppendEntries` carrying new entries.
+    ///
+    /// # arguments
+    /// - heartbeat: when `true`, send even if there isn't any new entry,
+    ///   and retransmit requests that are still in flight (they may be lost).
+    fn replicate_to(&mut self, follower: usize, heartbeat: bool) {
+        if !self.is_leader() {
+            return;
+        }
+        let max_inflight = self.extra.max_inflight_append_entries;
+        let last_index = self.last_log_index();
+        let snapshot_index = self.log.last_included_index();
+        let need_install_snapshot = self.need_install_snapshot(follower);
+        let progress = &mut self.leader_state.as_mut().unwrap().progress[follower];
+
+        if need_install_snapshot {
+            if progress.state != ProgressState::Snapshot
+                || progress.snapshot_index != snapshot_index
+            {
+                progress.become_snapshot(snapshot_index);
+            } else if heartbeat {
+                // the chunk in flight may be lost.
+                progress.paused = false;
+            }
+            if progress.paused {
+                return;
+            }
+            progress.paused = true;
+            let offset = progress.snapshot_offset;
+            let args = self.make_install_snapshot_args(offset);
+            self.send_request(
+                follower,
+                args,
+                |peers, to, args| peers.install_snapshot(to, args),
+                Message::InstallSnapshotResponse,
+            );
+            return;
+        }
+
+        if heartbeat {
+            match progress.state {
+                ProgressState::Replicate if !progress.inflight.is_empty() => {
+                    progress.become_replicate()
+                }
+                ProgressState::Replicate => {}
+                ProgressState::Probe | ProgressState::Snapshot => progress.become_probe(),
+            }
+        }
+        if !progress.can_send(max_inflight) || (!heartbeat && progress.next_index > last_index) {
+            return;
         }
+        let args = self.make_append_entries_for(follower);
+        let last = args.prev_log_index + args.entries.len() as u64;
+        self.leader_state.as_mut().unwrap().progress[follower].on_sent(last);
+        self.send_request(
+            follower,
+            args,
+            |peers, to, args| peers.append_entries(to, args),
+            Message::AppendEntriesResponse,
+        );
+    }
+
+    /// record that `follower` acknowledged a request of `term` sent at `sent_at`.
+    fn record_contact(&mut self, term: u64, follower: usize, sent_at: Instant) {
+        if term != self.term {
+            return;
+        }
+        if let Some(ls) = self.leader_state.as_mut() {
+            let contact = &mut ls.progress[follower].last_contact;
+            if contact.map(|t| t < sent_at).unwrap_or(true) {
+                *contact = Some(sent_at);
+            }
+        }
+        self.confirm_reads();
+    }
+
+    /// check whether the leader holds the read lease,
+    /// i.e. it has heard from a majority within `read_lease`.
+    ///
+    /// The lease is lost automatically once the majority contact is too old,
+    /// and while the leader is handing off, or has just given it up.
+    fn has_lease(&self) -> bool {
+        let lease = match self.extra.read_lease {
+            Some(lease) => lease,
+            None => return false,
+        };
+        let ls = match self.leader_state.as_ref() {
+            Some(ls) if self.is_leader() && ls.transferee.is_none() => ls,
+            _ => return false,
+        };
+        let now = self.now();
+        if ls.lease_paused_until.map_or(false, |t| now < t) {
+            return false;
+        }
+        let mut contacts = ls
+            .progress
+            .iter()
+            .map(|p| p.last_contact)
+            .collect::<Vec<_>>();
+        contacts[self.me] = Some(now);
+        (*mid(contacts.as_mut_slice()))
+            .map(|t| now.duration_since(t) < lease)
+            .unwrap_or(false)
+    }
+
+    /// check whether the leader has heard from a majority within the last `max_election_timeout`.
+    /// A new leader is given one `max_election_timeout` to contact its followers.
+    fn quorum_active(&self) -> bool {
+        let ls = match self.leader_state.as_ref() {
+            Some(ls) => ls,
+            None => return false,
+        };
+        let timeout = self.extra.max_election_timeout;
+        let now = self.now();
+        if now - ls.elected_at < timeout {
+            return true;
+        }
+        let active = ls
+            .progress
+            .iter()
+            .enumerate()
+            .filter(|(i, p)| {
+                *i == self.me || p.last_contact.map(|t| now - t < timeout).unwrap_or(false)
+            })
+            .count();
+        active > self.peers.peer_count() / 2
+    }
+
+    /// check whether this peer has heard from a valid leader recently, in lease read mode.
+    /// If so, it shall not vote for anybody else, or the lease of that leader would be broken.
+    fn in_leader_lease(&self) -> bool {
+        if self.extra.read_lease.is_none() {
+            return false;
+        }
+        self.has_lease()
+            || self
+                .last_leader_contact
+                .map(|t| self.now() - t < self.extra.min_election_timeout)
+                .unwrap_or(false)
     }
 
     /// check whether a follower is so far behind, that needs
@@ -948,182 +2054,99 @@ impl Raft {
             .leader_state
             .as_ref()
             .expect("fetal: leader node without leader state.");
-        self.log.is_in_snapshot(ls.next_index[server] as usize)
+        self.log.is_in_snapshot(ls.progress[server].next_index)
     }
 
-    /// make `InstallSnapshotArgs` by current state.
-    fn make_install_snapshot_args(&self) -> InstallSnapshotArgs {
+    /// make `InstallSnapshotArgs` carrying the chunk of current snapshot at `offset`.
+    fn make_install_snapshot_args(&self, offset: u64) -> InstallSnapshotArgs {
+        let last_included_index = self.log.last_included_index();
+        let chunks = &self.snapshot;
+        let start = Ord::min(offset as usize, chunks.data.len());
+        let end = Ord::min(start + self.extra.snapshot_chunk_bytes, chunks.data.len());
         InstallSnapshotArgs {
             term: self.term,
             leader_id: self.me as u64,
-            last_included_term: self.log.last_included_term,
-            last_included_index: self.log.last_included_index,
-            data: self.log.state_machine_state.commands.clone(),
+            last_included_term: self.log.last_included_term(),
+            last_included_index,
+            offset: start as u64,
+            data: chunks.data[start..end].to_vec(),
+            done: end == chunks.data.len(),
+            checksum: chunks.checksum,
         }
     }
 
     /// transform to Leader。
-    fn transform_to_leader(raft_lock: Arc<Mutex<Raft>>) {
-        use std::sync::mpsc::RecvTimeoutError;
-
-        // some basic state creation.
-        let mut guard = raft_lock.lock().unwrap();
-        guard.current_role = Leader;
-        let delay = guard.extra.leader_append_entries_delay;
-        let (sx, rx) = channel();
-        guard.try_send_to_election_timer(Stop);
-        guard.leader_state = Some(LeaderState::by_raft(guard.deref(), sx));
-        drop(guard);
-
-        // the loop of leader lifetime.
-        // when `start` called, the `rx` channel will fire a `()` message.
-        // then... we make `AppendEntries` or `InstallSnapshot` to distribute it.
-        // or... timeout, we send heartbeat to ensure authorization of our leader.
-        while let Err(RecvTimeoutError::Timeout) | Ok(()) = rx.recv_timeout(delay) {
-            let raft = raft_lock.lock().unwrap();
-            // leader is died.
-            if !raft.is_leader() {
-                break;
-            }
-            // after every turn send `AppendEntries`, try to commit logs.
-            let raft_info = raft.self_info();
-            debug!(
-                "{}: leader_state = {:?} (log len = {})",
-                raft_info,
-                raft.leader_state,
-                raft.log.len()
-            );
-
-            // NOTE: we can do this by some way more functional...
-            let mut append_entries_reqs = vec![];
-            let mut install_snapshot_reqs = vec![];
-            // send requests.
-            for i in 0..raft.peers.len() {
-                if i != raft.me {
-                    if raft.need_install_snapshot(i) {
-                        install_snapshot_reqs.push(raft.send_request(
-                            i,
-                            raft.make_install_snapshot_args(),
-                            |client, args| client.install_snapshot(args),
-                        ));
-                    } else {
-                        append_entries_reqs.push(raft.send_request(
-                            i,
-                            raft.make_append_entries_for(i),
-                            |client, args| client.append_entries(args),
-                        ))
-                    }
-                }
-            }
-            drop(raft);
-
-            // handle responses.
-            Raft::spawn_handler(
-                raft_lock.clone(),
-                append_entries_reqs.into_iter(),
-                Raft::handle_append_entries,
-            );
-            Raft::spawn_handler(
-                raft_lock.clone(),
-                install_snapshot_reqs.into_iter(),
-                Raft::handle_install_snapshot,
-            );
+    /// The heartbeats and replication are driven by `tick` since now.
+    fn become_leader(&mut self) {
+        info!(
+            "{} is now the leader of term {}.",
+            self.self_info(),
+            self.term
+        );
+        self.set_role(Leader);
+        self.set_leader(Some(self.me));
+        let term = self.term;
+        self.emit(|| RaftEvent::Elected { term });
+        self.stop_election_timer();
+        let mut leader_state = LeaderState::by_raft(self);
+        // a candidate has saved its log before campaigning.
+        leader_state.progress[self.me].match_index = self.last_log_index();
+        self.leader_state = Some(leader_state);
+        if self.extra.leader_no_op {
+            // the entries of earlier terms are committed along with it, see `leader_commit_logs`.
+            self.append_log(vec![LogEntry::no_op(term)]);
+            self.persist();
         }
-        let mut guard = raft_lock.lock().unwrap();
-        guard.leader_state = None;
     }
 
     /// leader `InstallSnapshot` reply handler.
     fn handle_install_snapshot(
-        raft_lock: Arc<Mutex<Raft>>,
-        _req: &InstallSnapshotArgs,
-        res: &InstallSnapshotReply,
-        follower: usize,
+        &mut self,
+        response: Response<InstallSnapshotArgs, InstallSnapshotReply>,
     ) {
-        let mut raft = raft_lock.lock().unwrap();
-        if !raft.is_leader() {
+        let Response {
+            follower,
+            request: req,
+            reply: res,
+            sent_at,
+        } = response;
+        if !self.is_leader() {
             return;
         }
-        if raft.term < res.term {
-            drop(raft);
-            Raft::check_term(raft_lock.clone(), res.term);
+        if self.term < res.term {
+            self.check_term(res.term);
             return;
         }
+        if self.term != req.term {
+            return;
+        }
+        self.record_contact(req.term, follower, sent_at);
 
-        let next_idx = raft.log.last_included_index + 1;
-        let ls = raft.leader_state.as_mut().unwrap();
-        ls.next_index[follower] = next_idx;
-        raft.leader_commit_logs()
-    }
-
-    /// spawn a handler of some request.
-    /// this function uses for the common pattern of sending rpc:
-    /// timeout, error-handling, thread spawning.
-    fn spawn_handler<Req: Debug + Send + 'static, Res: Send + 'static>(
-        raft_lock: Arc<Mutex<Self>>,
-        requests: impl Iterator<Item = SentRequest<Req, Res>>,
-        handler: impl Fn(Arc<Mutex<Raft>>, &Req, &Res, usize) + Send + Sync + 'static,
-    ) {
-        let raft = raft_lock.lock().unwrap();
-        let h = Arc::new(handler);
-        requests.for_each(|req| {
-            let raft_info = raft.self_info();
-            let raft_lock = raft_lock.clone();
-            let delay = raft.extra.get_timeout();
-            raft.leader_execution_pool.spawn({
-                let raft_lock = raft_lock.clone();
-                let h = h.clone();
-                move || {
-                    match req.response.recv_timeout(delay) {
-                        Err(_e) => debug!(
-                            "{} failed to receive append_entries result to NO{} because timeout.",
-                            raft_info, req.follower
-                        ),
-                        Ok(Err(e)) => {
-                            debug!(
-                                "{} Failed to get result of {:?}, because: {}",
-                                raft_info, req.request, e
-                            );
-                        }
-                        Ok(Ok(info)) => {
-                            h(raft_lock.clone(), &req.request, &info, req.follower);
-                        }
-                    };
-                }
-            });
-        });
-    }
-
-    /// try send a message to election timer.
-    ///
-    /// # return
-    ///
-    /// if success to send, return `true`.
-    /// otherwise, `false`.
-    fn try_send_to_election_timer(&self, message: TimerMsg) -> bool {
-        self.election_timer
-            .as_ref()
-            .map(|sx| sx.sx.send(message))
-            .map(|result| result.map(|_| true).unwrap_or(false))
-            .unwrap_or(false)
-    }
-
-    /// like `try_send_to_election_timer`, but unchecked.
-    /// when failed, a warn message will fire.
-    fn send_to_election_timer(&self, message: TimerMsg) {
-        if !self.try_send_to_election_timer(message) {
-            warn!(
-                "NO{} send_to_election_timer({:?}): failed try. if this is acceptable, \
-                 use try_send_to_election_timer instead.",
-                self.me, message
-            );
+        let progress = &mut self.leader_state.as_mut().unwrap().progress[follower];
+        // a response of the snapshot that isn't being sent.
+        if progress.state != ProgressState::Snapshot
+            || progress.snapshot_index != req.last_included_index
+        {
+            return;
         }
+        if res.done {
+            // the follower has installed the snapshot, or it has already got the last included entry.
+            progress.match_index = Ord::max(progress.match_index, req.last_included_index);
+            progress.next_index = progress.match_index + 1;
+            progress.become_probe();
+            self.leader_commit_logs();
+        } else {
+            // resume from where the follower expects.
+            progress.snapshot_offset = res.next_offset;
+            progress.paused = false;
+        }
+        self.replicate_to(follower, false);
     }
 
     /// reset the election timer.
     /// when receiving `AppendEntries` or `InstallSnapshot`
     /// from valid leader, call this.
-    fn reset_election_timer(&self) {
+    fn reset_election_timer(&mut self) {
         if self.is_leader() {
             warn!(
                 "NO{} Trying to reset election timer on a leader node.",
@@ -1131,19 +2154,101 @@ impl Raft {
             );
         }
 
-        self.send_to_election_timer(TimerMsg::Reset);
+        self.leader_transfer = false;
+        self.election_deadline = Some(self.now() + self.generate_election_timeout());
     }
 
     /// stop the election timer.
     /// when trans to leader, call this.
-    fn stop_election_timer(&self) {
-        self.send_to_election_timer(TimerMsg::Stop);
+    fn stop_election_timer(&mut self) {
+        self.election_deadline = None;
+    }
+
+    /// generate the next election timeout, delayed by `campaign_delay`.
+    fn generate_election_timeout(&self) -> Duration {
+        let min = self.extra.min_election_timeout;
+        let range = (self.extra.max_election_timeout - min).as_micros() as u64;
+        min + Duration::from_micros(rand::thread_rng().gen_range(0, range)) + self.campaign_delay()
+    }
+
+    /// the election priority of `peer`, see `RaftConfigBuilder::priorities`.
+    fn priority(&self, peer: usize) -> u64 {
+        self.extra.priorities.get(peer).cloned().unwrap_or(0)
+    }
+
+    /// how long this peer delays its campaigns, a `min_election_timeout` for each priority higher
+    /// than its own, among the peers known to be reachable and up-to-date in a `max_election_timeout`,
+    /// so they campaign first. Without such peers, e.g. they are down, this peer doesn't wait.
+    fn campaign_delay(&self) -> Duration {
+        let mine = self.priority(self.me);
+        let now = self.now();
+        let higher = self
+            .up_to_date_since
+            .iter()
+            .enumerate()
+            .filter(|(i, since)| {
+                *i != self.me && since.map_or(false, |t| now - t < self.extra.max_election_timeout)
+            })
+            .map(|(i, _)| self.priority(i))
+            .filter(|p| *p > mine)
+            .collect::<BTreeSet<_>>();
+        self.extra.min_election_timeout * higher.len() as u32
     }
 
-    /// generate the next election timeout.
-    fn generate_election_timeout() -> Duration {
-        let range = rand::thread_rng().gen_range(150, 300);
-        Duration::from_millis(range)
+    /// record that `peer` is reachable and up-to-date, see `campaign_delay`.
+    fn saw_up_to_date(&mut self, peer: usize) {
+        let now = self.now();
+        if let Some(since) = self.up_to_date_since.get_mut(peer) {
+            *since = Some(now);
+        }
+    }
+
+    /// hand off the leadership to the peer of the highest priority above this leader's,
+    /// among the peers that have caught up, and acknowledged a request in a `max_election_timeout`.
+    /// The next `AppendEntries` to it carries `timeout_now`, then it campaigns at once.
+    ///
+    /// A hand-off is given up after a `max_election_timeout`, or once the target hasn't acknowledged
+    /// a request in a `max_election_timeout`, if this peer is still the leader. Then it's tried
+    /// again, and the lease resumes a `max_election_timeout` later, by when a delayed `timeout_now`
+    /// must have arrived, as the lease relies on bounded delays anyway.
+    fn transfer_leadership(&mut self) {
+        let timeout = self.extra.max_election_timeout;
+        let now = self.now();
+        let last_index = self.last_log_index();
+        let mine = self.priority(self.me);
+        let ls = self.leader_state.as_mut().unwrap();
+        if let Some((to, since)) = ls.transferee {
+            let reachable = ls.progress[to]
+                .last_contact
+                .map_or(false, |t| now - t < timeout);
+            if reachable && now - since < timeout {
+                return;
+            }
+            info!("NO{} gives up handing off to NO{}.", self.me, to);
+            ls.transferee = None;
+            ls.lease_paused_until = Some(now + timeout);
+        }
+        let ls = self.leader_state.as_ref().unwrap();
+        let target = ls
+            .progress
+            .iter()
+            .enumerate()
+            .filter(|(i, p)| {
+                *i != self.me
+                    && self.priority(*i) > mine
+                    && p.match_index >= last_index
+                    && p.last_contact.map_or(false, |t| now - t < timeout)
+            })
+            .map(|(i, _)| i)
+            .max_by_key(|i| self.priority(*i));
+        if let Some(target) = target {
+            info!(
+                "{} hands off to NO{} of higher priority.",
+                self.self_info(),
+                target
+            );
+            self.leader_state.as_mut().unwrap().transferee = Some((target, now));
+        }
     }
 
     /// update self.term.
@@ -1160,23 +2265,30 @@ impl Raft {
             self.term,
             new_term
         );
-        if new_term != self.term {
+        let changed = new_term != self.term;
+        if changed {
             info!("{} is now set to term {}", self.self_info(), new_term);
             self.voted_for = None;
             self.persist();
         }
         self.term = new_term;
+        if changed {
+            self.notify(NodeEvent::TermChanged { term: new_term });
+            self.set_leader(None);
+        }
     }
 
     /// transform raft state to follower.
-    fn transform_to_follower(raft: Arc<Mutex<Raft>>) {
-        let mut guard = raft.lock().unwrap();
-        guard.try_send_to_election_timer(Stop);
-        guard.current_role = Follower;
-        guard.election_timer = Some(Timer::new(Raft::generate_election_timeout, {
-            let raft = raft.clone();
-            move || Raft::transform_to_candidate(raft.clone())
-        }));
+    fn become_follower(&mut self) {
+        self.set_role(Follower);
+        self.leader_state = None;
+        for read in self.pending_reads.drain(..) {
+            reply(&read.reply, Err(Error::NotLeader));
+        }
+        for (_, proposal) in std::mem::replace(&mut self.waiting_proposals, BTreeMap::new()) {
+            let _ = proposal.reply.send(Err(Error::NotLeader));
+        }
+        self.reset_election_timer();
     }
 
     /// check whether self should grant vote to candidate
@@ -1190,7 +2302,27 @@ impl Raft {
         let self_should_vote = (args.last_log_term > self.last_log_term())
             || (args.last_log_term == self.last_log_term()
                 && args.last_log_index >= self.last_log_index());
-        self_can_vote && self_should_vote
+        self_can_vote && self_should_vote && !self.declines_for_priority(args)
+    }
+
+    /// check whether this peer declines a candidate of lower priority, because its log is
+    /// at least as up-to-date as the candidate's, so it can be elected instead.
+    /// The candidate that the leader hands off to is never declined, nor the one given other
+    /// priorities than this peer, which are ignored then.
+    fn declines_for_priority(&self, args: &RequestVoteArgs) -> bool {
+        if args.priorities_digest != priorities_digest(&self.extra.priorities) {
+            error!(
+                "{} ignores the priorities of NO{}, which differ from its own {:?}.",
+                self.self_info(),
+                args.candidate_id,
+                self.extra.priorities
+            );
+            return false;
+        }
+        !args.leader_transfer
+            && self.priority(self.me) > self.priority(args.candidate_id as usize)
+            && (self.last_log_term(), self.last_log_index())
+                >= (args.last_log_term, args.last_log_index)
     }
 
     /// check whether current term is out-dated.
@@ -1198,21 +2330,19 @@ impl Raft {
     ///
     /// # returns
     /// returns `true` if the raft peer become follower since this function.
-    fn check_term(raft: Arc<Mutex<Raft>>, new_term: u64) -> bool {
-        let mut guard = raft.lock().unwrap();
-        if new_term >= guard.term {
-            let old_term = guard.term;
-            let leader_to_follower = guard.is_leader() && new_term > old_term;
-            let candidate_to_follower = guard.current_role == Candidate;
-            guard.update_term(new_term);
+    fn check_term(&mut self, new_term: u64) -> bool {
+        if new_term >= self.term {
+            let old_term = self.term;
+            let leader_to_follower = self.is_leader() && new_term > old_term;
+            let candidate_to_follower = self.current_role == Candidate;
+            self.update_term(new_term);
             if leader_to_follower || candidate_to_follower {
                 info!(
                     "{}, get RPC(term = {}), he eventually has known, he is a follower now.",
-                    guard.self_info(),
+                    self.self_info(),
                     new_term
                 );
-                drop(guard);
-                Raft::transform_to_follower(raft.clone());
+                self.become_follower();
                 return true;
             }
         }
@@ -1234,11 +2364,13 @@ impl Raft {
     /// and `remote log = [1,2,4]`,
     /// `check_and_trunc_log(2, [1,2,4])` returns `2` (for `[1,2]` of remote log matches).
     /// and leaving `self.log = [1,1,2]` (truncate any log entries that doesn't matches).
-    /// (Raft log starts at index 1, this function, along with `RaftLogWithSnapshot`, follows this.)
-    fn check_and_trunc_log(&mut self, base: usize, entries: &[LogEntry]) -> usize {
+    /// (Raft log starts at index 1, this function, along with `RaftLog`, follows this.)
+    fn check_and_trunc_log(&mut self, base: u64, entries: &[LogEntry]) -> usize {
         for (offset, remote) in entries.iter().enumerate() {
-            if self.log.term_at(base + offset) != remote.term {
-                self.log.truncate(base + offset);
+            let index = base + offset as u64;
+            if self.log.term_at(index) != remote.term {
+                self.emit(|| RaftEvent::Truncated { index });
+                self.log.truncate(index);
                 return offset;
             }
         }
@@ -1271,209 +2403,281 @@ enum FailedAppendEntries {
         conflicted_term_starts_at: u64,
     },
     InvalidLeader,
+    /// some entries are damaged in transit, the leader should send them again.
+    Corrupted,
 }
 
-impl Node {
-    /// Get raft log entries between `start` and `end` (inclusive).
-    ///
-    /// # returns
-    /// The cloned log range.
-    ///
-    /// # panics
-    /// If try to access log that is in snapshot or not committed.
-    pub fn log_between(&self, start: usize, end: usize) -> Vec<ApplyMsg> {
-        let rf = self.raft.lock().unwrap();
-        assert!(
-            end <= rf.commit_index as usize,
-            "Try to get log entry that not committed."
-        );
-        (start..=end)
-            .map(|i| rf.make_apply_message(i as u64))
-            .collect()
-    }
+impl Raft {
+    /// the event loop of this peer.
+    /// It handles the events one by one, and `tick`s after each of them,
+    /// until `Event::Kill` arrives.
+    fn run(mut self, events: Receiver<Event>) {
+        use std::sync::mpsc::RecvTimeoutError;
 
-    /// Like `log_between`, but returns empty vector when illegal access.
-    pub fn try_get_log_between(&self, start: usize, end: usize) -> Vec<ApplyMsg> {
-        let rf = self.raft.lock().unwrap();
-        if rf.log.is_in_snapshot(start) || end > rf.commit_index as usize {
-            return vec![];
+        let killed = loop {
+            match events.recv_timeout(self.wait_time()) {
+                Ok(Event::Kill(done)) => break Some(done),
+                Ok(event) => self.step(event),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break None,
+            }
+            if !self.write_failed {
+                self.tick();
+            }
+            if self.write_failed {
+                break None;
+            }
+            self.publish_state();
+        };
+
+        self.stop();
+        if let Some(done) = killed {
+            reply(&done, ());
         }
-        drop(rf);
-        self.log_between(start, end)
     }
 
-    /// take the snapshot of `state`, with `last_included_index = last_index`.
-    pub fn take_snapshot(&self, state: SnapshotFile, last_index: usize) {
-        let mut raft = self.raft.lock().unwrap();
-        if raft.log.is_in_snapshot(last_index) {
-            error!(
-                "{} :( (till_index = {}; last_contains_index = {})",
-                raft.self_info(),
-                last_index,
-                raft.log.last_included_index,
-            );
-            return;
-        }
+    /// how long the event loop can wait for events before the next `tick`.
+    fn wait_time(&self) -> Duration {
+        let now = self.now();
+        self.next_deadline()
+            .map_or(self.extra.max_election_timeout, |deadline| {
+                if deadline > now {
+                    deadline - now
+                } else {
+                    Duration::default()
+                }
+            })
+    }
 
-        assert!(
-            last_index <= raft.last_applied as usize,
-            "{} Try to take snapshot when not applied!",
-            raft.self_info(),
-        );
-
-        if raft.is_leader() {
-            info!("{} ls = {:?}", raft.self_info(), raft.leader_state);
+    /// stop this peer when its event loop exits.
+    fn stop(&mut self) {
+        // a restarted peer must not be overwritten by the writes of this one.
+        self.persister.flush();
+        self.set_role(Follower);
+        self.stop_election_timer();
+        self.publish_state();
+        info!("NO{} is dead.", self.me);
+    }
+
+    /// the clock of this peer, all timers and leases are measured by it.
+    fn now(&self) -> Instant {
+        Instant::now() + self.clock_offset
+    }
+
+    /// handle an event other than `Event::Kill`.
+    fn step(&mut self, event: Event) {
+        match event {
+            Event::Message(message) => self.step_message(message),
+            Event::Propose(command, sender, applied) => {
+                let result = self.start(command);
+                if let (Ok(&(index, term)), Some(applied)) = (result.as_ref(), applied) {
+                    self.waiting_proposals.insert(
+                        index,
+                        WaitingProposal {
+                            term,
+                            reply: applied,
+                        },
+                    );
+                }
+                reply(&sender, result);
+            }
+            Event::ReadIndex(sender) => self.read_index(sender),
+            Event::Call(f) => f(self),
+            Event::Persisted { write, term, index } => self.on_persisted(write, term, index),
+            Event::WriteFailed(e) => self.on_write_failed(e),
+            Event::Kill(_) => unreachable!("`Event::Kill` should be handled by the event loop."),
         }
-
-        let remained_log_starts = raft.log.offset_index(last_index) + 1;
-        let new_commands = if remained_log_starts < raft.log.commands.len() {
-            raft.log.commands.drain(remained_log_starts..).collect()
-        } else {
-            vec![]
-        };
-        let new_log = RaftLogWithSnapShot {
-            last_included_index: last_index as u64,
-            last_included_term: raft.log.term_at(last_index),
-            state_machine_state: state,
-            commands: new_commands,
-        };
-        raft.log = new_log;
-        info!(
-            "{} takes snapshot (from index: {}), remained log size = {}",
-            raft.self_info(),
-            last_index,
-            raft.log.commands.len(),
-        );
-        raft.persist();
     }
 
-    /// get current raft log size.
-    pub fn log_size(&self) -> usize {
-        let raft = self.raft.lock().unwrap();
-        raft.log_size
+    /// reply a rpc request once the writes issued before are saved, since the reply promises them.
+    /// If one of them fails, the reply is dropped, and the requester times out.
+    fn reply_persisted<T: Debug + Send + 'static>(&mut self, sender: oneshot::Sender<T>, value: T) {
+        if self.write_failed {
+            debug!(
+                "{} won't reply {:?} since a failed write.",
+                self.self_info(),
+                value
+            );
+            return;
+        }
+        if self.writes_saved >= self.writes_issued {
+            reply_rpc(sender, value);
+            return;
+        }
+        self.held_replies.push_back((
+            self.writes_issued,
+            Box::new(move || reply_rpc(sender, value)),
+        ));
+    }
+
+    /// handle a message from other peers.
+    fn step_message(&mut self, message: Message) {
+        match message {
+            Message::RequestVote(args, sender) => {
+                let reply = self.do_request_vote(args);
+                self.reply_persisted(sender, reply)
+            }
+            Message::AppendEntries(args, sender) => {
+                let reply = self.do_append_entries(args);
+                self.reply_persisted(sender, reply)
+            }
+            Message::InstallSnapshot(args, sender) => {
+                let reply = self.do_install_snapshot(args);
+                self.reply_persisted(sender, reply)
+            }
+            Message::RequestVoteResponse(response) => self.handle_request_vote(response),
+            Message::AppendEntriesResponse(response) => self.handle_append_entries(response),
+            Message::InstallSnapshotResponse(response) => self.handle_install_snapshot(response),
+        }
     }
 
-    /// Create a new raft service.
-    pub fn new(raft: Raft) -> Node {
-        // Your code here.
-        let me = raft.me;
-        info!("new node NO「{}」started.", me);
-        let raft = Arc::new(Mutex::new(raft));
-        Raft::transform_to_follower(raft.clone());
-        Node { raft: raft.clone() }
-    }
+    /// handle the timers, this is called after every event, and when the nearest deadline passes.
+    /// - followers and candidates start an election once `election_deadline` passes.
+    /// - the leader flushes the batched proposals and sends them once `flush_at` passes,
+    ///   and sends heartbeats every `heartbeat_interval`,
+    ///   which also retransmit the requests that may be lost.
+    fn tick(&mut self) {
+        let now = self.now();
+        if self.election_deadline.map_or(false, |t| t <= now) {
+            self.campaign();
+        }
 
-    /// the service using Raft (e.g. a k/v server) wants to start
-    /// agreement on the next command to be appended to Raft's log. if this
-    /// server isn't the leader, returns [Error::NotLeader]. otherwise start
-    /// the agreement and return immediately. there is no guarantee that this
-    /// command will ever be committed to the Raft log, since the leader
-    /// may fail or lose an election. even if the Raft instance has been killed,
-    /// this function should return gracefully.
-    ///
-    /// the first value of the tuple is the index that the command will appear
-    /// at if it's ever committed. the second is the current term.
-    ///
-    /// This method must return without blocking on the raft.
-    pub fn start<M>(&self, command: &M) -> Result<(u64, u64)>
-    where
-        M: labcodec::Message,
-    {
-        // Your code here.
-        // Example:
-        // self.raft.start(command)
-        let mut raft = self.raft.lock().unwrap();
-        raft.start(command)
+        let (flush, heartbeat) = match self.leader_state.as_ref() {
+            Some(ls) => (
+                ls.flush_at.map_or(false, |t| t <= now),
+                ls.next_heartbeat <= now,
+            ),
+            None => return,
+        };
+        if !flush && !heartbeat {
+            return;
+        }
+        if heartbeat && self.extra.check_quorum && !self.quorum_active() {
+            info!(
+                "{} hasn't heard from a majority in an election timeout, steps down.",
+                self.self_info()
+            );
+            self.become_follower();
+            return;
+        }
+        self.flush_proposals();
+        if heartbeat {
+            let delay = self.extra.heartbeat_interval;
+            self.leader_state.as_mut().unwrap().next_heartbeat = now + delay;
+            self.expire_reads();
+            self.transfer_leadership();
+            debug!(
+                "{}: leader_state = {:?} (log len = {})",
+                self.self_info(),
+                self.leader_state,
+                self.log.last_index()
+            );
+        }
+        for i in 0..self.peers.peer_count() {
+            if i != self.me {
+                self.replicate_to(i, heartbeat);
+            }
+        }
     }
 
-    /// The current term of this peer.
-    pub fn term(&self) -> u64 {
-        // Your code here.
-        // Example:
-        // self.raft.term
-        let guard = self.raft.lock().unwrap();
-        guard.term
+    /// the nearest deadline of the timers, `None` if all timers are stopped.
+    fn next_deadline(&self) -> Option<Instant> {
+        let ls = self.leader_state.as_ref();
+        self.election_deadline
+            .into_iter()
+            .chain(ls.map(|ls| ls.next_heartbeat))
+            .chain(ls.and_then(|ls| ls.flush_at))
+            .min()
     }
 
-    /// Whether this peer believes it is the leader.
-    pub fn is_leader(&self) -> bool {
-        // Your code here.
-        // Example:
-        // self.raft.leader_id == self.id
-        let guard = self.raft.lock().unwrap();
-        guard.is_leader()
+    /// publish the current term and role to `Node`.
+    fn publish_state(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.term = self.term;
+        state.is_leader = self.is_leader();
     }
 
-    /// The current state of this peer.
-    pub fn get_state(&self) -> State {
-        State {
-            term: self.term(),
-            is_leader: self.is_leader(),
+    /// take the snapshot of `state`, with `last_included_index = last_index`.
+    fn take_snapshot(&mut self, state: SnapshotFile, last_index: usize) {
+        let last_index = last_index as u64;
+        let mut snapshot = SnapshotEncoder::new(last_index, self.log.term_at(last_index));
+        for chunk in state.commands {
+            snapshot.write(chunk);
         }
+        self.take_encoded_snapshot(snapshot);
     }
 
-    /// get the current `commit_index` of raft.
-    pub fn commit_index(&self) -> u64 {
-        let rf = self.raft.lock().unwrap();
-        rf.commit_index
-    }
+    /// take the snapshot encoded by the service.
+    fn take_encoded_snapshot(&mut self, snapshot: SnapshotEncoder) {
+        let last_index = snapshot.last_included_index;
+        if self.log.is_in_snapshot(last_index) {
+            error!(
+                "{} :( (till_index = {}; last_contains_index = {})",
+                self.self_info(),
+                last_index,
+                self.log.last_included_index(),
+            );
+            return;
+        }
 
-    /// reset the election timer of raft.
-    pub fn reset_timer(&self) {
-        let guard = self.raft.lock().unwrap();
-        guard.reset_election_timer();
-    }
+        assert!(
+            last_index <= self.last_applied,
+            "{} Try to take snapshot when not applied!",
+            self.self_info(),
+        );
 
-    /// the tester calls kill() when a Raft instance won't be
-    /// needed again. you are not required to do anything in
-    /// kill(), but it might be convenient to (for example)
-    /// turn off debug output from this instance.
-    /// In Raft paper, a server crash is a PHYSICAL crash,
-    /// A.K.A all resources are reset. But we are simulating
-    /// a VIRTUAL crash in tester, so take care of background
-    /// threads you generated with this Raft Node.
-    pub fn kill(&self) {
-        let mut guard = self.raft.lock().unwrap();
-        // stop leader timer.
-        guard.current_role = Follower;
-        // stop follower timer.
-        guard.try_send_to_election_timer(Stop);
-        unsafe {
-            guard.leader_execution_pool.terminate();
+        if self.is_leader() {
+            info!("{} ls = {:?}", self.self_info(), self.leader_state);
         }
-        info!("NO{} is dead.", guard.me);
-    }
 
-    /// wrapper for `Raft::check_term`.
-    fn check_term(&self, new_term: u64) -> bool {
-        Raft::check_term(self.raft.clone(), new_term)
+        let last_included_term = self.log.term_at(last_index);
+        if snapshot.last_included_term != last_included_term {
+            error!(
+                "{} refuses the snapshot to {} of term {}, the entry is of term {}.",
+                self.self_info(),
+                last_index,
+                snapshot.last_included_term,
+                last_included_term,
+            );
+            return;
+        }
+        self.log.compact(last_index, last_included_term);
+        self.snapshot = snapshot.finish();
+        self.snapshot_saved = false;
+        info!(
+            "{} takes snapshot (from index: {}), remained log size = {}",
+            self.self_info(),
+            last_index,
+            self.log.last_index() - last_index,
+        );
+        self.persist();
     }
 
     /// The implementation of AppendEntries.
     /// See the raft paper figure 2.
-    /// This function will be executed in an OS thread, don't worry even it blocks.
+    /// This function runs in the event loop, it must not block.
     fn do_append_entries_judge(
-        &self,
+        &mut self,
         mut args: AppendEntriesArgs,
     ) -> std::result::Result<(), FailedAppendEntries> {
         // pre-handle: check term.
         self.check_term(args.term);
 
         // 1. Reply false if term < currentTerm.
-        if args.term < self.term() {
+        if args.term < self.term {
             return Err(FailedAppendEntries::InvalidLeader);
         }
 
         // this message is sent by a valid leader, reset election timer.
-        self.reset_timer();
-
-        let mut raft = self.raft.lock().unwrap();
+        self.reset_election_timer();
+        self.last_leader_contact = Some(self.now());
+        self.set_leader(Some(args.leader_id as usize));
 
         // 2. Reply false if log doesn't match.
-        let prev_log_index = args.prev_log_index as usize;
-        let term_matches = raft.log.term_at(prev_log_index) == args.prev_log_term;
+        let prev_log_index = args.prev_log_index;
+        let term_matches = self.log.term_at(prev_log_index) == args.prev_log_term;
         if !term_matches {
-            if raft.log.is_in_snapshot(prev_log_index) {
+            if self.log.is_in_snapshot(prev_log_index) {
                 return Err(FailedAppendEntries::ConflictedEntry {
                     conflicted_term: 0,
                     // just let leader reset our index, and send a snapshot.
@@ -1482,18 +2686,17 @@ impl Node {
                 });
             }
 
-            let entry = raft.log.get(prev_log_index);
-            if entry.is_none() {
+            if prev_log_index > self.last_log_index() {
                 return Err(FailedAppendEntries::ConflictedEntry {
                     conflicted_term: 0,
                     // roll back to last index.
-                    conflicted_term_starts_at: raft.last_log_index() + 1,
+                    conflicted_term_starts_at: self.last_log_index() + 1,
                 });
             }
 
-            let conflicted_term = entry.unwrap().term;
+            let conflicted_term = self.log.term_at(prev_log_index);
             let conflicted_term_starts_at =
-                raft.get_term_starts_at(conflicted_term, prev_log_index) as u64;
+                self.get_term_starts_at(conflicted_term, prev_log_index);
             return Err(FailedAppendEntries::ConflictedEntry {
                 conflicted_term,
                 conflicted_term_starts_at,
@@ -1502,43 +2705,68 @@ impl Node {
 
         // 3. Test matching. If conflict, truncate the log.
         let base = prev_log_index + 1;
+        if let Some(i) = args.entries.iter().position(|entry| !entry.is_intact()) {
+            warn!(
+                "{} the entry at {} from NO{} mismatches its checksum, rejecting it.",
+                self.self_info(),
+                base + i as u64,
+                args.leader_id
+            );
+            return Err(FailedAppendEntries::Corrupted);
+        }
         let mut entries: Vec<LogEntry> = args.entries.drain(..).map(Into::into).collect();
-        let new_log_base = raft.check_and_trunc_log(base, &entries);
+        let new_log_base = self.check_and_trunc_log(base, &entries);
+        // entries after the last new entry may not match the leader's.
+        let last_new_index = prev_log_index + entries.len() as u64;
 
         // 4. Append any new entries.
         let new_logs: Vec<LogEntry> = entries.drain(new_log_base..).collect();
         let log_changed = !new_logs.is_empty();
-        for entry in new_logs.into_iter() {
-            raft.log.push(entry)
-        }
+        self.append_log(new_logs);
 
         // 5. Set commit index.
-        if args.leader_commit > raft.commit_index {
-            let next = Ord::min(args.leader_commit, raft.last_log_index());
-            raft.commit_index = next;
-            raft.apply_logs();
+        let next = Ord::min(args.leader_commit, last_new_index);
+        if next > self.commit_index {
+            self.commit_to(next);
+            self.apply_logs();
         }
 
         // Anyway, persist it.
         if log_changed {
-            raft.persist();
+            self.persist();
         }
 
         Ok(())
     }
 
     /// follower handler for `AppendEntries`.
-    fn do_append_entries(&self, args: AppendEntriesArgs) -> AppendEntriesReply {
+    fn do_append_entries(&mut self, args: AppendEntriesArgs) -> AppendEntriesReply {
+        let prev_log_index = args.prev_log_index;
+        let timeout_now = args.timeout_now;
+        let up_to_date_peers = args.up_to_date_peers.clone();
         let success = self.do_append_entries_judge(args);
         match success {
-            Ok(()) => AppendEntriesReply {
-                term: self.term(),
-                success: true,
-                conflicted_term: 0,
-                conflicted_term_starts_at: 0,
-            },
+            Ok(()) => {
+                for peer in up_to_date_peers {
+                    self.saw_up_to_date(peer as usize);
+                }
+                if timeout_now {
+                    info!(
+                        "{} campaigns at once, since the leader hands off to it.",
+                        self.self_info()
+                    );
+                    self.leader_transfer = true;
+                    self.election_deadline = Some(self.now());
+                }
+                AppendEntriesReply {
+                    term: self.term,
+                    success: true,
+                    conflicted_term: 0,
+                    conflicted_term_starts_at: 0,
+                }
+            }
             Err(FailedAppendEntries::InvalidLeader) => AppendEntriesReply {
-                term: self.term(),
+                term: self.term,
                 success: false,
                 // for debug usage -- those fields shouldn't be used.
                 // TODO: 使用 oneof 而不是（不太安全的）积类型来完成这项工作。
@@ -1549,76 +2777,503 @@ impl Node {
                 conflicted_term,
                 conflicted_term_starts_at,
             }) => AppendEntriesReply {
-                term: self.term(),
+                term: self.term,
                 success: false,
                 conflicted_term,
                 conflicted_term_starts_at,
             },
+            // roll back to the entry before them, so the leader probes from there.
+            Err(FailedAppendEntries::Corrupted) => AppendEntriesReply {
+                term: self.term,
+                success: false,
+                conflicted_term: 0,
+                conflicted_term_starts_at: prev_log_index + 1,
+            },
         }
     }
 
     /// follower handler for `RequestVote`.
-    fn do_request_vote(&self, args: RequestVoteArgs) -> RequestVoteReply {
+    fn do_request_vote(&mut self, args: RequestVoteArgs) -> RequestVoteReply {
+        // the leader has given up its lease before handing off.
+        if !args.leader_transfer && self.in_leader_lease() {
+            info!(
+                "{} ignores RV({:?}) since the leader lease.",
+                self.self_info(),
+                args
+            );
+            return RequestVoteReply {
+                term: self.term,
+                vote_granted: false,
+            };
+        }
         self.check_term(args.term);
-        let mut raft = self.raft.lock().unwrap();
         debug!("request_vote({:?})", args);
-        let granted = raft.check_grant(&args);
+        if (args.last_log_term, args.last_log_index)
+            >= (self.last_log_term(), self.last_log_index())
+        {
+            self.saw_up_to_date(args.candidate_id as usize);
+        }
+        let granted = self.check_grant(&args);
         info!(
             "{} grant to RV({:?})? = {}",
-            raft.self_info(),
+            self.self_info(),
             args,
             granted
         );
         if granted {
-            raft.reset_election_timer();
+            self.reset_election_timer();
             // NOTE：即便没有设置投票者……（就是说，一个节点可以在一个 term 中投多个票）
             // 我们仍旧可以几乎所有情况下通过 2A 和 2B 的所有测试……
             // 为什么没有发生脑裂呢……？
-            raft.vote_for(args.candidate_id as usize)
+            self.vote_for(args.candidate_id as usize)
         }
         RequestVoteReply {
-            term: raft.term,
+            term: self.term,
             vote_granted: granted,
         }
     }
 
     /// follower handler for `InstallSnapshot`
-    fn do_install_snapshot(&self, args: InstallSnapshotArgs) -> InstallSnapshotReply {
+    fn do_install_snapshot(&mut self, args: InstallSnapshotArgs) -> InstallSnapshotReply {
         self.check_term(args.term);
-        let term = self.term();
+        let term = self.term;
         if args.term < term {
-            return InstallSnapshotReply { term };
+            return InstallSnapshotReply {
+                term,
+                next_offset: 0,
+                done: false,
+            };
         }
 
-        let mut raft = self.raft.lock().unwrap();
         // this is from a valid leader, reset election timer.
-        raft.reset_election_timer();
+        self.reset_election_timer();
+        self.last_leader_contact = Some(self.now());
+        self.set_leader(Some(args.leader_id as usize));
 
         // 防止返回乱序……
-        if raft.log.len() > args.last_included_index as usize {
-            return InstallSnapshotReply { term };
+        // if we have got the last included entry, retain the log following it.
+        let last_included_index = args.last_included_index;
+        if self.log.last_index() > last_included_index
+            && (self.log.is_in_snapshot(last_included_index)
+                || self.log.term_at(last_included_index) == args.last_included_term)
+        {
+            self.pending_snapshot = None;
+            return InstallSnapshotReply {
+                term,
+                next_offset: 0,
+                done: true,
+            };
+        }
+
+        // assemble the chunk.
+        let accepted = self
+            .pending_snapshot
+            .as_ref()
+            .map_or(false, |pending| pending.accepts(&args));
+        if !accepted && args.offset == 0 {
+            self.pending_snapshot = Some(PendingSnapshot {
+                last_included_index: args.last_included_index,
+                last_included_term: args.last_included_term,
+                checksum: args.checksum,
+                data: vec![],
+            });
+        }
+        let pending = match self.pending_snapshot.as_mut() {
+            Some(pending) if pending.accepts(&args) => pending,
+            // a chunk of another snapshot, ask the leader to restart.
+            _ => {
+                return InstallSnapshotReply {
+                    term,
+                    next_offset: 0,
+                    done: false,
+                }
+            }
+        };
+        let received = pending.data.len() as u64;
+        let end = args.offset + args.data.len() as u64;
+        if args.offset <= received && end > received {
+            pending
+                .data
+                .extend_from_slice(&args.data[(received - args.offset) as usize..]);
+        }
+        let received = pending.data.len() as u64;
+        if !args.done || received != end {
+            return InstallSnapshotReply {
+                term,
+                next_offset: received,
+                done: false,
+            };
         }
-        raft.log = args.into();
 
-        let last_included_index = raft.log.last_included_index;
-        raft.commit_index = last_included_index;
-        raft.persist();
-        raft.apply_snapshot();
+        let pending = self.pending_snapshot.take().unwrap();
+        if fnv1a(&pending.data) != pending.checksum {
+            warn!(
+                "{} Snapshot to index {} is corrupted, dropping it.",
+                self.self_info(),
+                pending.last_included_index
+            );
+            return InstallSnapshotReply {
+                term,
+                next_offset: 0,
+                done: false,
+            };
+        }
+        let snapshot: Snapshot = match decode(&pending.data) {
+            Ok(snapshot) if snapshot.is_intact() => snapshot,
+            Ok(_) => {
+                warn!(
+                    "{} Snapshot to index {} mismatches its checksum, dropping it.",
+                    self.self_info(),
+                    pending.last_included_index
+                );
+                return InstallSnapshotReply {
+                    term,
+                    next_offset: 0,
+                    done: false,
+                };
+            }
+            Err(e) => {
+                warn!(
+                    "{} Failed to decode snapshot to index {}: {:?}.",
+                    self.self_info(),
+                    pending.last_included_index,
+                    e
+                );
+                return InstallSnapshotReply {
+                    term,
+                    next_offset: 0,
+                    done: false,
+                };
+            }
+        };
+        self.log.reset(
+            snapshot.last_index_of_snapshot,
+            snapshot.last_term_of_snapshot,
+        );
+        self.emit(|| RaftEvent::LogReset {
+            index: snapshot.last_index_of_snapshot,
+            term: snapshot.last_term_of_snapshot,
+        });
+        self.snapshot = EncodedSnapshot {
+            data: pending.data,
+            checksum: pending.checksum,
+        };
+        self.snapshot_saved = false;
+
+        let last_included_index = self.log.last_included_index();
+        self.commit_index = last_included_index;
+        self.persist();
+        self.apply_snapshot(snapshot.state_machine_state);
         info!(
             "{} Installed snapshot to index {}.",
-            raft.self_info(),
+            self.self_info(),
             last_included_index,
         );
+        self.notify(NodeEvent::SnapshotInstalled {
+            last_included_index,
+            last_included_term: self.log.last_included_term(),
+        });
+
+        InstallSnapshotReply {
+            term,
+            next_offset: received,
+            done: true,
+        }
+    }
+}
+
+impl Node {
+    /// send an event to the event loop of the raft.
+    ///
+    /// # returns
+    /// `false` if the raft has been killed.
+    fn send_event(&self, event: Event) -> bool {
+        self.events.lock().unwrap().send(event)
+    }
+
+    /// run `f` on the raft in its event loop, and wait for the result.
+    ///
+    /// # returns
+    /// `None` if the raft has been killed.
+    fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Raft) -> T + Send + 'static,
+    ) -> Option<T> {
+        let (sx, rx) = channel();
+        self.send_event(Event::Call(Box::new(move |raft: &mut Raft| {
+            reply(&sx, f(raft))
+        })));
+        rx.recv().ok()
+    }
+
+    /// Get raft log entries between `start` and `end` (inclusive).
+    ///
+    /// # returns
+    /// The cloned log range.
+    ///
+    /// # panics
+    /// If try to access log that is in snapshot or not committed.
+    pub fn log_between(&self, start: usize, end: usize) -> Vec<ApplyMsg> {
+        self.call(move |rf| {
+            if end > rf.commit_index as usize || rf.log.is_in_snapshot(start as u64) {
+                return None;
+            }
+            Some(rf.make_apply_messages(start as u64, end as u64))
+        })
+        .map(|msgs| msgs.expect("Try to get log entry that not committed or in snapshot."))
+        .unwrap_or_default()
+    }
+
+    /// Like `log_between`, but returns empty vector when illegal access.
+    pub fn try_get_log_between(&self, start: usize, end: usize) -> Vec<ApplyMsg> {
+        self.call(move |rf| {
+            if rf.log.is_in_snapshot(start as u64) || end > rf.commit_index as usize {
+                return vec![];
+            }
+            rf.make_apply_messages(start as u64, end as u64)
+        })
+        .unwrap_or_default()
+    }
+
+    /// take the snapshot of `state`, with `last_included_index = last_index`.
+    pub fn take_snapshot(&self, state: SnapshotFile, last_index: usize) {
+        self.call(move |raft| raft.take_snapshot(state, last_index));
+    }
+
+    /// like `take_snapshot`, but the chunks have been encoded by the service as they were written,
+    /// see `state_machine::Driver`.
+    pub fn take_encoded_snapshot(&self, snapshot: SnapshotEncoder) {
+        self.call(move |raft| raft.take_encoded_snapshot(snapshot));
+    }
+
+    /// subscribe the `NodeEvent`s of this peer, e.g. to react when it becomes the leader
+    /// or loses the leadership, instead of polling `is_leader`.
+    ///
+    /// The first events are the current role and leader, then the changes are delivered in order.
+    /// The stream ends once the peer is killed.
+    pub fn subscribe(&self) -> UnboundedReceiver<NodeEvent> {
+        let (sx, rx) = unbounded();
+        self.send_event(Event::Call(Box::new(move |raft: &mut Raft| {
+            let term = raft.term;
+            let current = [
+                NodeEvent::RoleChanged {
+                    role: raft.current_role.into(),
+                    term,
+                },
+                NodeEvent::LeaderChanged {
+                    leader: raft.leader_id.map(|id| id as u64),
+                    term,
+                },
+            ];
+            if current
+                .iter()
+                .all(|event| sx.unbounded_send(event.clone()).is_ok())
+            {
+                raft.subscribers.push(sx);
+            }
+        })));
+        rx
+    }
+
+    /// get a snapshot of the state of raft, for debugging and monitoring.
+    ///
+    /// # returns
+    /// the default `RaftStatus` if the peer has been killed.
+    pub fn status(&self) -> RaftStatus {
+        self.call(|raft| raft.status()).unwrap_or_default()
+    }
+
+    /// whether the persisted raft state has grown over `RaftConfig::snapshot_threshold`,
+    /// so the service should take a snapshot.
+    /// It's cheap enough to check after every applied entry, since it doesn't call into the event loop.
+    pub fn needs_snapshot(&self) -> bool {
+        self.snapshot_threshold
+            .map_or(false, |threshold| self.log_size() > threshold)
+    }
+
+    /// get current raft log size.
+    pub fn log_size(&self) -> usize {
+        self.log_size.load(AtomicOrdering::Acquire)
+    }
+
+    /// Create a new raft service.
+    /// This spawns the event loop of `raft`.
+    pub fn new(mut raft: Raft) -> Node {
+        // Your code here.
+        let me = raft.me;
+        info!("new node NO「{}」started.", me);
+        let events = raft
+            .event_rx
+            .take()
+            .expect("fetal: the raft is driven by another node.");
+        let node = Node {
+            events: Arc::new(Mutex::new(raft.events.clone())),
+            state: raft.state.clone(),
+            log_size: raft.log_size.clone(),
+            snapshot_threshold: raft.extra.snapshot_threshold,
+        };
+        raft.become_follower();
+        raft.publish_state();
+        std::thread::Builder::new()
+            .name(format!("raft NO{}", me))
+            .spawn(move || raft.run(events))
+            .expect("failed to spawn the event loop of raft.");
+        node
+    }
+
+    /// the service using Raft (e.g. a k/v server) wants to start
+    /// agreement on the next command to be appended to Raft's log. if this
+    /// server isn't the leader, returns [Error::NotLeader]. otherwise start
+    /// the agreement and return immediately. there is no guarantee that this
+    /// command will ever be committed to the Raft log, since the leader
+    /// may fail or lose an election. even if the Raft instance has been killed,
+    /// this function should return gracefully.
+    ///
+    /// the first value of the tuple is the index that the command will appear
+    /// at if it's ever committed. the second is the current term.
+    ///
+    /// This doesn't wait for the agreement or any write, only for the event loop to append the command.
+    pub fn start<M>(&self, command: &M) -> Result<(u64, u64)>
+    where
+        M: labcodec::Message,
+    {
+        // Your code here.
+        // Example:
+        // self.raft.start(command)
+        let mut buf = vec![];
+        labcodec::encode(command, &mut buf).map_err(Error::Encode)?;
+        let (sx, rx) = channel();
+        self.send_event(Event::Propose(buf, sx, None));
+        rx.recv().unwrap_or(Err(Error::NotLeader))
+    }
 
-        InstallSnapshotReply { term: raft.term }
+    /// like `start`, but also track the entry until it is applied.
+    ///
+    /// Callers don't need to match applied entries by index and compare them with their commands,
+    /// to know whether the proposals are overwritten: the returned `Proposal` does this.
+    ///
+    /// # returns
+    /// `NotLeader` if this peer isn't the leader,
+    /// otherwise the `Proposal` future, see it for when it resolves or fails.
+    pub fn propose<M>(&self, command: &M) -> Result<Proposal>
+    where
+        M: labcodec::Message,
+    {
+        let mut buf = vec![];
+        labcodec::encode(command, &mut buf).map_err(Error::Encode)?;
+        let (sx, rx) = channel();
+        let (applied_sx, applied) = oneshot::channel();
+        self.send_event(Event::Propose(buf, sx, Some(applied_sx)));
+        let (index, term) = rx.recv().unwrap_or(Err(Error::NotLeader))?;
+        Ok(Proposal {
+            index,
+            term,
+            applied,
+        })
+    }
+
+    /// ReadIndex: get an index that is safe to serve linearizable reads at,
+    /// without appending anything to the log.
+    ///
+    /// The leader records its `commit_index`, then confirms that it is still the leader
+    /// by a round of heartbeats. Once the state machine has applied to the returned index,
+    /// it can answer the read locally.
+    ///
+    /// This function blocks until a majority acknowledges the heartbeats, or timeout.
+    ///
+    /// # returns
+    /// - `NotLeader` if this peer isn't (or is no longer) the leader.
+    /// - `NoCommittedEntryInTerm` if the leader hasn't committed any entry of its term,
+    ///   its `commit_index` may be stale then, the caller should fall back to `start`.
+    ///   It lasts only until the no-op entry is committed, unless `RaftConfigBuilder::leader_no_op` is off.
+    /// - `LeadershipUnconfirmed` if a majority doesn't respond in time.
+    pub fn read_index(&self) -> Result<u64> {
+        let (sx, rx) = channel();
+        self.send_event(Event::ReadIndex(sx));
+        rx.recv().unwrap_or(Err(Error::NotLeader))
+    }
+
+    /// Whether this peer is the leader, and holds the read lease.
+    /// Always `false` if the lease read mode isn't enabled.
+    pub fn has_lease(&self) -> bool {
+        self.call(|raft| raft.has_lease()).unwrap_or(false)
     }
+
+    /// The current term of this peer.
+    pub fn term(&self) -> u64 {
+        // Your code here.
+        // Example:
+        // self.raft.term
+        self.state.lock().unwrap().term
+    }
+
+    /// Whether this peer believes it is the leader.
+    pub fn is_leader(&self) -> bool {
+        // Your code here.
+        // Example:
+        // self.raft.leader_id == self.id
+        self.state.lock().unwrap().is_leader
+    }
+
+    /// The current state of this peer.
+    pub fn get_state(&self) -> State {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// get the current `commit_index` of raft.
+    pub fn commit_index(&self) -> u64 {
+        self.call(|raft| raft.commit_index).unwrap_or_default()
+    }
+
+    /// make the clock of raft jump forward by `by`, for simulating faulty clocks in tests.
+    /// The timers fire earlier, and the leases expire earlier.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn jump_clock(&self, by: Duration) {
+        self.call(move |raft| raft.clock_offset += by);
+    }
+
+    /// reset the election timer of raft.
+    pub fn reset_timer(&self) {
+        self.call(|raft| raft.reset_election_timer());
+    }
+
+    /// the tester calls kill() when a Raft instance won't be
+    /// needed again. you are not required to do anything in
+    /// kill(), but it might be convenient to (for example)
+    /// turn off debug output from this instance.
+    /// In Raft paper, a server crash is a PHYSICAL crash,
+    /// A.K.A all resources are reset. But we are simulating
+    /// a VIRTUAL crash in tester, so take care of background
+    /// threads you generated with this Raft Node.
+    ///
+    /// This stops the event loop, and waits for it.
+    pub fn kill(&self) {
+        let (sx, rx) = channel();
+        if self.send_event(Event::Kill(sx)) {
+            rx.recv()
+                .unwrap_or_else(|_| debug!("the event loop has stopped."));
+        }
+    }
+}
+
+/// declare a rpc endpoint, that hands the request to the event loop as `Message::$message`,
+/// and replies after the event loop handled it.
+/// Unlike `async_rpc!`, this doesn't spawn any thread.
+macro_rules! event_rpc {
+    ($name:ident($arg:ty) -> $rel:ty where sends $message:ident) => {
+        fn $name(&self, args: $arg) -> RpcFuture<$rel> {
+            let (sx, rx) = oneshot::channel();
+            self.send_event(Event::Message(Message::$message(args, sx)));
+            Box::new(rx.map_err(|_| labrpc::Error::Stopped))
+        }
+    };
 }
 
 impl RaftService for Node {
     // example RequestVote RPC handler.
     //
     // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
-    async_rpc! { request_vote(RequestVoteArgs) -> RequestVoteReply where uses Self::do_request_vote }
-    async_rpc! { append_entries(AppendEntriesArgs) -> AppendEntriesReply where uses Self::do_append_entries }
-    async_rpc! { install_snapshot(InstallSnapshotArgs) -> InstallSnapshotReply where uses Self::do_install_snapshot }
+    event_rpc! { request_vote(RequestVoteArgs) -> RequestVoteReply where sends RequestVote }
+    event_rpc! { append_entries(AppendEntriesArgs) -> AppendEntriesReply where sends AppendEntries }
+    event_rpc! { install_snapshot(InstallSnapshotArgs) -> InstallSnapshotReply where sends InstallSnapshot }
 }
@@ -0,0 +1,392 @@
+//! Multi-raft: many raft groups hosted by one store.
+//!
+//! A `Host` runs the peers of all its groups in one event loop, instead of a thread per peer,
+//! and saves their states by one `PersistWriter`, so a store needs two threads for any count of groups.
+//! The groups share:
+//! - the transport: one `MultiRaftClient` to each store, whose messages carry the group id,
+//!   and are routed to the group by the `MultiRaftService` of the receiving store.
+//! - the ticks: every `tick_interval`, all groups are ticked at once, so the leaders that send
+//!   heartbeats to the same store send them together. The heartbeats queued in a round of the
+//!   event loop are coalesced into one `heartbeats` rpc for each store.
+//!
+//! Every group spans all stores, and the peer id of a group is the index of its store,
+//! so `Raft::me` of every group equals `Host::me`.
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::sync::oneshot;
+use futures::{future, Future};
+use labrpc::RpcFuture;
+
+use crate::proto::raftpb::*;
+use crate::raft::async_persister::PersistWriter;
+use crate::raft::{reply, Event, Mailbox, Message, Node, Raft, Transport};
+
+/// A heartbeat waiting to be sent: the group, the args, and where the reply goes.
+type QueuedHeartbeat = (u64, AppendEntriesArgs, oneshot::Sender<AppendEntriesReply>);
+
+/// The messages to the event loop of a `Host`.
+pub(super) enum HostMessage {
+    /// an event to the peer of a group.
+    Event(u64, Event),
+    /// host a new group.
+    Add(u64, Box<Raft>),
+    /// stop all groups and the event loop.
+    Stop(Sender<()>),
+}
+
+struct HostInner {
+    me: usize,
+    /// the client to each store, including this one.
+    stores: Vec<MultiRaftClient>,
+    sender: Mutex<Sender<HostMessage>>,
+    /// the writer of the states of all groups.
+    writer: Mutex<PersistWriter>,
+    /// the heartbeats to each store, sent after each round of the event loop.
+    heartbeats: Mutex<Vec<Vec<QueuedHeartbeat>>>,
+}
+
+/// The raft groups of a store, it's also the `MultiRaftService` of the store.
+#[derive(Clone)]
+pub struct Host {
+    inner: Arc<HostInner>,
+}
+
+impl Host {
+    /// Create the host of store `me`, and spawn its event loop.
+    /// `stores` are the clients to all stores, including this one.
+    ///
+    /// The heartbeats of groups can be coalesced only if they are sent in the same tick,
+    /// so `tick_interval` should be well below the heartbeat interval, e.g. the half of it.
+    pub fn new(me: usize, stores: Vec<MultiRaftClient>, tick_interval: Duration) -> Host {
+        let (sender, messages) = channel();
+        let host = Host {
+            inner: Arc::new(HostInner {
+                me,
+                heartbeats: Mutex::new(stores.iter().map(|_| vec![]).collect()),
+                stores,
+                sender: Mutex::new(sender),
+                writer: Mutex::new(PersistWriter::spawn()),
+            }),
+        };
+        let event_loop = EventLoop {
+            host: host.clone(),
+            groups: HashMap::new(),
+            tick_interval,
+        };
+        std::thread::Builder::new()
+            .name(format!("raft host NO{}", me))
+            .spawn(move || event_loop.run(messages))
+            .expect("failed to spawn the event loop of raft host.");
+        host
+    }
+
+    /// the index of this store.
+    pub fn me(&self) -> usize {
+        self.inner.me
+    }
+
+    /// the transport of `group`, to create its peer by `Raft::with_transport`.
+    pub fn transport(&self, group: u64) -> Box<dyn Transport> {
+        Box::new(GroupTransport {
+            group,
+            host: self.clone(),
+        })
+    }
+
+    /// host the peer of `group`, which must be created with `transport(group)`.
+    ///
+    /// # panics
+    /// if the peer isn't the one of this store.
+    pub fn add_group(&self, group: u64, mut raft: Raft) -> Node {
+        assert_eq!(
+            raft.me, self.inner.me,
+            "the peer of group {} isn't the one of this store.",
+            group
+        );
+        info!("new node NO「{}」of group {} started.", raft.me, group);
+        let mailbox = Mailbox::Group(group, self.sender());
+        raft.events = mailbox.clone();
+        raft.event_rx = None;
+        raft.persister
+            .share_writer(self.inner.writer.lock().unwrap().clone());
+        let node = Node {
+            events: Arc::new(Mutex::new(mailbox)),
+            state: raft.state.clone(),
+            log_size: raft.log_size.clone(),
+            snapshot_threshold: raft.extra.snapshot_threshold,
+        };
+        self.send(HostMessage::Add(group, Box::new(raft)));
+        node
+    }
+
+    /// stop all groups and the event loop, and wait for it.
+    pub fn stop(&self) {
+        let (sx, rx) = channel();
+        if self.send(HostMessage::Stop(sx)) {
+            rx.recv()
+                .unwrap_or_else(|_| debug!("the event loop of host has stopped."));
+        }
+    }
+
+    fn sender(&self) -> Sender<HostMessage> {
+        self.inner.sender.lock().unwrap().clone()
+    }
+
+    /// # returns
+    /// `false` if the event loop has stopped.
+    fn send(&self, message: HostMessage) -> bool {
+        self.inner.sender.lock().unwrap().send(message).is_ok()
+    }
+
+    /// hand a rpc request to the peer of `group`, and reply after the peer handled it.
+    fn send_message<Rep>(
+        &self,
+        group: u64,
+        message: impl FnOnce(oneshot::Sender<Rep>) -> Message,
+    ) -> oneshot::Receiver<Rep> {
+        let (sx, rx) = oneshot::channel();
+        // if the group isn't hosted, the sender is dropped, and the receiver is cancelled.
+        self.send(HostMessage::Event(group, Event::Message(message(sx))));
+        rx
+    }
+
+    /// queue a heartbeat to `to`, it is sent by `flush_heartbeats`.
+    fn queue_heartbeat(
+        &self,
+        to: usize,
+        group: u64,
+        args: AppendEntriesArgs,
+    ) -> RpcFuture<AppendEntriesReply> {
+        let (sx, rx) = oneshot::channel();
+        self.inner.heartbeats.lock().unwrap()[to].push((group, args, sx));
+        Box::new(rx.map_err(|_| labrpc::Error::Stopped))
+    }
+
+    /// send the queued heartbeats, in one rpc for each store.
+    fn flush_heartbeats(&self) {
+        let batches = self
+            .inner
+            .heartbeats
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .map(|queue| queue.drain(..).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        for (to, batch) in batches.into_iter().enumerate() {
+            if batch.is_empty() {
+                continue;
+            }
+            let (heartbeats, senders): (Vec<_>, Vec<_>) = batch
+                .into_iter()
+                .map(|(group_id, args, sender)| {
+                    let args = GroupAppendEntriesArgs {
+                        group_id,
+                        args: Some(args),
+                    };
+                    (args, sender)
+                })
+                .unzip();
+            let store = &self.inner.stores[to];
+            let me = self.inner.me;
+            let request = store.heartbeats(&HeartbeatBatch { heartbeats });
+            store.spawn(request.then(move |res| {
+                match res {
+                    Ok(batch) => {
+                        for (group_reply, sender) in batch.replies.into_iter().zip(senders) {
+                            // the senders without reply are dropped, and their receivers are cancelled.
+                            if let Some(reply) = group_reply.reply {
+                                let _ = sender.send(reply);
+                            }
+                        }
+                    }
+                    Err(e) => debug!("NO{} failed to send heartbeats to NO{}: {}", me, to, e),
+                }
+                Ok(())
+            }));
+        }
+    }
+}
+
+/// The event loop of a `Host`, which owns the peers of all groups.
+struct EventLoop {
+    host: Host,
+    groups: HashMap<u64, Raft>,
+    tick_interval: Duration,
+}
+
+impl EventLoop {
+    /// handle the messages one by one, until `HostMessage::Stop` arrives.
+    /// The peer that handled an event is ticked after it, like `Raft::run`,
+    /// and all peers are ticked every `tick_interval`.
+    fn run(mut self, messages: Receiver<HostMessage>) {
+        let mut next_tick = Instant::now() + self.tick_interval;
+        let stopped = loop {
+            let now = Instant::now();
+            let wait = if next_tick > now {
+                next_tick - now
+            } else {
+                Duration::default()
+            };
+            match messages.recv_timeout(wait) {
+                Ok(HostMessage::Event(group, event)) => self.step(group, event),
+                Ok(HostMessage::Add(group, raft)) => {
+                    let mut raft = *raft;
+                    raft.become_follower();
+                    raft.publish_state();
+                    if let Some(mut old) = self.groups.insert(group, raft) {
+                        warn!("group {} has been hosted, the old peer is replaced.", group);
+                        old.stop();
+                    }
+                }
+                Ok(HostMessage::Stop(done)) => break Some(done),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break None,
+            }
+            let now = Instant::now();
+            if now >= next_tick {
+                for raft in self.groups.values_mut() {
+                    raft.tick();
+                    raft.publish_state();
+                }
+                self.stop_failed_groups();
+                next_tick += self.tick_interval;
+                if next_tick < now {
+                    // too busy to keep up with the ticks, skip the missed ones.
+                    next_tick = now + self.tick_interval;
+                }
+            }
+            self.host.flush_heartbeats();
+        };
+
+        for (_, mut raft) in self.groups.drain() {
+            raft.stop();
+        }
+        info!("host NO{} is dead.", self.host.me());
+        if let Some(done) = stopped {
+            reply(&done, ());
+        }
+    }
+
+    /// handle an event to the peer of `group`.
+    fn step(&mut self, group: u64, event: Event) {
+        match event {
+            Event::Kill(done) => {
+                if let Some(mut raft) = self.groups.remove(&group) {
+                    raft.stop();
+                }
+                reply(&done, ());
+            }
+            event => match self.groups.get_mut(&group) {
+                Some(raft) => {
+                    raft.step(event);
+                    if !raft.write_failed {
+                        raft.tick();
+                    }
+                    raft.publish_state();
+                    self.stop_failed_groups();
+                }
+                None => debug!("group {} isn't hosted, the event is dropped.", group),
+            },
+        }
+    }
+
+    /// stop the peers whose writes have failed, like `Raft::run` does.
+    fn stop_failed_groups(&mut self) {
+        let failed = self
+            .groups
+            .iter()
+            .filter(|(_, raft)| raft.write_failed)
+            .map(|(group, _)| *group)
+            .collect::<Vec<_>>();
+        for group in failed {
+            if let Some(mut raft) = self.groups.remove(&group) {
+                raft.stop();
+            }
+        }
+    }
+}
+
+/// The transport of a group hosted by a `Host`.
+struct GroupTransport {
+    group: u64,
+    host: Host,
+}
+
+impl Transport for GroupTransport {
+    fn peer_count(&self) -> usize {
+        self.host.inner.stores.len()
+    }
+
+    fn request_vote(&self, to: usize, args: &RequestVoteArgs) -> RpcFuture<RequestVoteReply> {
+        self.host.inner.stores[to].request_vote(&GroupRequestVoteArgs {
+            group_id: self.group,
+            args: Some(args.clone()),
+        })
+    }
+
+    fn append_entries(&self, to: usize, args: &AppendEntriesArgs) -> RpcFuture<AppendEntriesReply> {
+        if args.entries.is_empty() {
+            return self.host.queue_heartbeat(to, self.group, args.clone());
+        }
+        self.host.inner.stores[to].append_entries(&GroupAppendEntriesArgs {
+            group_id: self.group,
+            args: Some(args.clone()),
+        })
+    }
+
+    fn install_snapshot(
+        &self,
+        to: usize,
+        args: &InstallSnapshotArgs,
+    ) -> RpcFuture<InstallSnapshotReply> {
+        self.host.inner.stores[to].install_snapshot(&GroupInstallSnapshotArgs {
+            group_id: self.group,
+            args: Some(args.clone()),
+        })
+    }
+
+    fn spawn(&self, f: Box<dyn Future<Item = (), Error = ()> + Send + 'static>) {
+        self.host.inner.stores[self.host.inner.me].spawn(f)
+    }
+}
+
+impl MultiRaftService for Host {
+    fn request_vote(&self, args: GroupRequestVoteArgs) -> RpcFuture<RequestVoteReply> {
+        let request = args.args.unwrap_or_default();
+        let rx = self.send_message(args.group_id, |sx| Message::RequestVote(request, sx));
+        Box::new(rx.map_err(|_| labrpc::Error::Stopped))
+    }
+
+    fn append_entries(&self, args: GroupAppendEntriesArgs) -> RpcFuture<AppendEntriesReply> {
+        let request = args.args.unwrap_or_default();
+        let rx = self.send_message(args.group_id, |sx| Message::AppendEntries(request, sx));
+        Box::new(rx.map_err(|_| labrpc::Error::Stopped))
+    }
+
+    fn install_snapshot(&self, args: GroupInstallSnapshotArgs) -> RpcFuture<InstallSnapshotReply> {
+        let request = args.args.unwrap_or_default();
+        let rx = self.send_message(args.group_id, |sx| Message::InstallSnapshot(request, sx));
+        Box::new(rx.map_err(|_| labrpc::Error::Stopped))
+    }
+
+    fn heartbeats(&self, batch: HeartbeatBatch) -> RpcFuture<HeartbeatBatchReply> {
+        let replies = batch.heartbeats.into_iter().map(|heartbeat| {
+            let group_id = heartbeat.group_id;
+            let request = heartbeat.args.unwrap_or_default();
+            self.send_message(group_id, |sx| Message::AppendEntries(request, sx))
+                .then(move |res| -> labrpc::Result<_> {
+                    Ok(GroupAppendEntriesReply {
+                        group_id,
+                        reply: res.ok(),
+                    })
+                })
+        });
+        Box::new(
+            future::join_all(replies.collect::<Vec<_>>())
+                .map(|replies| HeartbeatBatchReply { replies }),
+        )
+    }
+}
@@ -0,0 +1,298 @@
+//! A seeded nemesis for tests, which injects random faults into a raft or kvraft cluster.
+//!
+//! A `Schedule` of faults (crashes, restarts, partitions, message loss and clock jumps) is
+//! generated from a seed, and `run` plays it against a `Cluster` while the cluster runs its
+//! workload. After that, all faults are healed, and the cluster checks its results.
+//!
+//! When a schedule fails, `check` shrinks it by removing steps, and reports the seed along with
+//! the smallest failing schedule it found. Only the schedule is determined by the seed, the
+//! workload and the interleaving of threads aren't, so a failure may need a few replays.
+use std::any::Any;
+use std::env;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng, StdRng};
+
+/// The environment variable to replay a seed, e.g. `NEMESIS_SEED=42 cargo test nemesis`.
+pub const SEED_ENV: &str = "NEMESIS_SEED";
+
+/// At most how many runs `check` makes for shrinking a failing schedule.
+const MAX_SHRINK_RUNS: usize = 8;
+
+/// How often `Cluster::work` is called during a run.
+const WORK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A fault that the nemesis injects.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// crash a server, keeping its persisted state.
+    Crash(usize),
+    /// restart a crashed server.
+    Restart(usize),
+    /// split the servers into two parts, only the servers in the same part can talk with each other.
+    Partition(Vec<usize>, Vec<usize>),
+    /// reconnect all servers.
+    Heal,
+    /// drop and delay messages or not.
+    Unreliable(bool),
+    /// the clock of a server jumps forward.
+    ClockJump(usize, Duration),
+}
+
+/// A fault, injected at `at` since the run begins.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub at: Duration,
+    pub fault: Fault,
+}
+
+/// The faults to inject in a run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    /// the seed that generated the schedule.
+    pub seed: u64,
+    /// how long the run lasts, before healing the faults.
+    pub duration: Duration,
+    pub steps: Vec<Step>,
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "schedule of seed {} ({:?}):", self.seed, self.duration)?;
+        for step in &self.steps {
+            writeln!(f, "  {:?}: {:?}", step.at, step.fault)?;
+        }
+        Ok(())
+    }
+}
+
+impl Schedule {
+    /// generate a schedule for a cluster of `servers` from `seed`.
+    /// A majority of servers are always running, but may be partitioned.
+    pub fn generate(seed: u64, servers: usize, duration: Duration) -> Schedule {
+        let mut rng: StdRng = SeedableRng::from_seed(&[seed as usize][..]);
+        let mut crashed = vec![false; servers];
+        let mut steps = vec![];
+        let mut at = Duration::default();
+        loop {
+            at += Duration::from_millis(rng.gen_range(100, 1000));
+            if at >= duration {
+                break;
+            }
+            let fault = match rng.gen_range(0, 6) {
+                0 | 1 => {
+                    let server = rng.gen_range(0, servers);
+                    let down = crashed.iter().filter(|c| **c).count();
+                    if crashed[server] {
+                        crashed[server] = false;
+                        Fault::Restart(server)
+                    } else if down < (servers - 1) / 2 {
+                        crashed[server] = true;
+                        Fault::Crash(server)
+                    } else {
+                        continue;
+                    }
+                }
+                2 => {
+                    let mut p1 = (0..servers).collect::<Vec<_>>();
+                    rng.shuffle(&mut p1);
+                    let p2 = p1.split_off(rng.gen_range(1, servers));
+                    Fault::Partition(p1, p2)
+                }
+                3 => Fault::Heal,
+                4 => Fault::Unreliable(rng.gen()),
+                _ => Fault::ClockJump(
+                    rng.gen_range(0, servers),
+                    Duration::from_millis(rng.gen_range(10, 1000)),
+                ),
+            };
+            steps.push(Step { at, fault });
+        }
+        Schedule {
+            seed,
+            duration,
+            steps,
+        }
+    }
+}
+
+/// A cluster under test, with its workload.
+pub trait Cluster {
+    /// the number of servers.
+    fn servers(&self) -> usize;
+
+    /// crash a running server, keeping its persisted state.
+    fn crash(&mut self, server: usize);
+
+    /// restart a crashed server, it can talk with all running servers then.
+    fn restart(&mut self, server: usize);
+
+    /// split the running servers into two parts,
+    /// only the servers in the same part can talk with each other.
+    fn partition(&mut self, p1: &[usize], p2: &[usize]);
+
+    /// connect all running servers with each other.
+    fn heal(&mut self);
+
+    /// drop and delay messages or not.
+    fn set_unreliable(&mut self, unreliable: bool);
+
+    /// make the clock of a running server jump forward.
+    fn jump_clock(&mut self, server: usize, by: Duration);
+
+    /// drive the workload, this is called every `WORK_INTERVAL` during a run.
+    /// The workload may be driven by its own threads instead.
+    fn work(&mut self) {}
+
+    /// stop the workload and check the results, after all faults are healed.
+    ///
+    /// # returns
+    /// `Err` with the reason if the check fails, panics are treated as failures too.
+    fn finish(&mut self) -> Result<(), String>;
+}
+
+/// the message of a caught panic.
+fn panic_message(e: Box<dyn Any + Send>) -> String {
+    e.downcast_ref::<String>()
+        .cloned()
+        .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| "unknown panic".to_owned())
+}
+
+/// play `schedule` against `cluster`, then heal all faults and check the results by `Cluster::finish`.
+///
+/// The steps that don't make sense (e.g. crashing a crashed server) are skipped,
+/// so any subset of a generated schedule can be played.
+pub fn run<C: Cluster>(cluster: &mut C, schedule: &Schedule) -> Result<(), String> {
+    let start = Instant::now();
+    let work_until = |cluster: &mut C, at: Duration| {
+        while start.elapsed() < at {
+            cluster.work();
+            thread::sleep(Ord::min(WORK_INTERVAL, at - Ord::min(at, start.elapsed())));
+        }
+    };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut crashed = vec![false; cluster.servers()];
+        let mut partition: Option<(Vec<usize>, Vec<usize>)> = None;
+        for step in &schedule.steps {
+            work_until(cluster, step.at);
+            info!("nemesis: {:?}", step.fault);
+            match step.fault {
+                Fault::Crash(server) if !crashed[server] => {
+                    crashed[server] = true;
+                    cluster.crash(server);
+                }
+                Fault::Restart(server) if crashed[server] => {
+                    crashed[server] = false;
+                    cluster.restart(server);
+                    // keep the partition.
+                    if let Some((p1, p2)) = partition.as_ref() {
+                        cluster.partition(p1, p2);
+                    }
+                }
+                Fault::Partition(ref p1, ref p2) => {
+                    cluster.partition(p1, p2);
+                    partition = Some((p1.clone(), p2.clone()));
+                }
+                Fault::Heal => {
+                    cluster.heal();
+                    partition = None;
+                }
+                Fault::Unreliable(unreliable) => cluster.set_unreliable(unreliable),
+                Fault::ClockJump(server, by) if !crashed[server] => cluster.jump_clock(server, by),
+                _ => debug!("nemesis: skip {:?}", step.fault),
+            }
+        }
+        work_until(cluster, schedule.duration);
+
+        // heal all faults, so the cluster can make progress again.
+        cluster.set_unreliable(false);
+        for (server, crashed) in crashed.iter().enumerate() {
+            if *crashed {
+                cluster.restart(server);
+            }
+        }
+        cluster.heal();
+        cluster.finish()
+    }));
+    result.unwrap_or_else(|e| Err(panic_message(e)))
+}
+
+/// remove steps of a failing schedule, as long as it still fails.
+/// `fails` plays a schedule, and returns the reason if it fails.
+///
+/// # returns
+/// the smallest failing schedule found in at most `max_runs` runs, and the reason it fails.
+pub fn shrink(
+    schedule: &Schedule,
+    reason: String,
+    max_runs: usize,
+    mut fails: impl FnMut(&Schedule) -> Option<String>,
+) -> (Schedule, String) {
+    let mut schedule = schedule.clone();
+    let mut reason = reason;
+    let mut runs = 0;
+    let mut chunk = schedule.steps.len() / 2;
+    while chunk > 0 {
+        let mut i = 0;
+        while i < schedule.steps.len() {
+            if runs == max_runs {
+                return (schedule, reason);
+            }
+            runs += 1;
+            let mut candidate = schedule.clone();
+            let end = Ord::min(i + chunk, candidate.steps.len());
+            candidate.steps.drain(i..end);
+            match fails(&candidate) {
+                Some(why) => {
+                    info!("nemesis: still fails without steps [{}, {}).", i, end);
+                    schedule = candidate;
+                    reason = why;
+                }
+                None => i += chunk,
+            }
+        }
+        chunk /= 2;
+    }
+    (schedule, reason)
+}
+
+/// the seed from `SEED_ENV`, or a random one.
+pub fn seed() -> u64 {
+    env::var(SEED_ENV)
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(rand::random)
+}
+
+/// play a random schedule of `duration` against a cluster made by `make_cluster`,
+/// a new cluster is made for each run.
+///
+/// # panics
+/// if the schedule fails, with the seed, the shrunk schedule and the reason.
+pub fn check<C: Cluster>(duration: Duration, make_cluster: impl Fn() -> C) {
+    let seed = seed();
+    info!(
+        "nemesis: seed = {} (replay by {}={}).",
+        seed, SEED_ENV, seed
+    );
+    let mut cluster = make_cluster();
+    let schedule = Schedule::generate(seed, cluster.servers(), duration);
+    let reason = match run(&mut cluster, &schedule) {
+        Ok(()) => return,
+        Err(reason) => reason,
+    };
+    drop(cluster);
+
+    error!("nemesis: {}failed: {}, shrinking.", schedule, reason);
+    let (schedule, reason) = shrink(&schedule, reason, MAX_SHRINK_RUNS, |candidate| {
+        run(&mut make_cluster(), candidate).err()
+    });
+    panic!(
+        "nemesis failed (replay by {}={}): {}\n{}",
+        SEED_ENV, seed, reason, schedule
+    );
+}
@@ -5,13 +5,28 @@
 //! so, while you can modify this code to help you debug, please
 //! test with the original before submitting.
 
+use std::io;
 use std::sync::{Arc, Mutex};
+#[cfg(any(test, feature = "testing"))]
+use std::thread;
+#[cfg(any(test, feature = "testing"))]
+use std::time::Duration;
 
 pub trait Persister: Send + 'static {
     fn raft_state(&self) -> Vec<u8>;
     fn save_raft_state(&self, state: Vec<u8>);
     fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>);
     fn snapshot(&self) -> Vec<u8>;
+
+    /// save the raft state, and the snapshot if it's given, but report a failed write
+    /// instead of panicking. Raft saves its state by this, and stops on a failed write.
+    fn try_save(&self, state: Vec<u8>, snapshot: Option<Vec<u8>>) -> io::Result<()> {
+        match snapshot {
+            Some(snapshot) => self.save_state_and_snapshot(state, snapshot),
+            None => self.save_raft_state(state),
+        }
+        Ok(())
+    }
 }
 
 impl<T: ?Sized + Persister> Persister for Box<T> {
@@ -27,6 +42,9 @@ impl<T: ?Sized + Persister> Persister for Box<T> {
     fn snapshot(&self) -> Vec<u8> {
         (**self).snapshot()
     }
+    fn try_save(&self, state: Vec<u8>, snapshot: Option<Vec<u8>>) -> io::Result<()> {
+        (**self).try_save(state, snapshot)
+    }
 }
 
 impl<T: ?Sized + Sync + Persister> Persister for Arc<T> {
@@ -42,6 +60,9 @@ impl<T: ?Sized + Sync + Persister> Persister for Arc<T> {
     fn snapshot(&self) -> Vec<u8> {
         (**self).snapshot()
     }
+    fn try_save(&self, state: Vec<u8>, snapshot: Option<Vec<u8>>) -> io::Result<()> {
+        (**self).try_save(state, snapshot)
+    }
 }
 
 #[derive(Default)]
@@ -79,8 +100,131 @@ impl Persister for SimplePersister {
     }
 }
 
+#[cfg(any(test, feature = "testing"))]
+#[derive(Default)]
+struct FaultyState {
+    raft_state: Vec<u8>,
+    snapshot: Vec<u8>,
+    /// the writes in flight and after crashing are dropped.
+    crashed: bool,
+    write_delay: Duration,
+    /// how many of the next writes fail.
+    failing_writes: usize,
+}
+
+/// A persister for testing crash consistency, which injects faults into writes.
+///
+/// A write takes `write_delay`, and isn't durable until `save_*` returns:
+/// if the persister `crash`es during a write, the write is lost, or torn in `save_state_and_snapshot`.
+/// A torn write keeps the new snapshot and the old raft state, because the snapshot is written first,
+/// so the raft state never follows a snapshot that isn't saved.
+///
+/// The writes after crashing are dropped silently, they never reach the persister returned by
+/// `crash`, which the peer restarts on. A write can also fail by `fail_writes`, without saving
+/// anything, like a store returning an I/O error: `try_save` reports it, and the `save_*` panic.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Default)]
+pub struct FaultyPersister {
+    state: Mutex<FaultyState>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl FaultyPersister {
+    pub fn new() -> FaultyPersister {
+        FaultyPersister::default()
+    }
+
+    /// make each write take `delay`, during which a crash loses or tears it.
+    pub fn set_write_delay(&self, delay: Duration) {
+        self.state.lock().unwrap().write_delay = delay;
+    }
+
+    /// fail the next `count` writes.
+    pub fn fail_writes(&self, count: usize) {
+        self.state.lock().unwrap().failing_writes = count;
+    }
+
+    /// crash this persister, the writes in flight and after it are dropped.
+    ///
+    /// # returns
+    /// a fresh persister holding what has been saved, with the same write delay.
+    pub fn crash(&self) -> FaultyPersister {
+        let mut state = self.state.lock().unwrap();
+        state.crashed = true;
+        FaultyPersister {
+            state: Mutex::new(FaultyState {
+                raft_state: state.raft_state.clone(),
+                snapshot: state.snapshot.clone(),
+                write_delay: state.write_delay,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// start a write.
+    ///
+    /// # returns
+    /// the write delay, or an error if the write should fail.
+    fn begin_write(&self) -> io::Result<Duration> {
+        let mut state = self.state.lock().unwrap();
+        if state.failing_writes > 0 {
+            state.failing_writes -= 1;
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "injected write failure",
+            ));
+        }
+        Ok(state.write_delay)
+    }
+
+    /// apply a part of a write, unless the persister has crashed.
+    fn write(&self, f: impl FnOnce(&mut FaultyState)) {
+        let mut state = self.state.lock().unwrap();
+        if !state.crashed {
+            f(&mut state);
+        }
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Persister for FaultyPersister {
+    fn raft_state(&self) -> Vec<u8> {
+        self.state.lock().unwrap().raft_state.clone()
+    }
+
+    fn save_raft_state(&self, raft_state: Vec<u8>) {
+        self.try_save(raft_state, None)
+            .unwrap_or_else(|e| panic!("failed to save the raft state: {}", e))
+    }
+
+    fn save_state_and_snapshot(&self, raft_state: Vec<u8>, snapshot: Vec<u8>) {
+        self.try_save(raft_state, Some(snapshot))
+            .unwrap_or_else(|e| panic!("failed to save the raft state: {}", e))
+    }
+
+    fn try_save(&self, raft_state: Vec<u8>, snapshot: Option<Vec<u8>>) -> io::Result<()> {
+        let delay = self.begin_write()?;
+        match snapshot {
+            Some(snapshot) => {
+                thread::sleep(delay / 2);
+                self.write(|state| state.snapshot = snapshot);
+                thread::sleep(delay / 2);
+            }
+            None => thread::sleep(delay),
+        }
+        self.write(|state| state.raft_state = raft_state);
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.state.lock().unwrap().snapshot.clone()
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
     use super::*;
 
     #[test]
@@ -106,4 +250,50 @@ mod tests {
         let obj: Arc<dyn Persister + Sync> = Arc::new(sp);
         let _box_obj: Box<dyn Persister> = Box::new(obj);
     }
+
+    #[test]
+    fn test_faulty_persister() {
+        let delay = Duration::from_millis(200);
+        let persister = Arc::new(FaultyPersister::new());
+        persister.save_state_and_snapshot(vec![1], vec![1]);
+        persister.set_write_delay(delay);
+        // crash at `at` since a write of `(2, 2)` starts.
+        let crash_during_write = |persister: &Arc<FaultyPersister>, at: Duration| {
+            let writer = {
+                let persister = persister.clone();
+                thread::spawn(move || persister.try_save(vec![2], Some(vec![2])))
+            };
+            thread::sleep(at);
+            let crashed = Arc::new(persister.crash());
+            // the write in flight is dropped silently.
+            assert!(writer.join().unwrap().is_ok());
+            crashed
+        };
+
+        // a write not reaching the store is lost.
+        let crashed = crash_during_write(&persister, delay / 4);
+        assert_eq!(
+            (crashed.raft_state(), crashed.snapshot()),
+            (vec![1], vec![1])
+        );
+        // the crashed persister doesn't save the rest of it.
+        assert_eq!(persister.snapshot(), vec![1]);
+        // a torn write keeps the new snapshot only.
+        let crashed = crash_during_write(&crashed, delay * 3 / 4);
+        assert_eq!(
+            (crashed.raft_state(), crashed.snapshot()),
+            (vec![1], vec![2])
+        );
+
+        // a failed write is reported, and saves nothing.
+        let persister = crashed.crash();
+        persister.set_write_delay(Duration::default());
+        persister.fail_writes(2);
+        assert!(persister.try_save(vec![4], None).is_err());
+        let failed = panic::catch_unwind(AssertUnwindSafe(|| persister.save_raft_state(vec![4])));
+        assert!(failed.is_err());
+        assert_eq!(persister.raft_state(), vec![1]);
+        persister.save_raft_state(vec![5]);
+        assert_eq!(persister.raft_state(), vec![5]);
+    }
 }
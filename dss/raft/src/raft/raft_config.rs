@@ -0,0 +1,271 @@
+//! The configuration of a raft peer.
+use std::time::Duration;
+
+use crate::raft::errors::{Error, Result};
+
+/// The configuration of a raft peer, built and validated by `RaftConfigBuilder`.
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    /// The election timeout is chosen randomly from `[min_election_timeout, max_election_timeout)`.
+    pub(crate) min_election_timeout: Duration,
+    pub(crate) max_election_timeout: Duration,
+    /// How often the leader sends heartbeats during idle periods.
+    pub(crate) heartbeat_interval: Duration,
+    /// How long the leader waits for more proposals before flushing a batch.
+    pub(crate) proposal_batch_window: Duration,
+    /// The leader flushes a batch at once when its proposals reach this byte size.
+    pub(crate) proposal_batch_bytes: usize,
+    /// How many `AppendEntries` can be in flight to a follower at the same time.
+    pub(crate) max_inflight_append_entries: usize,
+    /// The max count of entries carried by one `AppendEntries`.
+    pub(crate) max_append_entries: usize,
+    /// The max byte size of entries carried by one `AppendEntries`,
+    /// one entry is always sent even if it's larger.
+    pub(crate) max_append_bytes: usize,
+    /// The max byte size of the snapshot chunk carried by one `InstallSnapshot`.
+    pub(crate) snapshot_chunk_bytes: usize,
+    /// The service should take a snapshot once the persisted raft state grows over this byte size,
+    /// see `Node::needs_snapshot`. `None` for never.
+    pub(crate) snapshot_threshold: Option<usize>,
+    /// When `Some`, the leader serves reads locally while it has heard from a majority
+    /// within this duration.
+    pub(crate) read_lease: Option<Duration>,
+    /// When `true`, the leader steps down once it hasn't heard from a majority
+    /// within `max_election_timeout`.
+    pub(crate) check_quorum: bool,
+    /// When `true` (the default), a new leader appends a no-op entry at the start of its term,
+    /// so it can commit the entries of earlier terms without waiting for a proposal.
+    pub(crate) leader_no_op: bool,
+    /// The election priority of each peer, by its index. Empty for the same priority of all peers.
+    pub(crate) priorities: Vec<u64>,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            min_election_timeout: Duration::from_millis(150),
+            max_election_timeout: Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(40),
+            proposal_batch_window: Duration::from_millis(2),
+            proposal_batch_bytes: 64 * 1024,
+            max_inflight_append_entries: 4,
+            max_append_entries: 1024,
+            max_append_bytes: 1024 * 1024,
+            snapshot_chunk_bytes: 16 * 1024,
+            snapshot_threshold: None,
+            read_lease: None,
+            check_quorum: true,
+            leader_no_op: true,
+            priorities: vec![],
+        }
+    }
+}
+
+impl RaftConfig {
+    /// start building a config from the default one.
+    pub fn builder() -> RaftConfigBuilder {
+        RaftConfigBuilder {
+            config: RaftConfig::default(),
+        }
+    }
+
+    /// the range of the randomized election timeout, `[min, max)`.
+    pub fn election_timeout(&self) -> (Duration, Duration) {
+        (self.min_election_timeout, self.max_election_timeout)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.heartbeat_interval
+    }
+
+    /// the window and the byte budget of a batch of proposals.
+    pub fn proposal_batch(&self) -> (Duration, usize) {
+        (self.proposal_batch_window, self.proposal_batch_bytes)
+    }
+
+    pub fn max_inflight_append_entries(&self) -> usize {
+        self.max_inflight_append_entries
+    }
+
+    /// the max count and byte size of entries carried by one `AppendEntries`.
+    pub fn max_append(&self) -> (usize, usize) {
+        (self.max_append_entries, self.max_append_bytes)
+    }
+
+    pub fn snapshot_chunk_bytes(&self) -> usize {
+        self.snapshot_chunk_bytes
+    }
+
+    pub fn snapshot_threshold(&self) -> Option<usize> {
+        self.snapshot_threshold
+    }
+
+    pub fn read_lease(&self) -> Option<Duration> {
+        self.read_lease
+    }
+
+    pub fn check_quorum(&self) -> bool {
+        self.check_quorum
+    }
+
+    pub fn leader_no_op(&self) -> bool {
+        self.leader_no_op
+    }
+
+    pub fn priorities(&self) -> &[u64] {
+        &self.priorities
+    }
+
+    /// validate the config against the count of peers, which `RaftConfigBuilder::build`
+    /// doesn't know. The constructors of `Raft` check it.
+    ///
+    /// # returns
+    /// `Error::InvalidConfig` if the priorities are given, but not one for each peer.
+    pub fn check_peers(&self, peers: usize) -> Result<()> {
+        if !self.priorities.is_empty() && self.priorities.len() != peers {
+            return Err(Error::InvalidConfig(format!(
+                "{} priorities are given to {} peers.",
+                self.priorities.len(),
+                peers
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The builder of `RaftConfig`.
+pub struct RaftConfigBuilder {
+    config: RaftConfig,
+}
+
+impl RaftConfigBuilder {
+    /// the range of the randomized election timeout, `[min, max)`.
+    pub fn election_timeout(mut self, min: Duration, max: Duration) -> Self {
+        self.config.min_election_timeout = min;
+        self.config.max_election_timeout = max;
+        self
+    }
+
+    /// how often the leader sends heartbeats during idle periods.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.config.heartbeat_interval = interval;
+        self
+    }
+
+    /// how the leader batches proposals: it waits at most `window` for more proposals,
+    /// unless they have reached `bytes`.
+    pub fn proposal_batch(mut self, window: Duration, bytes: usize) -> Self {
+        self.config.proposal_batch_window = window;
+        self.config.proposal_batch_bytes = bytes;
+        self
+    }
+
+    /// how many `AppendEntries` can be in flight to a follower at the same time.
+    pub fn max_inflight_append_entries(mut self, count: usize) -> Self {
+        self.config.max_inflight_append_entries = count;
+        self
+    }
+
+    /// the max count and byte size of entries carried by one `AppendEntries`.
+    pub fn max_append(mut self, entries: usize, bytes: usize) -> Self {
+        self.config.max_append_entries = entries;
+        self.config.max_append_bytes = bytes;
+        self
+    }
+
+    /// the max byte size of the snapshot chunk carried by one `InstallSnapshot`.
+    pub fn snapshot_chunk_bytes(mut self, bytes: usize) -> Self {
+        self.config.snapshot_chunk_bytes = bytes;
+        self
+    }
+
+    /// take snapshots once the persisted raft state grows over `bytes`, `None` for never.
+    pub fn snapshot_threshold(mut self, bytes: Option<usize>) -> Self {
+        self.config.snapshot_threshold = bytes;
+        self
+    }
+
+    /// enable the lease read mode: the leader serves reads without any network round trip
+    /// while it has heard from a majority within `lease`.
+    ///
+    /// It is safe only when all peers of the cluster enable it,
+    /// because followers in this mode refuse to vote while the leader is alive.
+    pub fn read_lease(mut self, lease: Duration) -> Self {
+        self.config.read_lease = Some(lease);
+        self
+    }
+
+    /// whether the leader steps down once it hasn't heard from a majority in an election timeout.
+    pub fn check_quorum(mut self, check_quorum: bool) -> Self {
+        self.config.check_quorum = check_quorum;
+        self
+    }
+
+    /// whether a new leader appends a no-op entry at the start of its term, `true` by default.
+    ///
+    /// Without it, a new leader cannot commit the entries of earlier terms, nor serve reads,
+    /// until a client proposes something. The service sees the entry as `ApplyMsg::NoOp`.
+    pub fn leader_no_op(mut self, leader_no_op: bool) -> Self {
+        self.config.leader_no_op = leader_no_op;
+        self
+    }
+
+    /// the election priority of each peer, by its index, one for each peer (see
+    /// `RaftConfig::check_peers`), and all peers must be given the same ones.
+    ///
+    /// A peer delays its campaigns by the count of higher priorities among the peers it knows to be
+    /// reachable and up-to-date, and an up-to-date peer declines to vote for the candidates of lower
+    /// priority, so it's elected instead. A peer given other priorities than the candidate's logs
+    /// an error, and votes without them.
+    /// The leader hands off to the peer of the highest priority above its own,
+    /// once that peer has caught up.
+    pub fn priorities(mut self, priorities: Vec<u64>) -> Self {
+        self.config.priorities = priorities;
+        self
+    }
+
+    /// validate and build the config.
+    ///
+    /// # returns
+    /// `Error::InvalidConfig` if:
+    /// - the election timeout range is empty.
+    /// - the heartbeat interval isn't well below the election timeout (at most a third of it),
+    ///   or a follower may start an election because of a delayed heartbeat.
+    /// - the read lease isn't less than the election timeout.
+    /// - any of the limits is zero.
+    ///
+    /// The count of `priorities` is checked against the peers by `RaftConfig::check_peers`.
+    pub fn build(self) -> Result<RaftConfig> {
+        let config = self.config;
+        let invalid = |reason: String| Err(Error::InvalidConfig(reason));
+        if config.min_election_timeout >= config.max_election_timeout {
+            return invalid(format!(
+                "the election timeout range [{:?}, {:?}) is empty.",
+                config.min_election_timeout, config.max_election_timeout
+            ));
+        }
+        if config.heartbeat_interval * 3 > config.min_election_timeout {
+            return invalid(format!(
+                "the heartbeat interval ({:?}) must be at most a third of the election timeout ({:?}).",
+                config.heartbeat_interval, config.min_election_timeout
+            ));
+        }
+        if let Some(lease) = config.read_lease {
+            if lease >= config.min_election_timeout {
+                return invalid(format!(
+                    "the read lease ({:?}) must be less than the election timeout ({:?}).",
+                    lease, config.min_election_timeout
+                ));
+            }
+        }
+        if config.max_inflight_append_entries == 0
+            || config.max_append_entries == 0
+            || config.max_append_bytes == 0
+            || config.snapshot_chunk_bytes == 0
+            || config.snapshot_threshold == Some(0)
+        {
+            return invalid(format!("the limits must be positive: {:?}.", config));
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,494 @@
+//! A raft log that stores entries in segment files on disk.
+//!
+//! Each segment is a file named by its sequence number, it begins with the index of its first entry,
+//! and holds records of `[term: u64][length: u32][checksum: u64][no-op: u8][data]`
+//! (all numbers are little endian), the checksum is `entry_checksum` of the term, no-op flag and data.
+//! Only the position and term of each entry is kept in memory (the index of segments),
+//! entries are read from the files when needed.
+//!
+//! A new segment starts once the last one grows over `segment_bytes`. `truncate` rewrites the tail
+//! at once, but the segments dropped by `compact` and `reset` are deleted at the next `sync`,
+//! after raft has saved the snapshot that includes them. `sync_job` flushes the segments written
+//! since the last sync, and deletes the dropped ones, by duplicated handles in the background.
+//! When opening, a torn record at the end is dropped, and so are the segments after a gap.
+//! A complete record that mismatches its checksum is damaged: opening, or reading it fails.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::raft::log::RaftLog;
+use crate::raft::{entry_checksum, LogEntry};
+
+/// the byte size of `[term][length][checksum][no-op]` of a record.
+const RECORD_HEADER: u64 = 21;
+/// the byte size of the first index at the beginning of a segment.
+const SEGMENT_HEADER: u64 = 8;
+const SEGMENT_EXTENSION: &str = "seg";
+
+/// The position of an entry in its segment.
+#[derive(Clone, Copy)]
+struct EntryPos {
+    offset: u64,
+    term: u64,
+}
+
+struct Segment {
+    path: PathBuf,
+    first_index: u64,
+    entries: Vec<EntryPos>,
+    /// the byte size of the file.
+    size: u64,
+}
+
+impl Segment {
+    /// the index after the last entry of this segment.
+    fn end_index(&self) -> u64 {
+        self.first_index + self.entries.len() as u64
+    }
+
+    /// the byte size of data of the `i`th entry.
+    fn data_len(&self, i: usize) -> u64 {
+        let end = self
+            .entries
+            .get(i + 1)
+            .map_or(self.size, |next| next.offset);
+        end - self.entries[i].offset - RECORD_HEADER
+    }
+
+    fn create(path: PathBuf, first_index: u64) -> io::Result<Self> {
+        let mut file = File::create(&path)?;
+        file.write_all(&first_index.to_le_bytes())?;
+        Ok(Segment {
+            path,
+            first_index,
+            entries: vec![],
+            size: SEGMENT_HEADER,
+        })
+    }
+
+    /// load the index of a segment, dropping the torn record at the end.
+    ///
+    /// # returns
+    /// `None` if the segment is torn before its header is written,
+    /// or `io::ErrorKind::InvalidData` if a record is damaged.
+    fn load(path: PathBuf) -> io::Result<Option<Self>> {
+        let mut data = vec![];
+        File::open(&path)?.read_to_end(&mut data)?;
+        if (data.len() as u64) < SEGMENT_HEADER {
+            return Ok(None);
+        }
+        let first_index = read_u64(&data[..8]);
+        let mut entries = vec![];
+        let mut offset = SEGMENT_HEADER;
+        while offset + RECORD_HEADER <= data.len() as u64 {
+            let header = &data[offset as usize..(offset + RECORD_HEADER) as usize];
+            let term = read_u64(&header[..8]);
+            let len = u64::from(read_u32(&header[8..12]));
+            let checksum = read_u64(&header[12..20]);
+            let no_op = header[20] != 0;
+            if offset + RECORD_HEADER + len > data.len() as u64 {
+                break;
+            }
+            let start = (offset + RECORD_HEADER) as usize;
+            if entry_checksum(term, no_op, &data[start..start + len as usize]) != checksum {
+                return Err(damaged(&path, first_index + entries.len() as u64));
+            }
+            entries.push(EntryPos { offset, term });
+            offset += RECORD_HEADER + len;
+        }
+        if offset != data.len() as u64 {
+            warn!(
+                "segment {:?} ends with a torn record, dropping {} bytes.",
+                path,
+                data.len() as u64 - offset
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(offset)?;
+        }
+        Ok(Some(Segment {
+            path,
+            first_index,
+            entries,
+            size: offset,
+        }))
+    }
+}
+
+/// the error of a record that mismatches its checksum.
+fn damaged(path: &Path, index: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "the entry at {} in segment {:?} mismatches its checksum",
+            index, path
+        ),
+    )
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(bytes);
+    u32::from_le_bytes(buf)
+}
+
+/// A raft log that stores entries in segment files under a directory.
+///
+/// # panics
+/// The `RaftLog` methods panic on IO errors, raft cannot go on without its log.
+pub struct SegmentedLog {
+    dir: PathBuf,
+    /// a new segment starts once the last one grows over this size.
+    segment_bytes: u64,
+    segments: Vec<Segment>,
+    /// the sequence number of the next segment.
+    next_seq: u64,
+    /// the last segment, opened for appending.
+    writer: Option<File>,
+    /// the segments that are full, and not synced yet.
+    sealed: Vec<File>,
+    /// the segments to delete at the next `sync`.
+    obsolete: Vec<PathBuf>,
+    last_included_index: u64,
+    last_included_term: u64,
+}
+
+impl SegmentedLog {
+    /// open the log in `dir`, create it if it doesn't exist.
+    ///
+    /// The term of the snapshot isn't stored by the log, the caller should `compact` or `reset`
+    /// the log by its snapshot after opening.
+    pub fn open(dir: impl AsRef<Path>, segment_bytes: usize) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut files = vec![];
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            if path
+                .extension()
+                .map_or(true, |ext| ext != SEGMENT_EXTENSION)
+            {
+                continue;
+            }
+            let seq = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok());
+            if let Some(seq) = seq {
+                files.push((seq, path));
+            }
+        }
+        files.sort();
+
+        let next_seq = files.last().map_or(0, |(seq, _)| seq + 1);
+        let mut segments: Vec<Segment> = vec![];
+        let mut files = files.into_iter();
+        while let Some((_, path)) = files.next() {
+            let segment = match Segment::load(path.clone())? {
+                Some(segment) => segment,
+                None => {
+                    fs::remove_file(path)?;
+                    continue;
+                }
+            };
+            let continuous = segments
+                .last()
+                .map_or(true, |last| last.end_index() == segment.first_index);
+            if !continuous {
+                // the segments after a gap are stale, they cannot follow the log.
+                warn!(
+                    "segment {:?} isn't continuous, dropping it and the following segments.",
+                    segment.path
+                );
+                fs::remove_file(&segment.path)?;
+                for (_, path) in files.by_ref() {
+                    fs::remove_file(path)?;
+                }
+                break;
+            }
+            segments.push(segment);
+        }
+
+        let last_included_index = segments.first().map_or(0, |s| s.first_index - 1);
+        Ok(SegmentedLog {
+            dir,
+            segment_bytes: segment_bytes as u64,
+            segments,
+            next_seq,
+            writer: None,
+            sealed: vec![],
+            obsolete: vec![],
+            last_included_index,
+            last_included_term: 0,
+        })
+    }
+
+    /// find the segment that contains `index`.
+    fn segment_of(&self, index: u64) -> &Segment {
+        let i = match self
+            .segments
+            .binary_search_by_key(&index, |s| s.first_index)
+        {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        &self.segments[i]
+    }
+
+    /// get the last segment for appending, starts a new one if it is full.
+    fn writable_segment(&mut self) -> io::Result<(&mut Segment, &mut File)> {
+        let full = self
+            .segments
+            .last()
+            .map_or(true, |s| s.size >= self.segment_bytes);
+        if full {
+            if let Some(writer) = self.writer.take() {
+                self.sealed.push(writer);
+            }
+            let path = self
+                .dir
+                .join(format!("{:016}.{}", self.next_seq, SEGMENT_EXTENSION));
+            self.next_seq += 1;
+            let first_index = self.last_index() + 1;
+            self.segments.push(Segment::create(path, first_index)?);
+        }
+        let segment = self.segments.last_mut().unwrap();
+        if self.writer.is_none() {
+            self.writer = Some(OpenOptions::new().append(true).open(&segment.path)?);
+        }
+        Ok((segment, self.writer.as_mut().unwrap()))
+    }
+
+    fn read_entries(
+        &self,
+        start: u64,
+        max_entries: usize,
+        max_bytes: usize,
+    ) -> io::Result<Vec<LogEntry>> {
+        let mut entries = vec![];
+        let mut bytes = 0;
+        let mut index = start;
+        while index <= self.last_index() && entries.len() < max_entries {
+            let segment = self.segment_of(index);
+            let mut file = BufReader::new(File::open(&segment.path)?);
+            let mut i = (index - segment.first_index) as usize;
+            file.seek(SeekFrom::Start(segment.entries[i].offset))?;
+            let mut header = [0; RECORD_HEADER as usize];
+            while i < segment.entries.len() && entries.len() < max_entries {
+                let len = segment.data_len(i);
+                bytes += len as usize;
+                if !entries.is_empty() && bytes > max_bytes {
+                    return Ok(entries);
+                }
+                // the records are continuous, so we only need the checksum and flag in the header.
+                file.read_exact(&mut header)?;
+                let mut data = vec![0; len as usize];
+                file.read_exact(&mut data)?;
+                let term = segment.entries[i].term;
+                let no_op = header[20] != 0;
+                if entry_checksum(term, no_op, &data) != read_u64(&header[12..20]) {
+                    return Err(damaged(&segment.path, index));
+                }
+                entries.push(LogEntry { data, term, no_op });
+                i += 1;
+                index += 1;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn append_entries(&mut self, entries: Vec<LogEntry>) -> io::Result<()> {
+        for entry in entries {
+            let mut record = Vec::with_capacity(RECORD_HEADER as usize + entry.data.len());
+            record.extend_from_slice(&entry.term.to_le_bytes());
+            record.extend_from_slice(&(entry.data.len() as u32).to_le_bytes());
+            record.extend_from_slice(
+                &entry_checksum(entry.term, entry.no_op, &entry.data).to_le_bytes(),
+            );
+            record.push(entry.no_op as u8);
+            record.extend_from_slice(&entry.data);
+            let (segment, writer) = self.writable_segment()?;
+            writer.write_all(&record)?;
+            segment.entries.push(EntryPos {
+                offset: segment.size,
+                term: entry.term,
+            });
+            segment.size += record.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn truncate_entries(&mut self, index: u64) -> io::Result<()> {
+        if let Some(writer) = self.writer.take() {
+            self.sealed.push(writer);
+        }
+        // delete the newest segment first, so the remained segments are always continuous.
+        while self
+            .segments
+            .last()
+            .map_or(false, |s| s.first_index >= index)
+        {
+            let segment = self.segments.pop().unwrap();
+            fs::remove_file(&segment.path)?;
+        }
+        if let Some(segment) = self.segments.last_mut() {
+            if index < segment.end_index() {
+                let keep = (index - segment.first_index) as usize;
+                let size = segment.entries[keep].offset;
+                OpenOptions::new()
+                    .write(true)
+                    .open(&segment.path)?
+                    .set_len(size)?;
+                segment.entries.truncate(keep);
+                segment.size = size;
+            }
+        }
+        Ok(())
+    }
+
+    /// take what the next sync has to do: the files to flush, and the segments to delete.
+    fn take_unsynced(&mut self) -> io::Result<(Vec<File>, Vec<PathBuf>)> {
+        let mut files = std::mem::replace(&mut self.sealed, vec![]);
+        if let Some(writer) = self.writer.as_ref() {
+            files.push(writer.try_clone()?);
+        }
+        Ok((files, std::mem::replace(&mut self.obsolete, vec![])))
+    }
+}
+
+/// flush `files`, then delete the `obsolete` segments, whose entries are in the saved snapshot.
+fn sync_files(files: Vec<File>, obsolete: Vec<PathBuf>) -> io::Result<()> {
+    for file in files {
+        file.sync_data()?;
+    }
+    for path in obsolete {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+impl RaftLog for SegmentedLog {
+    fn last_included_index(&self) -> u64 {
+        self.last_included_index
+    }
+
+    fn last_included_term(&self) -> u64 {
+        self.last_included_term
+    }
+
+    fn last_index(&self) -> u64 {
+        self.segments.last().map_or(self.last_included_index, |s| {
+            Ord::max(s.end_index() - 1, self.last_included_index)
+        })
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        if self.is_in_snapshot(index) {
+            return self.last_included_term;
+        }
+        if index > self.last_index() {
+            return 0;
+        }
+        let segment = self.segment_of(index);
+        segment.entries[(index - segment.first_index) as usize].term
+    }
+
+    fn entry(&self, index: u64) -> Option<LogEntry> {
+        if self.is_in_snapshot(index) || index > self.last_index() {
+            return None;
+        }
+        self.entries(index, 1, usize::max_value()).pop()
+    }
+
+    fn entries(&self, start: u64, max_entries: usize, max_bytes: usize) -> Vec<LogEntry> {
+        assert!(
+            !self.is_in_snapshot(start),
+            "Trying to access a entry that is in the snapshot: snapshot last index = {}, accessing = {}",
+            self.last_included_index,
+            start
+        );
+        self.read_entries(start, max_entries, max_bytes)
+            .unwrap_or_else(|e| panic!("failed to read log entries from {}: {}", start, e))
+    }
+
+    fn append(&mut self, entries: Vec<LogEntry>) {
+        self.append_entries(entries)
+            .unwrap_or_else(|e| panic!("failed to append log entries: {}", e))
+    }
+
+    fn truncate(&mut self, index: u64) {
+        assert!(
+            !self.is_in_snapshot(index),
+            "Trying to truncate the snapshot: snapshot last index = {}, truncating = {}",
+            self.last_included_index,
+            index
+        );
+        self.truncate_entries(index)
+            .unwrap_or_else(|e| panic!("failed to truncate log entries from {}: {}", index, e))
+    }
+
+    fn compact(&mut self, index: u64, term: u64) {
+        if index <= self.last_included_index {
+            return;
+        }
+        self.last_included_index = index;
+        self.last_included_term = term;
+        // the segments that all entries are compacted.
+        while self
+            .segments
+            .first()
+            .map_or(false, |s| s.end_index() <= index + 1)
+        {
+            let segment = self.segments.remove(0);
+            if self.segments.is_empty() {
+                self.writer = None;
+            }
+            self.obsolete.push(segment.path);
+        }
+    }
+
+    fn restore_snapshot(&mut self, index: u64, term: u64) {
+        if index == self.last_included_index {
+            self.last_included_term = term;
+        }
+        self.compact(index, term);
+    }
+
+    fn reset(&mut self, index: u64, term: u64) {
+        self.writer = None;
+        self.obsolete
+            .extend(self.segments.drain(..).map(|segment| segment.path));
+        self.last_included_index = index;
+        self.last_included_term = term;
+    }
+
+    fn is_durable(&self) -> bool {
+        true
+    }
+
+    fn stored_bytes(&self) -> usize {
+        self.segments.iter().map(|s| s.size as usize).sum()
+    }
+
+    fn sync(&mut self) {
+        self.take_unsynced()
+            .and_then(|(files, obsolete)| sync_files(files, obsolete))
+            .unwrap_or_else(|e| panic!("failed to sync the log: {}", e))
+    }
+
+    fn sync_job(&mut self) -> Option<Box<dyn FnOnce() + Send>> {
+        let (files, obsolete) = self
+            .take_unsynced()
+            .unwrap_or_else(|e| panic!("failed to sync the log: {}", e));
+        Some(Box::new(move || {
+            sync_files(files, obsolete).unwrap_or_else(|e| panic!("failed to sync the log: {}", e))
+        }))
+    }
+}
@@ -0,0 +1,504 @@
+//! A generic replicated state machine on top of raft.
+//!
+//! A service implements `StateMachine`, and `Driver` does the plumbing:
+//! applying committed entries in order, skipping retried commands of the same client session,
+//! taking and restoring snapshots, and resolving the futures of proposals and reads.
+//!
+//! A snapshot is written by chunks, the first of them is the `StateMachineSnapshot` header,
+//! and the others are written by `StateMachine::snapshot_chunk`. The driver takes snapshots
+//! in the background, and locks the state machine only while writing a chunk, so commands are
+//! applied during a snapshot. The chunks are encoded as they're written (`SnapshotEncoder`),
+//! so besides the snapshot itself, a snapshot takes the memory of a chunk and whatever the state
+//! machine keeps for the entries modified during the snapshot, which is bounded by
+//! `MAX_SNAPSHOT_BACKLOG`.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use futures::future::Either;
+use futures::sync::mpsc::{channel, Sender, UnboundedReceiver};
+use futures::sync::oneshot;
+use futures::{future, Future, Sink, Stream};
+
+use labcodec::{decode, encode};
+
+use crate::proto::raftpb::StateMachineSnapshot;
+use crate::raft::errors::{Error, Result};
+use crate::raft::{ApplyMsg, Node, SnapshotEncoder};
+
+/// The future of a proposal or a read.
+pub type StateMachineFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send + 'static>;
+
+/// Where the chunks of a snapshot are written to.
+pub trait SnapshotWriter {
+    fn write(&mut self, chunk: Vec<u8>);
+}
+
+impl SnapshotWriter for Vec<Vec<u8>> {
+    fn write(&mut self, chunk: Vec<u8>) {
+        self.push(chunk)
+    }
+}
+
+/// The chunks of a snapshot, in the order they were written.
+pub type SnapshotReader<'a> = dyn Iterator<Item = Vec<u8>> + 'a;
+
+/// At most how many bytes a state machine keeps for the snapshot being taken,
+/// see `StateMachine::snapshot_backlog`.
+pub const MAX_SNAPSHOT_BACKLOG: usize = 1 << 20;
+
+/// A deterministic state machine, replicated by raft.
+pub trait StateMachine: Send + 'static {
+    /// The command that is committed to the raft log.
+    type Command: labcodec::Message + Clone + PartialEq;
+    /// The result of applying a command.
+    type Output: Send + 'static;
+
+    /// transform the state by a committed command.
+    fn apply(&mut self, command: Self::Command) -> Self::Output;
+
+    /// the client session of the command, as `(client, command id)`.
+    ///
+    /// A command with the same id as the last applied command of its client is a retry,
+    /// so it won't be applied again.
+    /// `None` for commands that are safe to apply more than once, e.g. reads.
+    fn session(_command: &Self::Command) -> Option<(String, Vec<u8>)> {
+        None
+    }
+
+    /// begin a snapshot of the current state, whose chunks are written by `snapshot_chunk` later.
+    /// A snapshot that has begun is abandoned.
+    fn begin_snapshot(&mut self);
+
+    /// write the next chunk of the snapshot.
+    ///
+    /// Commands may have been applied since `begin_snapshot`, but the snapshot must be
+    /// the state when it began, e.g. by keeping the old values of the modified entries.
+    /// A chunk should be small, for the state machine is locked while writing it.
+    ///
+    /// # returns
+    /// `false` if all chunks have been written, or no snapshot has begun.
+    fn snapshot_chunk(&mut self, writer: &mut dyn SnapshotWriter) -> bool;
+
+    /// how many bytes the state machine keeps for the snapshot that has begun, e.g. the old values
+    /// of the entries modified since `begin_snapshot`. While it's over `MAX_SNAPSHOT_BACKLOG`,
+    /// the driver writes a chunk along with each command it applies.
+    fn snapshot_backlog(&self) -> usize {
+        0
+    }
+
+    /// replace the current state by the chunks written by `snapshot_chunk`,
+    /// this abandons the snapshot that has begun.
+    fn restore(&mut self, reader: &mut SnapshotReader<'_>);
+}
+
+/// A proposal waiting for its index to be applied.
+struct Proposal<S: StateMachine> {
+    /// the term of the leader that accepted it, the entry at its index is it only in this term.
+    term: u64,
+    sender: oneshot::Sender<Result<Option<S::Output>>>,
+}
+
+struct Core<S: StateMachine> {
+    machine: S,
+    /// the id of the last applied command of each client.
+    sessions: HashMap<String, Vec<u8>>,
+    last_applied: u64,
+    /// the term of the entry at `last_applied`.
+    last_applied_term: u64,
+    /// proposals, by the index raft gave them.
+    proposals: BTreeMap<u64, Proposal<S>>,
+    /// the proposals that raft is accepting, by the `last_applied` when they began (how many of them).
+    /// Their entries come after that, and may be applied before the proposals are registered.
+    proposing: BTreeMap<u64, usize>,
+    /// the results of the entries applied after the oldest of `proposing` began, and not proposed
+    /// by a registered proposal, by index, with the terms of the entries.
+    unclaimed: BTreeMap<u64, (u64, Result<Option<S::Output>>)>,
+    /// reads, by the index they are waiting for.
+    reads: BTreeMap<u64, Vec<oneshot::Sender<()>>>,
+    /// the snapshot being taken, it's abandoned by a restore.
+    snapshot: Option<PendingSnapshot>,
+}
+
+/// A snapshot whose chunks are being written.
+struct PendingSnapshot {
+    encoder: SnapshotEncoder,
+    /// whether all chunks have been written.
+    written: bool,
+}
+
+impl<S: StateMachine> Core<S> {
+    /// write the next chunk of the snapshot being taken.
+    ///
+    /// # returns
+    /// `false` if all chunks have been written, or no snapshot is being taken.
+    fn write_snapshot_chunk(&mut self) -> bool {
+        match self.snapshot.as_mut() {
+            Some(snapshot) if !snapshot.written => {
+                snapshot.written = !self.machine.snapshot_chunk(&mut snapshot.encoder);
+                !snapshot.written
+            }
+            _ => false,
+        }
+    }
+
+    /// send the result of the entry at `index` of `term` to its proposal,
+    /// or keep it for a proposal that is being registered.
+    fn resolve(&mut self, index: u64, term: u64, result: Result<Option<S::Output>>) {
+        match self.proposals.remove(&index) {
+            Some(proposal) => {
+                let result = if proposal.term == term {
+                    result
+                } else {
+                    Err(Error::NotCommitted)
+                };
+                let _ = proposal.sender.send(result);
+            }
+            None => {
+                if self
+                    .proposing
+                    .keys()
+                    .next()
+                    .map_or(false, |since| index > *since)
+                {
+                    self.unclaimed.insert(index, (term, result));
+                }
+            }
+        }
+    }
+
+    /// a proposal that began at `since` has been registered,
+    /// drop the results that no other proposal can claim.
+    fn end_proposing(&mut self, since: u64) {
+        let remains = {
+            let count = self.proposing.get_mut(&since).unwrap();
+            *count -= 1;
+            *count
+        };
+        if remains == 0 {
+            self.proposing.remove(&since);
+        }
+        match self.proposing.keys().next() {
+            Some(since) => self.unclaimed = self.unclaimed.split_off(&(since + 1)),
+            None => self.unclaimed.clear(),
+        }
+    }
+
+    /// wake up the reads waiting on `last_applied`.
+    fn wake_reads(&mut self) {
+        let pending = self.reads.split_off(&(self.last_applied + 1));
+        for sender in std::mem::replace(&mut self.reads, pending)
+            .into_iter()
+            .flat_map(|(_, senders)| senders)
+        {
+            let _ = sender.send(());
+        }
+    }
+}
+
+struct Inner<S: StateMachine> {
+    core: Mutex<Core<S>>,
+    raft: Node,
+    /// The internal channel to stop the apply worker.
+    cancel_ch: Sender<Option<ApplyMsg>>,
+    /// held while taking a snapshot, so only one snapshot is taken at a time.
+    snapshot_lock: Mutex<()>,
+    /// whether a snapshot is being taken in the background.
+    snapshotting: AtomicBool,
+}
+
+/// Drives a `StateMachine` by the entries raft applies.
+pub struct Driver<S: StateMachine> {
+    inner: Arc<Inner<S>>,
+}
+
+impl<S: StateMachine> Clone for Driver<S> {
+    fn clone(&self) -> Self {
+        Driver {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: StateMachine> Driver<S> {
+    /// start a driver, whose worker applies the messages from `apply_ch` to `machine`.
+    ///
+    /// A snapshot will be taken when raft `needs_snapshot`, see `RaftConfig::snapshot_threshold`.
+    pub fn new(machine: S, raft: Node, apply_ch: UnboundedReceiver<ApplyMsg>) -> Self {
+        let (cancel_ch, cancel) = channel(1);
+        let driver = Driver {
+            inner: Arc::new(Inner {
+                core: Mutex::new(Core {
+                    machine,
+                    sessions: HashMap::new(),
+                    last_applied: 0,
+                    last_applied_term: 0,
+                    proposals: BTreeMap::new(),
+                    proposing: BTreeMap::new(),
+                    unclaimed: BTreeMap::new(),
+                    reads: BTreeMap::new(),
+                    snapshot: None,
+                }),
+                raft,
+                cancel_ch,
+                snapshot_lock: Mutex::new(()),
+                snapshotting: AtomicBool::new(false),
+            }),
+        };
+        thread::spawn({
+            let driver = driver.clone();
+            move || {
+                let mut messages = apply_ch.map(Some).select(cancel).wait();
+                while let Some(Ok(Some(message))) = messages.next() {
+                    match message {
+                        ApplyMsg::Command { index, term, data } => {
+                            driver.apply(index, term, &data);
+                            driver.maybe_snapshot();
+                        }
+                        ApplyMsg::InstallSnapshot {
+                            last_included_term,
+                            commands,
+                            ..
+                        } => driver.restore(last_included_term, commands),
+                        ApplyMsg::NoOp { index, term } => driver.skip(index, term),
+                    }
+                }
+                info!("state machine driver ends.")
+            }
+        });
+        driver
+    }
+
+    /// the raft node that replicates the state machine.
+    pub fn raft(&self) -> &Node {
+        &self.inner.raft
+    }
+
+    /// propose a command.
+    ///
+    /// # returns
+    /// The future resolved after the command is applied,
+    /// with `None` if it's a retry of an applied command (see `StateMachine::session`).
+    pub fn propose(&self, command: &S::Command) -> StateMachineFuture<Option<S::Output>> {
+        // the entry may be applied before the proposal is registered,
+        // its result is kept in `unclaimed` then.
+        let since = {
+            let mut core = self.inner.core.lock().unwrap();
+            let since = core.last_applied;
+            *core.proposing.entry(since).or_default() += 1;
+            since
+        };
+        let proposed = self.inner.raft.propose(command);
+        let mut core = self.inner.core.lock().unwrap();
+        let (sender, receiver) = oneshot::channel();
+        if let Ok(proposal) = proposed.as_ref() {
+            let (index, term) = (proposal.index(), proposal.term());
+            if index <= core.last_applied {
+                let result = match core.unclaimed.remove(&index) {
+                    Some((applied_term, result)) if applied_term == term => result,
+                    // included in a snapshot, or replaced by an entry of another term.
+                    _ => Err(Error::NotCommitted),
+                };
+                let _ = sender.send(result);
+            } else {
+                core.proposals.insert(index, Proposal { term, sender });
+            }
+        }
+        core.end_proposing(since);
+        drop(core);
+        let proposal = match proposed {
+            Ok(proposal) => proposal,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let output = receiver
+            .map_err(|_| Error::Stopped)
+            .and_then(|result| result);
+        // fail fast once raft knows that the proposal won't be applied,
+        // e.g. the leader has stepped down, instead of waiting for another entry at its index.
+        let failed = proposal.then(|result| match result {
+            Ok(_) => Either::A(future::empty()),
+            Err(e) => Either::B(future::err(e)),
+        });
+        Box::new(
+            output
+                .select(failed)
+                .map(|(output, _)| output)
+                .map_err(|(e, _)| e),
+        )
+    }
+
+    /// read the state machine by ReadIndex, without appending anything to the raft log.
+    ///
+    /// # returns
+    /// The future resolved with `f(state)` after the state machine catches up the read index.
+    /// Fails with `Error::NoCommittedEntryInTerm` when the leader cannot serve reads yet,
+    /// the service may propose the read as a command instead.
+    pub fn read<R, F>(&self, f: F) -> StateMachineFuture<R>
+    where
+        F: FnOnce(&S) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let index = match self.inner.raft.read_index() {
+            Ok(index) => index,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let inner = self.inner.clone();
+        Box::new(
+            self.wait_applied(index)
+                .map(move |()| f(&inner.core.lock().unwrap().machine)),
+        )
+    }
+
+    /// check whether the command `id` is the last applied command of `client`.
+    pub fn has_applied(&self, client: &str, id: &[u8]) -> bool {
+        let core = self.inner.core.lock().unwrap();
+        core.sessions
+            .get(client)
+            .map_or(false, |last| last.as_slice() == id)
+    }
+
+    /// stop the worker, and shrink the raft log by a snapshot.
+    pub fn shutdown(&self) {
+        self.inner
+            .cancel_ch
+            .clone()
+            .send(None)
+            .wait()
+            .unwrap_or_else(|e| panic!("Failed to shutdown state machine driver, because: {}", e));
+        let mut core = self.inner.core.lock().unwrap();
+        // drop all pending channels.
+        core.proposals.clear();
+        core.reads.clear();
+        drop(core);
+
+        // shrink log size (synchronously) to make tester happy.
+        self.take_snapshot();
+    }
+
+    /// wait until the state machine has applied to `index`.
+    fn wait_applied(&self, index: u64) -> StateMachineFuture<()> {
+        let mut core = self.inner.core.lock().unwrap();
+        if core.last_applied >= index {
+            return Box::new(future::ok(()));
+        }
+        let (sender, receiver) = oneshot::channel();
+        core.reads.entry(index).or_default().push(sender);
+        Box::new(receiver.map_err(|_| Error::Stopped))
+    }
+
+    /// apply a committed entry of `term`.
+    ///
+    /// # panics
+    /// if the entry isn't a `S::Command`.
+    fn apply(&self, index: u64, term: u64, data: &[u8]) {
+        let mut core = self.inner.core.lock().unwrap();
+        if index <= core.last_applied {
+            // this entry has been applied, or is included in the snapshot.
+            return;
+        }
+        let command = decode::<S::Command>(data)
+            .unwrap_or_else(|e| panic!("Invalid entry {} received: {}", index, e));
+        let output = match S::session(&command) {
+            Some((client, id)) => {
+                if core.sessions.get(&client) == Some(&id) {
+                    debug!("command {:?} of {} has been applied.", id, client);
+                    None
+                } else {
+                    core.sessions.insert(client, id);
+                    Some(core.machine.apply(command))
+                }
+            }
+            None => Some(core.machine.apply(command)),
+        };
+        core.last_applied = index;
+        core.last_applied_term = term;
+        // keep the memory of the snapshot being taken bounded.
+        while core.machine.snapshot_backlog() > MAX_SNAPSHOT_BACKLOG {
+            if !core.write_snapshot_chunk() {
+                break;
+            }
+        }
+        core.wake_reads();
+        core.resolve(index, term, Ok(output));
+    }
+
+    /// skip a committed entry of `term` that isn't a command, e.g. the no-op of a new leader.
+    fn skip(&self, index: u64, term: u64) {
+        let mut core = self.inner.core.lock().unwrap();
+        if index <= core.last_applied {
+            return;
+        }
+        core.last_applied = index;
+        core.last_applied_term = term;
+        core.wake_reads();
+        core.resolve(index, term, Err(Error::NotCommitted));
+    }
+
+    /// replace the state machine by the chunks of a snapshot from raft,
+    /// whose last included entry is of `term`.
+    fn restore(&self, term: u64, chunks: Vec<Vec<u8>>) {
+        let mut chunks = chunks.into_iter();
+        let header = match chunks.next() {
+            Some(header) => header,
+            // the empty snapshot of a fresh raft.
+            None => return,
+        };
+        let snapshot = decode::<StateMachineSnapshot>(&header)
+            .unwrap_or_else(|e| panic!("Failed to decode the state machine snapshot: {}", e));
+        let mut core = self.inner.core.lock().unwrap();
+        core.machine.restore(&mut chunks);
+        core.snapshot = None;
+        core.sessions = snapshot.sessions;
+        core.last_applied = snapshot.last_applied;
+        core.last_applied_term = term;
+        // we cannot tell whether the proposals in the snapshot are applied.
+        let pending = core.proposals.split_off(&(core.last_applied + 1));
+        for (_, proposal) in std::mem::replace(&mut core.proposals, pending) {
+            let _ = proposal.sender.send(Err(Error::NotCommitted));
+        }
+        core.wake_reads();
+    }
+
+    /// take a snapshot in the background if raft needs one, and none is being taken.
+    fn maybe_snapshot(&self) {
+        if self.inner.raft.needs_snapshot() && !self.inner.snapshotting.swap(true, Ordering::AcqRel)
+        {
+            let driver = self.clone();
+            thread::spawn(move || {
+                driver.take_snapshot();
+                driver.inner.snapshotting.store(false, Ordering::Release);
+            });
+        }
+    }
+
+    /// take a snapshot chunk by chunk, the commands are applied between the chunks.
+    fn take_snapshot(&self) {
+        let _snapshotting = self.inner.snapshot_lock.lock().unwrap();
+        let mut core = self.inner.core.lock().unwrap();
+        let header = StateMachineSnapshot {
+            last_applied: core.last_applied,
+            sessions: core.sessions.clone(),
+        };
+        let mut encoder = SnapshotEncoder::new(core.last_applied, core.last_applied_term);
+        let mut data = vec![];
+        encode(&header, &mut data).unwrap();
+        encoder.write(data);
+        core.machine.begin_snapshot();
+        core.snapshot = Some(PendingSnapshot {
+            encoder,
+            written: false,
+        });
+        drop(core);
+
+        while self.inner.core.lock().unwrap().write_snapshot_chunk() {}
+        let snapshot = self.inner.core.lock().unwrap().snapshot.take();
+        match snapshot {
+            Some(snapshot) => self.inner.raft.take_encoded_snapshot(snapshot.encoder),
+            None => info!(
+                "a snapshot is restored, abandon the snapshot to {}.",
+                header.last_applied
+            ),
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! A cluster harness for testing services built on raft, public with the `testing` feature.
+//!
+//! A `Cluster` runs N servers over a `labrpc::Network`, each made by the function passed to
+//! `Cluster::new` from the clients to its raft peers and its persister. The tests then crash and
+//! restart servers (keeping what they have persisted, see `FaultyPersister` for injecting faults
+//! into the writes), partition them, and find the leader, like the testers of raft and kvraft do.
+//!
+//! The testers are built on it: `raft::config::Config` runs its rafts by a `Cluster`, and adds
+//! the checks of the applied entries; `kvraft::config::Config` adds the clerks of kvraft.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::proto::raftpb::{add_raft_service, RaftClient};
+use crate::raft::persister::{FaultyPersister, Persister};
+use crate::raft::Node;
+
+static ID: AtomicUsize = AtomicUsize::new(100_000);
+
+fn uniqstring() -> String {
+    format!("{}", ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// A server of a `Cluster`, the raft peer and the service on it.
+pub trait Server {
+    /// the raft peer of this server.
+    fn raft(&self) -> Node;
+
+    /// add the rpc services of this server to `builder`, including the raft service.
+    fn add_services(&self, builder: &mut labrpc::ServerBuilder);
+
+    /// stop this server, it won't be used again.
+    fn kill(&self);
+}
+
+/// A server running raft alone.
+impl Server for Node {
+    fn raft(&self) -> Node {
+        self.clone()
+    }
+
+    fn add_services(&self, builder: &mut labrpc::ServerBuilder) {
+        add_raft_service(self.clone(), builder).unwrap();
+    }
+
+    fn kill(&self) {
+        Node::kill(self);
+    }
+}
+
+/// The function making server `i` from the clients to the raft peers and the persister.
+type MakeServer<S> = dyn Fn(usize, Vec<RaftClient>, Box<dyn Persister>) -> S + Send + Sync;
+
+struct Servers<S> {
+    // `None` for a crashed server.
+    servers: Vec<Option<S>>,
+    saved: Vec<Arc<FaultyPersister>>,
+    // the names of the client ends server i sends to server j by.
+    endnames: Vec<Vec<String>>,
+}
+
+/// N servers over a `labrpc::Network`.
+///
+/// The servers are named `0..n` in the network, so the clients of the service
+/// connect to server `j` by `net.connect(client, &j.to_string())`.
+pub struct Cluster<S: Server> {
+    pub net: labrpc::Network,
+    pub n: usize,
+    make: Box<MakeServer<S>>,
+    servers: Mutex<Servers<S>>,
+}
+
+impl<S: Server> Cluster<S> {
+    /// start `n` servers made by `make` on `net`, all connected with each other.
+    pub fn new(
+        net: labrpc::Network,
+        n: usize,
+        make: impl Fn(usize, Vec<RaftClient>, Box<dyn Persister>) -> S + Send + Sync + 'static,
+    ) -> Cluster<S> {
+        let servers = Servers {
+            servers: (0..n).map(|_| None).collect(),
+            saved: (0..n).map(|_| Arc::new(FaultyPersister::new())).collect(),
+            endnames: vec![vec![String::new(); n]; n],
+        };
+        let cluster = Cluster {
+            net,
+            n,
+            make: Box::new(make),
+            servers: Mutex::new(servers),
+        };
+        for i in 0..n {
+            cluster.start(i);
+        }
+        cluster.connect_all();
+        cluster
+    }
+
+    pub fn all(&self) -> Vec<usize> {
+        (0..self.n).collect()
+    }
+
+    /// the server i, `None` if it has crashed.
+    pub fn server(&self, i: usize) -> Option<S>
+    where
+        S: Clone,
+    {
+        self.servers.lock().unwrap().servers[i].clone()
+    }
+
+    /// the raft peer of server i, `None` if it has crashed.
+    pub fn raft(&self, i: usize) -> Option<Node> {
+        self.servers.lock().unwrap().servers[i]
+            .as_ref()
+            .map(Server::raft)
+    }
+
+    /// the persister of server i, to inject faults into its writes.
+    pub fn persister(&self, i: usize) -> Arc<FaultyPersister> {
+        self.servers.lock().unwrap().saved[i].clone()
+    }
+
+    /// the largest raft state persisted by the servers.
+    pub fn log_size(&self) -> usize {
+        let servers = self.servers.lock().unwrap();
+        servers
+            .saved
+            .iter()
+            .map(|p| p.raft_state().len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// the largest snapshot persisted by the servers.
+    pub fn snapshot_size(&self) -> usize {
+        let servers = self.servers.lock().unwrap();
+        servers
+            .saved
+            .iter()
+            .map(|p| p.snapshot().len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// start or restart server i from what it has persisted, crashing it first if it's running.
+    /// It can't talk with any server until `connect`ed.
+    pub fn start(&self, i: usize) {
+        let mut servers = self.servers.lock().unwrap();
+        self.crash_server(i, &mut servers);
+
+        // a fresh set of client ends, so the crashed instance can't send.
+        servers.endnames[i] = (0..self.n).map(|_| uniqstring()).collect();
+        let clients: Vec<RaftClient> = servers.endnames[i]
+            .iter()
+            .enumerate()
+            .map(|(j, name)| {
+                let client = self.net.create_client(name.clone());
+                self.net.connect(name, &format!("{}", j));
+                RaftClient::new(client)
+            })
+            .collect();
+
+        let server = (self.make)(i, clients, Box::new(servers.saved[i].clone()));
+        let mut builder = labrpc::ServerBuilder::new(format!("{}", i));
+        server.add_services(&mut builder);
+        self.net.add_server(builder.build());
+        servers.servers[i] = Some(server);
+    }
+
+    /// crash server i, keeping what it has persisted.
+    pub fn crash(&self, i: usize) {
+        let mut servers = self.servers.lock().unwrap();
+        self.crash_server(i, &mut servers);
+    }
+
+    fn crash_server(&self, i: usize, servers: &mut Servers<S>) {
+        self.disconnect_from(i, &self.all(), servers);
+        // disable the client connections to the server before crashing its persister,
+        // so it can't reply to a request whose result isn't persisted.
+        self.net.delete_server(&format!("{}", i));
+
+        // a fresh persister holding what has been saved, so the crashed instance can't
+        // overwrite it. the write in flight may be lost or torn.
+        servers.saved[i] = Arc::new(servers.saved[i].crash());
+        if let Some(server) = servers.servers[i].take() {
+            server.kill();
+        }
+    }
+
+    /// let server i talk with the servers in `to`.
+    pub fn connect(&self, i: usize, to: &[usize]) {
+        let servers = self.servers.lock().unwrap();
+        self.connect_to(i, to, &servers);
+    }
+
+    /// stop server i from talking with the servers in `from`.
+    pub fn disconnect(&self, i: usize, from: &[usize]) {
+        let servers = self.servers.lock().unwrap();
+        self.disconnect_from(i, from, &servers);
+    }
+
+    pub fn connect_all(&self) {
+        let servers = self.servers.lock().unwrap();
+        for i in 0..self.n {
+            self.connect_to(i, &self.all(), &servers);
+        }
+    }
+
+    /// split the servers into two parts, only the servers in the same part can talk with each other.
+    pub fn partition(&self, p1: &[usize], p2: &[usize]) {
+        debug!("partition servers into: {:?} {:?}", p1, p2);
+        let servers = self.servers.lock().unwrap();
+        for (part, other) in &[(p1, p2), (p2, p1)] {
+            for i in part.iter() {
+                self.disconnect_from(*i, other, &servers);
+                self.connect_to(*i, part, &servers);
+            }
+        }
+    }
+
+    fn connect_to(&self, i: usize, to: &[usize], servers: &Servers<S>) {
+        debug!("connect peer {} to {:?}", i, to);
+        for j in to {
+            self.net.enable(&servers.endnames[i][*j], true);
+            self.net.enable(&servers.endnames[*j][i], true);
+        }
+    }
+
+    fn disconnect_from(&self, i: usize, from: &[usize], servers: &Servers<S>) {
+        debug!("disconnect peer {} from {:?}", i, from);
+        for j in from {
+            self.net.enable(&servers.endnames[i][*j], false);
+            self.net.enable(&servers.endnames[*j][i], false);
+        }
+    }
+
+    /// make the clock of the raft of server i jump forward.
+    pub fn jump_clock(&self, i: usize, by: Duration) {
+        if let Some(rf) = self.raft(i) {
+            rf.jump_clock(by);
+        }
+    }
+
+    /// the leaders of the running servers, by their terms.
+    ///
+    /// # panics
+    /// if a term has more than one leader.
+    fn leaders(&self) -> HashMap<u64, usize> {
+        let rafts = (0..self.n).filter_map(|i| self.raft(i).map(|rf| (i, rf)));
+        let mut leaders = HashMap::new();
+        for (i, rf) in rafts {
+            let state = rf.get_state();
+            if state.is_leader() {
+                if let Some(j) = leaders.insert(state.term(), i) {
+                    panic!("term {} has leaders {} and {}", state.term(), j, i);
+                }
+            }
+        }
+        leaders
+    }
+
+    /// the running server that believes it's the leader of the newest term, if any.
+    /// A partitioned server may still believe it's the leader of an older term.
+    pub fn leader(&self) -> Option<usize> {
+        let leaders = self.leaders();
+        leaders.keys().max().map(|term| leaders[term])
+    }
+
+    /// wait until a leader is elected, trying a few times in case re-elections are needed.
+    ///
+    /// # panics
+    /// if no leader is elected in about 5 seconds, or a term has more than one leader.
+    pub fn check_one_leader(&self) -> usize {
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            thread::sleep(Duration::from_millis(450 + rng.gen_range(0, 100)));
+            if let Some(leader) = self.leader() {
+                return leader;
+            }
+        }
+        panic!("expected one leader, got none")
+    }
+}
+
+impl<S: Server> Drop for Cluster<S> {
+    fn drop(&mut self) {
+        if let Ok(servers) = self.servers.lock() {
+            for server in servers.servers.iter().flatten() {
+                server.kill();
+            }
+        }
+    }
+}
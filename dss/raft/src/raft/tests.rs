@@ -1,17 +1,43 @@
 #![allow(clippy::identity_op)]
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::sync::oneshot;
-use futures::{future, Future};
+use futures::{future, Future, Stream};
 use rand::{Rng, ThreadRng};
 
+use labrpc::RpcFuture;
+
+use crate::proto::raftpb::{
+    add_multi_raft_service, add_raft_service, AppendEntriesArgs, AppendEntriesReply,
+    InstallSnapshotArgs, InstallSnapshotReply, MultiRaftClient, PersistedStatus, ProtoEntry,
+    RaftClient, RaftStatus, RequestVoteArgs, RequestVoteReply, Role, Snapshot,
+};
 use crate::raft::config::{Config, Entry, Storage};
-use crate::raft::Node;
+use crate::raft::dump;
+use crate::raft::errors::Error;
+use crate::raft::invariants::InvariantChecker;
+use crate::raft::log::{MemoryLog, RaftLog};
+use crate::raft::multi::Host;
+use crate::raft::nemesis;
+use crate::raft::persister::{FaultyPersister, Persister, SimplePersister};
+use crate::raft::segmented_log::SegmentedLog;
+use crate::raft::state_machine::{Driver, SnapshotReader, SnapshotWriter, StateMachine};
+use crate::raft::testing::{self, Cluster};
+use crate::raft::{
+    priorities_digest, ApplyMsg, Event, LogEntry, Message, Node, NodeEvent, ProgressState, Raft,
+    RaftConfig, RaftEvent, Response, SnapshotEncoder, SnapshotFile, Transport,
+};
 
 /// The tester generously allows solutions to complete elections in one second
 /// (much more than the paper's range of timeouts).
@@ -85,6 +111,348 @@ fn test_reelection_2a() {
     cfg.end();
 }
 
+#[test]
+fn test_check_quorum_2a() {
+    let servers = 5;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2A): isolated leader steps down");
+
+    let leader1 = cfg.check_one_leader();
+    // the leader with only one follower can't reach a majority.
+    let follower = (leader1 + 1) % servers;
+    for i in 0..servers {
+        if i != leader1 && i != follower {
+            cfg.disconnect(i);
+        }
+    }
+    thread::sleep(2 * RAFT_ELECTION_TIMEOUT);
+    if node_of(&cfg, leader1).is_leader() {
+        panic!(
+            "leader {} isolated from the majority doesn't step down",
+            leader1
+        );
+    }
+
+    // the majority elects a new leader, and the old one follows it after rejoining.
+    for i in 0..servers {
+        cfg.connect(i);
+    }
+    cfg.check_one_leader();
+    cfg.one(Entry { x: 100 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_subscribe_2a() {
+    let servers = 3;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2A): subscribe role changes");
+
+    let leader1 = cfg.check_one_leader();
+    let term1 = node_of(&cfg, leader1).term();
+    let subscriptions = (0..servers)
+        .map(|i| node_of(&cfg, i).subscribe())
+        .collect::<Vec<_>>();
+
+    // the isolated leader steps down, and the others elect a new one.
+    cfg.disconnect(leader1);
+    let leader2 = cfg.check_one_leader();
+    let term2 = node_of(&cfg, leader2).term();
+    thread::sleep(RAFT_ELECTION_TIMEOUT);
+    cfg.connect(leader1);
+    cfg.check_one_leader();
+    cfg.end();
+
+    // the subscriptions end once the peers are killed.
+    for i in 0..servers {
+        cfg.crash1(i);
+    }
+    let events = subscriptions
+        .into_iter()
+        .map(|subscription| subscription.wait().map(Result::unwrap).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        events[leader1][..2],
+        [
+            NodeEvent::RoleChanged {
+                role: Role::Leader,
+                term: term1
+            },
+            NodeEvent::LeaderChanged {
+                leader: Some(leader1 as u64),
+                term: term1
+            },
+        ]
+    );
+    let stepped_down = NodeEvent::RoleChanged {
+        role: Role::Follower,
+        term: term1,
+    };
+    if !events[leader1].contains(&stepped_down) {
+        panic!(
+            "the isolated leader didn't report stepping down: {:?}",
+            events[leader1]
+        );
+    }
+    let elected = NodeEvent::RoleChanged {
+        role: Role::Leader,
+        term: term2,
+    };
+    if !events[leader2].contains(&elected) {
+        panic!(
+            "the new leader didn't report its election: {:?}",
+            events[leader2]
+        );
+    }
+    let follower = (0..servers)
+        .find(|i| *i != leader1 && *i != leader2)
+        .unwrap();
+    let learned = NodeEvent::LeaderChanged {
+        leader: Some(leader2 as u64),
+        term: term2,
+    };
+    if !events[follower].contains(&learned) {
+        panic!(
+            "the follower didn't report the new leader: {:?}",
+            events[follower]
+        );
+    }
+    for events in &events {
+        let terms = events
+            .iter()
+            .filter_map(|event| match *event {
+                NodeEvent::TermChanged { term } => Some(term),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if terms.windows(2).any(|w| w[0] >= w[1]) {
+            panic!("the terms aren't increasing: {:?}", events);
+        }
+    }
+}
+
+#[test]
+fn test_config_validation_2a() {
+    use crate::raft::RaftConfig;
+
+    assert!(RaftConfig::builder().build().is_ok());
+    // the effective settings can be read back.
+    let config = RaftConfig::builder()
+        .election_timeout(Duration::from_millis(200), Duration::from_millis(400))
+        .max_append(16, 4096)
+        .snapshot_threshold(Some(1024))
+        .build()
+        .unwrap();
+    let (min, max) = config.election_timeout();
+    assert_eq!(
+        (min, max),
+        (Duration::from_millis(200), Duration::from_millis(400))
+    );
+    assert_eq!(config.max_append(), (16, 4096));
+    assert_eq!(config.snapshot_threshold(), Some(1024));
+    assert_eq!(
+        config.heartbeat_interval(),
+        RaftConfig::default().heartbeat_interval()
+    );
+    let invalid = vec![
+        RaftConfig::builder()
+            .election_timeout(Duration::from_millis(300), Duration::from_millis(150)),
+        RaftConfig::builder().heartbeat_interval(Duration::from_millis(100)),
+        RaftConfig::builder().read_lease(Duration::from_millis(200)),
+        RaftConfig::builder().max_append(0, 1024),
+        RaftConfig::builder().snapshot_chunk_bytes(0),
+    ];
+    for builder in invalid {
+        match builder.build() {
+            Err(Error::InvalidConfig(_)) => {}
+            other => panic!("expected InvalidConfig, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_election_priority_2a() {
+    let servers = 5;
+    // server 4 is preferred, then server 3.
+    let mut cfg = Config::new_with_priorities(servers, false, vec![1, 1, 1, 2, 3]);
+    cfg.begin("Test (2A): election priority");
+
+    let wait_leader = |cfg: &Config, expected: usize| {
+        let t0 = Instant::now();
+        loop {
+            let leader = cfg.check_one_leader();
+            if leader == expected {
+                return;
+            }
+            if t0.elapsed() > RAFT_ELECTION_TIMEOUT * 5 {
+                panic!("expected leader {}, got {}", expected, leader);
+            }
+        }
+    };
+    wait_leader(&cfg, 4);
+    cfg.one(Entry { x: 101 }, servers, true);
+
+    // without the preferred server, the next one is elected.
+    cfg.disconnect(4);
+    wait_leader(&cfg, 3);
+    cfg.one(Entry { x: 102 }, servers - 1, true);
+
+    // the preferred server catches up, and the leader hands off to it.
+    cfg.connect(4);
+    wait_leader(&cfg, 4);
+    cfg.one(Entry { x: 103 }, servers, true);
+
+    // the servers of low priority are still elected without the others.
+    cfg.disconnect(4);
+    cfg.disconnect(3);
+    cfg.check_one_leader();
+    cfg.one(Entry { x: 104 }, servers - 2, true);
+
+    cfg.end();
+}
+
+/// start peer 0 of `peers` of `priorities` by a `RecordingTransport`.
+fn priority_peer(peers: usize, priorities: Vec<u64>) -> Raft {
+    let config = RaftConfig::builder()
+        .priorities(priorities)
+        .build()
+        .unwrap();
+    let transport = RecordingTransport {
+        peers,
+        sent: Arc::default(),
+    };
+    let persister = Box::new(SimplePersister::new());
+    let log = Box::new(MemoryLog::default());
+    Raft::with_transport(
+        Box::new(transport),
+        0,
+        persister,
+        unbounded().0,
+        config,
+        log,
+    )
+}
+
+#[test]
+fn test_priority_vote_2a() {
+    let mut raft = priority_peer(2, vec![2, 1]);
+    let mut args = RequestVoteArgs {
+        term: 1,
+        candidate_id: 1,
+        last_log_index: 0,
+        last_log_term: 0,
+        leader_transfer: false,
+        priorities_digest: priorities_digest(&[2, 1]),
+    };
+    // an up-to-date peer of higher priority declines the candidate, so it's elected instead.
+    assert!(!raft.do_request_vote(args.clone()).vote_granted);
+    // unless the leader hands off to the candidate.
+    args.leader_transfer = true;
+    assert!(raft.do_request_vote(args.clone()).vote_granted);
+
+    // a peer of lower priority votes as usual.
+    let mut raft = priority_peer(2, vec![1, 2]);
+    args.leader_transfer = false;
+    args.priorities_digest = priorities_digest(&[1, 2]);
+    assert!(raft.do_request_vote(args.clone()).vote_granted);
+
+    // so does a peer given other priorities than the candidate.
+    let mut raft = priority_peer(2, vec![2, 1]);
+    args.priorities_digest = priorities_digest(&[1, 2]);
+    assert!(raft.do_request_vote(args).vote_granted);
+
+    // the priorities must be given to each peer.
+    let config = RaftConfig::builder()
+        .priorities(vec![2, 1])
+        .build()
+        .unwrap();
+    assert!(config.check_peers(2).is_ok());
+    match config.check_peers(3) {
+        Err(Error::InvalidConfig(_)) => {}
+        other => panic!("expected InvalidConfig, got {:?}", other),
+    }
+    let started = panic::catch_unwind(|| priority_peer(3, vec![2, 1]));
+    assert!(
+        started.is_err(),
+        "a peer starts with priorities of other peers"
+    );
+}
+
+#[test]
+fn test_campaign_delay_2a() {
+    let mut raft = priority_peer(3, vec![1, 2, 3]);
+    let min = raft.extra.min_election_timeout;
+    // without a peer of higher priority known to be up, this peer doesn't wait.
+    assert_eq!(raft.campaign_delay(), Duration::from_millis(0));
+
+    // the leader reports peer 1 up-to-date, so this peer waits for it to campaign first,
+    // but not for the leader, which has gone once the election timer fires.
+    let args = AppendEntriesArgs {
+        term: 1,
+        leader_id: 2,
+        up_to_date_peers: vec![1],
+        ..AppendEntriesArgs::default()
+    };
+    assert!(raft.do_append_entries(args).success);
+    assert_eq!(raft.campaign_delay(), min);
+
+    // nor once peer 1 hasn't been reported for an election timeout.
+    raft.clock_offset += raft.extra.max_election_timeout;
+    assert_eq!(raft.campaign_delay(), Duration::from_millis(0));
+
+    // an up-to-date candidate is reachable as well.
+    let args = RequestVoteArgs {
+        term: 2,
+        candidate_id: 2,
+        priorities_digest: priorities_digest(&[1, 2, 3]),
+        ..RequestVoteArgs::default()
+    };
+    raft.do_request_vote(args);
+    assert_eq!(raft.campaign_delay(), min);
+}
+
+#[test]
+fn test_priority_hand_off_2a() {
+    let config = RaftConfig::builder()
+        .priorities(vec![1, 2, 1])
+        .read_lease(Duration::from_millis(100))
+        .build()
+        .unwrap();
+    let (mut raft, sent, _apply_ch) = recording_leader(3, Box::new(SimplePersister::new()), config);
+    let term = raft.term;
+    let timeout = raft.extra.max_election_timeout;
+    raft.tick();
+    let probes = (1..3)
+        .map(|i| sent.lock().unwrap().take_append_entries(i).remove(0))
+        .collect::<Vec<_>>();
+    for (i, probe) in probes.iter().enumerate() {
+        reply_append_entries(&mut raft, i + 1, probe.clone(), accepted(term));
+    }
+    assert!(raft.has_lease());
+    // the followers learn which peers are up-to-date.
+    assert_eq!(raft.make_append_entries_for(2).up_to_date_peers, vec![1]);
+
+    // the leader hands off to peer 1, which has caught up, and gives up the lease meanwhile.
+    raft.transfer_leadership();
+    assert_eq!(raft.leader_state.as_ref().unwrap().transferee.unwrap().0, 1);
+    assert!(raft.make_append_entries_for(1).timeout_now);
+    assert!(!raft.has_lease());
+
+    // peer 1 becomes unreachable, the hand-off is given up.
+    raft.clock_offset += timeout;
+    reply_append_entries(&mut raft, 2, probes[1].clone(), accepted(term));
+    raft.transfer_leadership();
+    assert!(raft.leader_state.as_ref().unwrap().transferee.is_none());
+    assert!(!raft.make_append_entries_for(1).timeout_now);
+    // the lease resumes once a delayed `timeout_now` can't arrive any more.
+    assert!(!raft.has_lease());
+    raft.clock_offset += timeout;
+    reply_append_entries(&mut raft, 2, probes[1].clone(), accepted(term));
+    assert!(raft.has_lease());
+}
+
 #[test]
 fn test_basic_agree_2b() {
     let servers = 5;
@@ -92,7 +460,8 @@ fn test_basic_agree_2b() {
     cfg.begin("Test (2B): basic agreement");
 
     let iters = 3;
-    for index in 1..=iters {
+    // the entry at 1 is the no-op of the leader.
+    for index in 2..=iters + 1 {
         let (nd, _) = cfg.n_committed(index);
         if nd > 0 {
             panic!("some have committed before start()");
@@ -145,6 +514,7 @@ fn test_fail_no_agree_2b() {
 
     cfg.begin("Test (2B): no agreement if too many followers disconnect");
 
+    // the entry at 1 is the no-op of the leader.
     cfg.one(Entry { x: 10 }, servers, false);
 
     // 3 of 5 followers disconnect
@@ -152,13 +522,13 @@ fn test_fail_no_agree_2b() {
     cfg.disconnect((leader + 1) % servers);
     cfg.disconnect((leader + 2) % servers);
     cfg.disconnect((leader + 3) % servers);
-    let (index, _) = cfg.rafts.lock().unwrap()[leader]
-        .as_ref()
+    let (index, _) = cfg
+        .raft(leader)
         .unwrap()
         .start(&Entry { x: 20 })
         .expect("leader rejected start");
-    if index != 2 {
-        panic!("expected index 2, got {}", index);
+    if index != 3 {
+        panic!("expected index 3, got {}", index);
     }
 
     thread::sleep(2 * RAFT_ELECTION_TIMEOUT);
@@ -174,14 +544,15 @@ fn test_fail_no_agree_2b() {
     cfg.connect((leader + 3) % servers);
 
     // the disconnected majority may have chosen a leader from
-    // among their own ranks, forgetting index 2.
+    // among their own ranks, forgetting index 3. The leaders elected since then
+    // append their no-ops, the majority's one at 3, and the one after the repair.
     let leader2 = cfg.check_one_leader();
-    let (index2, _) = cfg.rafts.lock().unwrap()[leader2]
-        .as_ref()
+    let (index2, _) = cfg
+        .raft(leader2)
         .unwrap()
         .start(&Entry { x: 30 })
         .expect("leader2 rejected start");
-    if index2 < 2 || index2 > 3 {
+    if index2 < 3 || index2 > 5 {
         panic!("unexpected index {}", index2);
     }
 
@@ -204,11 +575,7 @@ fn test_concurrent_starts_2b() {
         }
 
         let leader = cfg.check_one_leader();
-        let term = match cfg.rafts.lock().unwrap()[leader]
-            .as_ref()
-            .unwrap()
-            .start(&Entry { x: 1 })
-        {
+        let term = match cfg.raft(leader).unwrap().start(&Entry { x: 1 }) {
             Err(err) => {
                 warn!("start leader {} meet error {:?}", leader, err);
                 continue;
@@ -220,7 +587,7 @@ fn test_concurrent_starts_2b() {
         for ii in 0..5 {
             let (tx, rx) = oneshot::channel();
             idx_rxs.push(rx);
-            let node = cfg.rafts.lock().unwrap()[leader].clone().unwrap();
+            let node = cfg.raft(leader).unwrap();
             cfg.net.spawn(future::lazy(move || {
                 let idx = match node.start(&Entry { x: 100 + ii }) {
                     Err(err) => {
@@ -241,7 +608,7 @@ fn test_concurrent_starts_2b() {
         let idxes = future::join_all(idx_rxs).wait().unwrap();
 
         for j in 0..servers {
-            let t = cfg.rafts.lock().unwrap()[j].as_ref().unwrap().term();
+            let t = cfg.raft(j).unwrap().term();
             if t != term {
                 // term changed -- can't expect low RPC counts
                 continue 'outer;
@@ -294,18 +661,9 @@ fn test_rejoin_2b() {
     cfg.disconnect(leader1);
 
     // make old leader try to agree on some entries
-    let _ = cfg.rafts.lock().unwrap()[leader1]
-        .as_ref()
-        .unwrap()
-        .start(&Entry { x: 102 });
-    let _ = cfg.rafts.lock().unwrap()[leader1]
-        .as_ref()
-        .unwrap()
-        .start(&Entry { x: 103 });
-    let _ = cfg.rafts.lock().unwrap()[leader1]
-        .as_ref()
-        .unwrap()
-        .start(&Entry { x: 104 });
+    let _ = cfg.raft(leader1).unwrap().start(&Entry { x: 102 });
+    let _ = cfg.raft(leader1).unwrap().start(&Entry { x: 103 });
+    let _ = cfg.raft(leader1).unwrap().start(&Entry { x: 104 });
 
     // new leader commits, also for index=2
     cfg.one(Entry { x: 103 }, 2, true);
@@ -345,10 +703,7 @@ fn test_backup_2b() {
 
     // submit lots of commands that won't commit
     for _i in 0..50 {
-        let _ = cfg.rafts.lock().unwrap()[leader1]
-            .as_ref()
-            .unwrap()
-            .start(&random_entry(&mut random));
+        let _ = cfg.raft(leader1).unwrap().start(&random_entry(&mut random));
     }
 
     thread::sleep(RAFT_ELECTION_TIMEOUT / 2);
@@ -376,10 +731,7 @@ fn test_backup_2b() {
 
     // lots more commands that won't commit
     for _i in 0..50 {
-        let _ = cfg.rafts.lock().unwrap()[leader2]
-            .as_ref()
-            .unwrap()
-            .start(&random_entry(&mut random));
+        let _ = cfg.raft(leader2).unwrap().start(&random_entry(&mut random));
     }
 
     thread::sleep(RAFT_ELECTION_TIMEOUT / 2);
@@ -440,11 +792,7 @@ fn test_count_2b() {
         total1 = rpcs(&cfg);
 
         let iters = 10;
-        let (starti, term) = match cfg.rafts.lock().unwrap()[leader]
-            .as_ref()
-            .unwrap()
-            .start(&Entry { x: 1 })
-        {
+        let (starti, term) = match cfg.raft(leader).unwrap().start(&Entry { x: 1 }) {
             Ok((starti, term)) => (starti, term),
             Err(err) => {
                 warn!("start leader {} meet error {:?}", leader, err);
@@ -457,11 +805,7 @@ fn test_count_2b() {
         for i in 1..iters + 2 {
             let x = random.gen::<u64>();
             cmds.push(x);
-            match cfg.rafts.lock().unwrap()[leader]
-                .as_ref()
-                .unwrap()
-                .start(&Entry { x })
-            {
+            match cfg.raft(leader).unwrap().start(&Entry { x }) {
                 Ok((index1, term1)) => {
                     if term1 != term {
                         // Term changed while starting
@@ -494,7 +838,7 @@ fn test_count_2b() {
         let mut failed = false;
         total2 = 0;
         for j in 0..SERVERS {
-            let t = cfg.rafts.lock().unwrap()[j].as_ref().unwrap().term();
+            let t = cfg.raft(j).unwrap().term();
             if t != term {
                 // term changed -- can't expect low RPC counts
                 // need to keep going to update total2
@@ -536,134 +880,859 @@ fn test_count_2b() {
 }
 
 #[test]
-fn test_persist1_2c() {
-    let servers = 3;
+fn test_pipelined_agree_2b() {
+    let servers = 5;
     let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2B): pipelined agreement of back-to-back start()s");
 
-    cfg.begin("Test (2C): basic persistence");
-
-    cfg.one(Entry { x: 11 }, servers, true);
-
-    // crash and re-start all
-    for i in 0..servers {
-        cfg.start1(i);
+    cfg.one(Entry { x: 1 }, servers, false);
+    let leader = cfg.check_one_leader();
+    let node = node_of(&cfg, leader);
+    let t0 = Instant::now();
+    let mut last = None;
+    for x in 0..100 {
+        match node.start(&Entry { x: 100 + x }) {
+            Ok((index, term)) => last = Some((index, term)),
+            Err(err) => panic!("start leader {} meet error {:?}", leader, err),
+        }
     }
-    for i in 0..servers {
-        cfg.disconnect(i);
-        cfg.connect(i);
+    let (index, term) = last.unwrap();
+    if cfg.wait(index, servers, Some(term)).is_none() {
+        panic!("the leader changed while committing 100 back-to-back entries");
+    }
+    if t0.elapsed() > RAFT_ELECTION_TIMEOUT {
+        panic!("100 back-to-back entries took {:?} to commit", t0.elapsed());
     }
 
-    cfg.one(Entry { x: 12 }, servers, true);
-
-    let leader1 = cfg.check_one_leader();
-    cfg.disconnect(leader1);
-    cfg.start1(leader1);
-    cfg.connect(leader1);
+    cfg.end();
+}
 
-    cfg.one(Entry { x: 13 }, servers, true);
+/// set in the process that a test counting threads runs itself in, see `in_own_process`.
+const THREAD_COUNT_CHILD: &str = "RAFT_THREAD_COUNT_CHILD";
 
-    let leader2 = cfg.check_one_leader();
-    cfg.disconnect(leader2);
-    cfg.one(Entry { x: 14 }, servers - 1, true);
-    cfg.start1(leader2);
-    cfg.connect(leader2);
+/// run `test` again in a process of its own, since the other tests spawn threads meanwhile.
+///
+/// # returns
+/// `true` in that process, where the test goes on, `false` in the process that ran it.
+fn in_own_process(test: &str) -> bool {
+    if std::env::var_os(THREAD_COUNT_CHILD).is_some() {
+        return true;
+    }
+    let status = process::Command::new(std::env::current_exe().unwrap())
+        .args(&["--exact", test])
+        .args(&["--test-threads", "1", "--nocapture"])
+        .env(THREAD_COUNT_CHILD, "1")
+        .status()
+        .unwrap();
+    assert!(
+        status.success(),
+        "{} failed in its own process: {}",
+        test,
+        status
+    );
+    false
+}
 
-    cfg.wait(4, servers, None); // wait for leader2 to join before killing i3
+/// the count of threads of this process, `None` if they can't be counted, except on Linux.
+fn thread_count() -> Option<usize> {
+    fs::read_dir("/proc/self/task").map(Iterator::count).ok()
+}
 
-    let i3 = (cfg.check_one_leader() + 1) % servers;
-    cfg.disconnect(i3);
-    cfg.one(Entry { x: 15 }, servers - 1, true);
-    cfg.start1(i3);
-    cfg.connect(i3);
+#[test]
+fn test_thread_count_2b() {
+    if !in_own_process("raft::tests::test_thread_count_2b") {
+        return;
+    }
+    let before = match thread_count() {
+        Some(count) => count,
+        None => return,
+    };
+    let net = labrpc::Network::new();
+    let network = thread_count().unwrap() - before;
 
-    cfg.one(Entry { x: 16 }, servers, true);
+    let servers = 5;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2B): threads of peers");
+    let mut most = 0;
+    for round in 0..10 {
+        let leader = cfg.check_one_leader();
+        let node = node_of(&cfg, leader);
+        for x in 0..20 {
+            let _ = node.start(&Entry { x: round * 100 + x });
+        }
+        cfg.one(Entry { x: round }, servers, true);
+        most = Ord::max(most, thread_count().unwrap());
+        if round == 5 {
+            // elections, and a peer restarting from its persisted state.
+            cfg.crash1(leader);
+            cfg.start1(leader);
+            cfg.connect(leader);
+        }
+    }
+    // the network of `cfg` has as many threads as `net`.
+    let spawned = most - before - 2 * network;
+    // an event loop and a persister for each peer.
+    if spawned > 2 * servers {
+        panic!("{} peers run {} threads", servers, spawned);
+    }
+    drop(net);
 
     cfg.end();
 }
 
-#[test]
-fn test_persist2_2c() {
-    let servers = 5;
-    let mut cfg = Config::new(servers, false);
+/// The requests a raft has sent by a `RecordingTransport`.
+#[derive(Default)]
+struct Sent {
+    append_entries: Vec<(usize, AppendEntriesArgs)>,
+    install_snapshot: Vec<(usize, InstallSnapshotArgs)>,
+}
 
-    cfg.begin("Test (2C): more persistence");
+impl Sent {
+    /// take the `AppendEntries` sent to `follower` since last taken.
+    fn take_append_entries(&mut self, follower: usize) -> Vec<AppendEntriesArgs> {
+        let (to, others) = self
+            .append_entries
+            .drain(..)
+            .partition::<Vec<_>, _>(|(to, _)| *to == follower);
+        self.append_entries = others;
+        to.into_iter().map(|(_, args)| args).collect()
+    }
 
-    let mut index = 1;
-    for _ in 0..5 {
-        cfg.one(Entry { x: 10 + index }, servers, true);
-        index += 1;
+    /// take the `InstallSnapshot` sent to `follower` since last taken.
+    fn take_install_snapshot(&mut self, follower: usize) -> Vec<InstallSnapshotArgs> {
+        let (to, others) = self
+            .install_snapshot
+            .drain(..)
+            .partition::<Vec<_>, _>(|(to, _)| *to == follower);
+        self.install_snapshot = others;
+        to.into_iter().map(|(_, args)| args).collect()
+    }
+}
 
-        let leader1 = cfg.check_one_leader();
+/// A transport that records the requests and never replies them,
+/// so a test drives a raft by the responses it makes up, in any order.
+struct RecordingTransport {
+    peers: usize,
+    sent: Arc<Mutex<Sent>>,
+}
 
-        cfg.disconnect((leader1 + 1) % servers);
-        cfg.disconnect((leader1 + 2) % servers);
+impl Transport for RecordingTransport {
+    fn peer_count(&self) -> usize {
+        self.peers
+    }
 
-        cfg.one(Entry { x: 10 + index }, servers - 2, true);
-        index += 1;
+    fn request_vote(&self, _: usize, _: &RequestVoteArgs) -> RpcFuture<RequestVoteReply> {
+        Box::new(future::empty())
+    }
 
-        cfg.disconnect((leader1 + 0) % servers);
-        cfg.disconnect((leader1 + 3) % servers);
-        cfg.disconnect((leader1 + 4) % servers);
+    fn append_entries(&self, to: usize, args: &AppendEntriesArgs) -> RpcFuture<AppendEntriesReply> {
+        let mut sent = self.sent.lock().unwrap();
+        sent.append_entries.push((to, args.clone()));
+        Box::new(future::empty())
+    }
 
-        cfg.start1((leader1 + 1) % servers);
-        cfg.start1((leader1 + 2) % servers);
-        cfg.connect((leader1 + 1) % servers);
-        cfg.connect((leader1 + 2) % servers);
+    fn install_snapshot(
+        &self,
+        to: usize,
+        args: &InstallSnapshotArgs,
+    ) -> RpcFuture<InstallSnapshotReply> {
+        let mut sent = self.sent.lock().unwrap();
+        sent.install_snapshot.push((to, args.clone()));
+        Box::new(future::empty())
+    }
 
-        thread::sleep(RAFT_ELECTION_TIMEOUT);
+    fn spawn(&self, _: Box<dyn Future<Item = (), Error = ()> + Send + 'static>) {}
+}
 
-        cfg.start1((leader1 + 3) % servers);
-        cfg.connect((leader1 + 3) % servers);
+/// start peer 0 of `peers` on `persister` by a `RecordingTransport`,
+/// and elect it as the leader by the votes of the others.
+fn recording_leader(
+    peers: usize,
+    persister: Box<dyn Persister>,
+    config: RaftConfig,
+) -> (Raft, Arc<Mutex<Sent>>, UnboundedReceiver<ApplyMsg>) {
+    let sent = Arc::new(Mutex::new(Sent::default()));
+    let transport = RecordingTransport {
+        peers,
+        sent: sent.clone(),
+    };
+    let (tx, apply_ch) = unbounded();
+    let log = Box::new(MemoryLog::default());
+    let mut raft = Raft::with_transport(Box::new(transport), 0, persister, tx, config, log);
+    raft.campaign();
+    for follower in 1..peers {
+        let request = raft.make_request_vote_args(false);
+        let reply = RequestVoteReply {
+            term: request.term,
+            vote_granted: true,
+        };
+        let sent_at = raft.now();
+        raft.step(Event::Message(Message::RequestVoteResponse(Response {
+            follower,
+            request,
+            reply,
+            sent_at,
+        })));
+    }
+    // it wins once its own vote is saved.
+    settle(&mut raft);
+    assert!(raft.is_leader());
+    (raft, sent, apply_ch)
+}
 
-        cfg.one(Entry { x: 10 + index }, servers - 2, true);
-        index += 1;
+/// wait for the writes of `raft` in the background, and handle the events they sent,
+/// until no write is in flight.
+fn settle(raft: &mut Raft) {
+    loop {
+        raft.persister.flush();
+        while let Ok(event) = raft.event_rx.as_ref().unwrap().try_recv() {
+            raft.step(event);
+        }
+        if raft.writes_saved >= raft.writes_issued {
+            break;
+        }
+    }
+}
 
-        cfg.connect((leader1 + 4) % servers);
-        cfg.connect((leader1 + 0) % servers);
+/// reply `request` that the leader `raft` sent to `follower` by `reply`.
+fn reply_append_entries(
+    raft: &mut Raft,
+    follower: usize,
+    request: AppendEntriesArgs,
+    reply: AppendEntriesReply,
+) {
+    let sent_at = raft.now();
+    raft.step(Event::Message(Message::AppendEntriesResponse(Response {
+        follower,
+        request,
+        reply,
+        sent_at,
+    })));
+}
+
+/// reply `request` that the leader `raft` sent to `follower` by `reply`.
+fn reply_install_snapshot(
+    raft: &mut Raft,
+    follower: usize,
+    request: InstallSnapshotArgs,
+    reply: InstallSnapshotReply,
+) {
+    let sent_at = raft.now();
+    raft.step(Event::Message(Message::InstallSnapshotResponse(Response {
+        follower,
+        request,
+        reply,
+        sent_at,
+    })));
+}
+
+fn accepted(term: u64) -> AppendEntriesReply {
+    AppendEntriesReply {
+        term,
+        success: true,
+        ..AppendEntriesReply::default()
     }
+}
 
-    cfg.one(Entry { x: 1000 }, servers, true);
+#[test]
+fn test_replication_progress_2b() {
+    // each `AppendEntries` carries one entry, and two of them can be in flight.
+    let config = RaftConfig::builder()
+        .max_inflight_append_entries(2)
+        .max_append(1, 1024)
+        .build()
+        .unwrap();
+    let (mut raft, sent, _apply_ch) = recording_leader(3, Box::new(SimplePersister::new()), config);
+    let term = raft.term;
+    let progress = |raft: &Raft, follower: usize| {
+        raft.leader_state.as_ref().unwrap().progress[follower].clone()
+    };
 
-    cfg.end();
+    // a new leader probes: one `AppendEntries` to each follower, until it's replied.
+    raft.tick();
+    let probes = sent.lock().unwrap().take_append_entries(1);
+    assert_eq!(probes.len(), 1);
+    assert_eq!(progress(&raft, 1).state, ProgressState::Probe);
+    for x in 1..=3 {
+        raft.start(vec![x]).unwrap();
+    }
+    raft.flush_proposals();
+    raft.replicate_to(1, false);
+    assert!(sent.lock().unwrap().take_append_entries(1).is_empty());
+
+    // the probe matches, then the entries are pipelined, at most two in flight.
+    let probe = probes.into_iter().next().unwrap();
+    let matched = probe.prev_log_index + probe.entries.len() as u64;
+    reply_append_entries(&mut raft, 1, probe, accepted(term));
+    raft.replicate_to(1, false);
+    raft.replicate_to(1, false);
+    let pipelined = sent.lock().unwrap().take_append_entries(1);
+    assert_eq!(pipelined.len(), 2);
+    assert_eq!(pipelined[0].prev_log_index, matched);
+    assert_eq!(pipelined[1].prev_log_index, matched + 1);
+    let p = progress(&raft, 1);
+    assert_eq!(p.state, ProgressState::Replicate);
+    assert_eq!(p.match_index, matched);
+    // `next_index` is advanced optimistically, before the entries are acknowledged.
+    assert_eq!(p.next_index, matched + 3);
+    raft.replicate_to(1, false);
+    assert!(sent.lock().unwrap().take_append_entries(1).is_empty());
+
+    // the responses arrive out of order: the later one acknowledges both, and frees the window,
+    // the earlier one doesn't move `match_index` back.
+    let mut pipelined = pipelined.into_iter();
+    let (first, second) = (pipelined.next().unwrap(), pipelined.next().unwrap());
+    reply_append_entries(&mut raft, 1, second, accepted(term));
+    assert_eq!(progress(&raft, 1).match_index, matched + 2);
+    let last = sent.lock().unwrap().take_append_entries(1);
+    assert_eq!(last.len(), 1);
+    reply_append_entries(&mut raft, 1, first.clone(), accepted(term));
+    assert_eq!(progress(&raft, 1).match_index, matched + 2);
+    // so is a stale rejection.
+    let rejected = AppendEntriesReply {
+        term,
+        success: false,
+        conflicted_term: 0,
+        conflicted_term_starts_at: matched + 1,
+    };
+    reply_append_entries(&mut raft, 1, first, rejected.clone());
+    assert_eq!(progress(&raft, 1).state, ProgressState::Replicate);
+    assert!(sent.lock().unwrap().take_append_entries(1).is_empty());
+    reply_append_entries(&mut raft, 1, last[0].clone(), accepted(term));
+    // the leader commits once its own write is saved.
+    settle(&mut raft);
+    let status = raft.status();
+    let follower = status.followers.iter().find(|f| f.id == 1).unwrap();
+    assert_eq!(follower.match_index, raft.last_log_index());
+    assert_eq!(follower.next_index, raft.last_log_index() + 1);
+    assert_eq!(status.commit_index, raft.last_log_index());
+
+    // the first entry sent to follower 2 is dropped, so it rejects the second,
+    // and the leader rolls `next_index` back, and probes again.
+    let probe = sent.lock().unwrap().take_append_entries(2).remove(0);
+    reply_append_entries(&mut raft, 2, probe, accepted(term));
+    raft.replicate_to(2, false);
+    let mut pipelined = sent.lock().unwrap().take_append_entries(2);
+    assert_eq!(pipelined.len(), 2);
+    assert_eq!(progress(&raft, 2).next_index, matched + 3);
+    reply_append_entries(&mut raft, 2, pipelined.pop().unwrap(), rejected);
+    assert_eq!(progress(&raft, 2).state, ProgressState::Probe);
+    assert_eq!(progress(&raft, 2).next_index, matched + 1);
+    let probes = sent.lock().unwrap().take_append_entries(2);
+    assert_eq!(probes.len(), 1);
+    assert_eq!(probes[0].prev_log_index, matched);
+
+    // once the entries it needs are in the snapshot, the leader sends the snapshot instead.
+    let commit_index = raft.commit_index;
+    raft.take_snapshot(SnapshotFile::default(), commit_index as usize);
+    raft.replicate_to(2, true);
+    assert_eq!(progress(&raft, 2).state, ProgressState::Snapshot);
+    assert!(sent.lock().unwrap().take_append_entries(2).is_empty());
+    let chunks = sent.lock().unwrap().take_install_snapshot(2);
+    assert_eq!(chunks.len(), 1);
+    assert!(chunks[0].done);
+    let reply = InstallSnapshotReply {
+        term,
+        next_offset: chunks[0].data.len() as u64,
+        done: true,
+    };
+    reply_install_snapshot(&mut raft, 2, chunks[0].clone(), reply);
+    let p = progress(&raft, 2);
+    assert_eq!(p.state, ProgressState::Probe);
+    assert_eq!(p.match_index, commit_index);
+    assert_eq!(p.next_index, commit_index + 1);
 }
 
 #[test]
-fn test_persist3_2c() {
+fn test_batched_concurrent_starts_2b() {
     let servers = 3;
     let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2B): batched concurrent start()s");
 
-    cfg.begin("Test (2C): partitioned leader and one follower crash, leader restarts");
+    cfg.one(Entry { x: 1 }, servers, false);
+    let leader = cfg.check_one_leader();
+    let (tx, rx) = channel();
+    let handles = (0..20)
+        .map(|x| {
+            let node = node_of(&cfg, leader);
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let started = node.start(&Entry { x: 100 + x });
+                tx.send((started, x)).unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(tx);
+    for handle in handles {
+        handle.join().unwrap();
+    }
 
-    cfg.one(Entry { x: 101 }, 3, true);
+    // every proposal gets its own index, and commits there.
+    let mut indexes = vec![];
+    for (started, x) in rx.iter() {
+        let (index, term) = started.expect("leader refused a proposal");
+        indexes.push(index);
+        if let Some(cmd) = cfg.wait(index, servers, Some(term)) {
+            if cmd.x != 100 + x {
+                panic!(
+                    "index {} committed {:?}, but {} is proposed",
+                    index,
+                    cmd,
+                    100 + x
+                );
+            }
+        }
+    }
+    indexes.sort();
+    indexes.dedup();
+    if indexes.len() != 20 {
+        panic!("proposals share indexes: {:?}", indexes);
+    }
 
-    let leader = cfg.check_one_leader();
-    cfg.disconnect((leader + 2) % servers);
+    cfg.end();
+}
 
-    cfg.one(Entry { x: 102 }, 2, true);
+fn node_of(cfg: &Config, i: usize) -> Node {
+    cfg.raft(i).unwrap()
+}
 
-    cfg.crash1((leader + 0) % servers);
-    cfg.crash1((leader + 1) % servers);
-    cfg.connect((leader + 2) % servers);
-    cfg.start1((leader + 0) % servers);
-    cfg.connect((leader + 0) % servers);
+#[test]
+fn test_status_2b() {
+    let servers = 3;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2B): status");
 
-    cfg.one(Entry { x: 103 }, 2, true);
+    let leader = cfg.check_one_leader();
+    cfg.one(Entry { x: 101 }, servers, true);
+    // let the heartbeats bring every follower's progress up to date.
+    thread::sleep(RAFT_ELECTION_TIMEOUT / 2);
 
-    cfg.start1((leader + 1) % servers);
-    cfg.connect((leader + 1) % servers);
+    let status = node_of(&cfg, leader).status();
+    assert_eq!(status.role, Role::Leader as i32);
+    assert_eq!(status.leader_id, vec![leader as u64]);
+    assert_eq!(status.commit_index, status.last_log_index);
+    assert_eq!(status.followers.len(), servers - 1);
+    for follower in &status.followers {
+        assert_eq!(follower.match_index, status.last_log_index);
+        assert_eq!(follower.next_index, status.last_log_index + 1);
+        assert_eq!(follower.last_contact_millis.len(), 1);
+    }
 
-    cfg.one(Entry { x: 104 }, servers, true);
+    let status = node_of(&cfg, (leader + 1) % servers).status();
+    assert_eq!(status.role, Role::Follower as i32);
+    assert_eq!(status.leader_id, vec![leader as u64]);
+    assert!(status.followers.is_empty());
+
+    // the status is a protobuf message, so it can be encoded and printed.
+    let mut buf = vec![];
+    labcodec::encode(&status, &mut buf).unwrap();
+    assert_eq!(labcodec::decode::<RaftStatus>(&buf).unwrap(), status);
 
     cfg.end();
 }
 
-// Test the scenarios described in Figure 8 of the extended Raft paper. Each
-// iteration asks a leader, if there is one, to insert a command in the Raft
-// log.  If there is a leader, that leader will fail quickly with a high
-// probability (perhaps without committing the command), or crash after a while
-// with low probability (most likey committing the command).  If the number of
-// alive servers isn't enough to form a majority, perhaps start a new server.
-// The leader in a new term may try to finish replicating log entries that
+#[test]
+fn test_read_index_2b() {
+    let servers = 3;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2B): read index");
+
+    let index = cfg.one(Entry { x: 101 }, servers, false);
+    let leader = cfg.check_one_leader();
+
+    let read_index = node_of(&cfg, leader)
+        .read_index()
+        .expect("leader failed to get a read index");
+    if read_index < index {
+        panic!(
+            "read index {} is behind the committed index {}",
+            read_index, index
+        );
+    }
+    assert_eq!(
+        node_of(&cfg, (leader + 1) % servers).read_index(),
+        Err(Error::NotLeader)
+    );
+
+    // the leader cannot confirm its leadership without a majority.
+    cfg.disconnect((leader + 1) % servers);
+    cfg.disconnect((leader + 2) % servers);
+    if node_of(&cfg, leader).read_index().is_ok() {
+        panic!("an isolated leader served a read index");
+    }
+
+    cfg.connect((leader + 1) % servers);
+    cfg.connect((leader + 2) % servers);
+    cfg.one(Entry { x: 102 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_propose_2b() {
+    let servers = 3;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2B): propose");
+
+    let leader = cfg.check_one_leader();
+    let proposal = node_of(&cfg, leader)
+        .propose(&Entry { x: 101 })
+        .expect("leader rejected a proposal");
+    let index = proposal.index();
+    let term = proposal.term();
+    assert_eq!(proposal.wait(), Ok((index, term)));
+    assert_eq!(cfg.wait(index, servers, None), Some(Entry { x: 101 }));
+    assert_eq!(
+        node_of(&cfg, (leader + 1) % servers)
+            .propose(&Entry { x: 102 })
+            .err(),
+        Some(Error::NotLeader)
+    );
+
+    // a proposal to an isolated leader fails once the leader steps down,
+    // for the majority has committed another entry at its index.
+    cfg.disconnect(leader);
+    let proposal = node_of(&cfg, leader)
+        .propose(&Entry { x: 103 })
+        .expect("leader rejected a proposal");
+    cfg.one(Entry { x: 104 }, servers - 1, true);
+    cfg.connect(leader);
+    match proposal.wait() {
+        Err(Error::NotLeader) | Err(Error::NotCommitted) => {}
+        result => panic!("an overwritten proposal resolved with {:?}", result),
+    }
+    cfg.one(Entry { x: 105 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_multi_raft_2b() {
+    if !in_own_process("raft::tests::test_multi_raft_2b") {
+        return;
+    }
+    let before = thread_count();
+    let stores = 3;
+    let groups = 20;
+    let net = labrpc::Network::new();
+    let network = thread_count().map(|count| count - before.unwrap());
+    let hosts = (0..stores)
+        .map(|i| {
+            let clients = (0..stores)
+                .map(|j| {
+                    let name = format!("store-{}-to-{}", i, j);
+                    let client = net.create_client(name.clone());
+                    net.connect(&name, &format!("store-{}", j));
+                    net.enable(&name, true);
+                    MultiRaftClient::new(client)
+                })
+                .collect();
+            // the half of the default heartbeat interval.
+            Host::new(i, clients, Duration::from_millis(20))
+        })
+        .collect::<Vec<_>>();
+    for (i, host) in hosts.iter().enumerate() {
+        let mut builder = labrpc::ServerBuilder::new(format!("store-{}", i));
+        add_multi_raft_service(host.clone(), &mut builder).unwrap();
+        net.add_server(builder.build());
+    }
+    let mut apply_chs = vec![];
+    let nodes = (0..groups)
+        .map(|group| {
+            hosts
+                .iter()
+                .map(|host| {
+                    let (tx, apply_ch) = unbounded();
+                    apply_chs.push(apply_ch);
+                    let raft = Raft::with_transport(
+                        host.transport(group),
+                        host.me(),
+                        Box::new(SimplePersister::new()),
+                        tx,
+                        RaftConfig::default(),
+                        Box::new(MemoryLog::default()),
+                    );
+                    host.add_group(group, raft)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    // every group elects a leader, and commits an entry.
+    let start = Instant::now();
+    for (group, peers) in nodes.iter().enumerate() {
+        loop {
+            assert!(
+                start.elapsed() < RAFT_ELECTION_TIMEOUT * 10,
+                "group {} failed to reach agreement",
+                group
+            );
+            let committed = peers
+                .iter()
+                .find(|node| node.is_leader())
+                .and_then(|leader| leader.propose(&Entry { x: group as u64 }).ok())
+                .map_or(false, |proposal| proposal.wait().is_ok());
+            if committed {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    // without coalescing, each leader would send a heartbeat to each follower every 40ms.
+    let before = net.total_count();
+    thread::sleep(Duration::from_secs(1));
+    let rpcs = net.total_count() - before;
+    let uncoalesced = groups as usize * (stores - 1) * 25;
+    assert!(
+        rpcs < uncoalesced / 2,
+        "{} rpcs in an idle second, the heartbeats aren't coalesced ({} without coalescing)",
+        rpcs,
+        uncoalesced
+    );
+    for peers in &nodes {
+        assert_eq!(
+            peers.iter().filter(|node| node.is_leader()).count(),
+            1,
+            "a group lost its leader while idle"
+        );
+    }
+
+    // an event loop and a persister for each store, whatever the count of groups.
+    if let (Some(before), Some(network), Some(after)) = (before, network, thread_count()) {
+        let spawned = after - before - network;
+        if spawned > 2 * stores {
+            panic!(
+                "{} stores of {} groups run {} threads",
+                stores, groups, spawned
+            );
+        }
+    }
+
+    for host in &hosts {
+        host.stop();
+    }
+}
+
+#[test]
+fn test_lease_read_2b() {
+    let servers = 3;
+    let mut cfg = Config::new_with_read_lease(servers, false, Some(Duration::from_millis(100)));
+    cfg.begin("Test (2B): lease read");
+
+    let index = cfg.one(Entry { x: 101 }, servers, false);
+    let leader = cfg.check_one_leader();
+    if !node_of(&cfg, leader).has_lease() {
+        panic!("leader {} doesn't hold the lease", leader);
+    }
+    if node_of(&cfg, (leader + 1) % servers).has_lease() {
+        panic!("follower {} holds the lease", (leader + 1) % servers);
+    }
+    let read_index = node_of(&cfg, leader)
+        .read_index()
+        .expect("leader failed to read by lease");
+    if read_index < index {
+        panic!(
+            "read index {} is behind the committed index {}",
+            read_index, index
+        );
+    }
+
+    // the lease expires once the leader loses contact with the majority.
+    cfg.disconnect((leader + 1) % servers);
+    cfg.disconnect((leader + 2) % servers);
+    thread::sleep(Duration::from_millis(200));
+    if node_of(&cfg, leader).has_lease() {
+        panic!("an isolated leader still holds the lease");
+    }
+    if node_of(&cfg, leader).read_index().is_ok() {
+        panic!("an isolated leader served a read index");
+    }
+
+    cfg.connect((leader + 1) % servers);
+    cfg.connect((leader + 2) % servers);
+    cfg.one(Entry { x: 102 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_leader_no_op_2b() {
+    let servers = 3;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2B): leader no-op entry");
+
+    let index = cfg.one(Entry { x: 101 }, servers, false);
+    let leader1 = cfg.check_one_leader();
+    cfg.disconnect(leader1);
+
+    // the new leader commits its no-op, and so the entries of earlier terms,
+    // then serves reads without any proposal.
+    let failover = Instant::now();
+    let read_index = loop {
+        let read_index = (0..servers)
+            .filter(|i| *i != leader1)
+            .find_map(|i| node_of(&cfg, i).read_index().ok());
+        if let Some(read_index) = read_index {
+            break read_index;
+        }
+        if failover.elapsed() > RAFT_ELECTION_TIMEOUT {
+            panic!("no leader served a read index after failover");
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    if read_index <= index {
+        panic!(
+            "read index {} doesn't cover the no-op after index {}",
+            read_index, index
+        );
+    }
+    // the no-op isn't delivered as a command.
+    let (nd, _) = cfg.n_committed(read_index);
+    if nd > 0 {
+        panic!(
+            "{} servers applied the no-op at {} as a command",
+            nd, read_index
+        );
+    }
+
+    // the old leader catches up, including the no-op.
+    cfg.one(Entry { x: 102 }, servers - 1, false);
+    cfg.connect(leader1);
+    cfg.one(Entry { x: 103 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_persist1_2c() {
+    let servers = 3;
+    let mut cfg = Config::new(servers, false);
+
+    cfg.begin("Test (2C): basic persistence");
+
+    cfg.one(Entry { x: 11 }, servers, true);
+
+    // crash and re-start all
+    for i in 0..servers {
+        cfg.start1(i);
+    }
+    for i in 0..servers {
+        cfg.disconnect(i);
+        cfg.connect(i);
+    }
+
+    cfg.one(Entry { x: 12 }, servers, true);
+
+    let leader1 = cfg.check_one_leader();
+    cfg.disconnect(leader1);
+    cfg.start1(leader1);
+    cfg.connect(leader1);
+
+    cfg.one(Entry { x: 13 }, servers, true);
+
+    let leader2 = cfg.check_one_leader();
+    cfg.disconnect(leader2);
+    let index = cfg.one(Entry { x: 14 }, servers - 1, true);
+    cfg.start1(leader2);
+    cfg.connect(leader2);
+
+    cfg.wait(index, servers, None); // wait for leader2 to join before killing i3
+
+    let i3 = (cfg.check_one_leader() + 1) % servers;
+    cfg.disconnect(i3);
+    cfg.one(Entry { x: 15 }, servers - 1, true);
+    cfg.start1(i3);
+    cfg.connect(i3);
+
+    cfg.one(Entry { x: 16 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_persist2_2c() {
+    let servers = 5;
+    let mut cfg = Config::new(servers, false);
+
+    cfg.begin("Test (2C): more persistence");
+
+    let mut index = 1;
+    for _ in 0..5 {
+        cfg.one(Entry { x: 10 + index }, servers, true);
+        index += 1;
+
+        let leader1 = cfg.check_one_leader();
+
+        cfg.disconnect((leader1 + 1) % servers);
+        cfg.disconnect((leader1 + 2) % servers);
+
+        cfg.one(Entry { x: 10 + index }, servers - 2, true);
+        index += 1;
+
+        cfg.disconnect((leader1 + 0) % servers);
+        cfg.disconnect((leader1 + 3) % servers);
+        cfg.disconnect((leader1 + 4) % servers);
+
+        cfg.start1((leader1 + 1) % servers);
+        cfg.start1((leader1 + 2) % servers);
+        cfg.connect((leader1 + 1) % servers);
+        cfg.connect((leader1 + 2) % servers);
+
+        thread::sleep(RAFT_ELECTION_TIMEOUT);
+
+        cfg.start1((leader1 + 3) % servers);
+        cfg.connect((leader1 + 3) % servers);
+
+        cfg.one(Entry { x: 10 + index }, servers - 2, true);
+        index += 1;
+
+        cfg.connect((leader1 + 4) % servers);
+        cfg.connect((leader1 + 0) % servers);
+    }
+
+    cfg.one(Entry { x: 1000 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_persist3_2c() {
+    let servers = 3;
+    let mut cfg = Config::new(servers, false);
+
+    cfg.begin("Test (2C): partitioned leader and one follower crash, leader restarts");
+
+    cfg.one(Entry { x: 101 }, 3, true);
+
+    let leader = cfg.check_one_leader();
+    cfg.disconnect((leader + 2) % servers);
+
+    cfg.one(Entry { x: 102 }, 2, true);
+
+    cfg.crash1((leader + 0) % servers);
+    cfg.crash1((leader + 1) % servers);
+    cfg.connect((leader + 2) % servers);
+    cfg.start1((leader + 0) % servers);
+    cfg.connect((leader + 0) % servers);
+
+    cfg.one(Entry { x: 103 }, 2, true);
+
+    cfg.start1((leader + 1) % servers);
+    cfg.connect((leader + 1) % servers);
+
+    cfg.one(Entry { x: 104 }, servers, true);
+
+    cfg.end();
+}
+
+// Test the scenarios described in Figure 8 of the extended Raft paper. Each
+// iteration asks a leader, if there is one, to insert a command in the Raft
+// log.  If there is a leader, that leader will fail quickly with a high
+// probability (perhaps without committing the command), or crash after a while
+// with low probability (most likey committing the command).  If the number of
+// alive servers isn't enough to form a majority, perhaps start a new server.
+// The leader in a new term may try to finish replicating log entries that
 // haven't been committed yet.
 #[test]
 fn test_figure_8_2c() {
@@ -679,12 +1748,9 @@ fn test_figure_8_2c() {
     for _iters in 0..1000 {
         let mut leader = None;
         for i in 0..servers {
-            let mut rafts = cfg.rafts.lock().unwrap();
-            if let Some(raft) = rafts.get_mut(i) {
-                if let Some(raft) = raft {
-                    if raft.start(&random_entry(&mut random)).is_ok() {
-                        leader = Some(i);
-                    }
+            if let Some(raft) = cfg.raft(i) {
+                if raft.start(&random_entry(&mut random)).is_ok() {
+                    leader = Some(i);
                 }
             }
         }
@@ -704,7 +1770,7 @@ fn test_figure_8_2c() {
 
         if nup < 3 {
             let s = random.gen::<usize>() % servers;
-            if cfg.rafts.lock().unwrap().get(s).unwrap().is_none() {
+            if cfg.raft(s).is_none() {
                 cfg.start1(s);
                 cfg.connect(s);
                 nup += 1;
@@ -713,7 +1779,7 @@ fn test_figure_8_2c() {
     }
 
     for i in 0..servers {
-        if cfg.rafts.lock().unwrap().get(i).unwrap().is_none() {
+        if cfg.raft(i).is_none() {
             cfg.start1(i);
             cfg.connect(i);
         }
@@ -785,8 +1851,8 @@ fn test_figure_8_unreliable_2c() {
         }
         let mut leader = None;
         for i in 0..servers {
-            if cfg.rafts.lock().unwrap()[i]
-                .as_ref()
+            if cfg
+                .raft(i)
                 .unwrap()
                 .start(&Entry {
                     x: random.gen::<u64>() % 10000,
@@ -856,7 +1922,7 @@ fn internal_churn(unreliable: bool) {
         me: usize,
         stop_clone: Arc<AtomicUsize>,
         tx: Sender<Option<Vec<u64>>>,
-        rafts: Arc<Mutex<Box<[Option<Node>]>>>,
+        cluster: Arc<Cluster<Node>>,
         storage: Arc<Mutex<Storage>>,
     ) {
         let mut values = vec![];
@@ -866,7 +1932,7 @@ fn internal_churn(unreliable: bool) {
             let mut index: i64 = -1;
             let mut ok = false;
             // try them all, maybe one of them is a leader
-            let rafts: Vec<_> = rafts.lock().unwrap().iter().cloned().collect();
+            let rafts: Vec<_> = (0..cluster.n).map(|i| cluster.raft(i)).collect();
             for raft in &rafts {
                 match raft {
                     Some(rf) => {
@@ -916,9 +1982,9 @@ fn internal_churn(unreliable: bool) {
         let stop_clone = stop.clone();
         let (tx, rx) = channel();
         let storage = cfg.storage.clone();
-        let rafts = cfg.rafts.clone();
+        let cluster = cfg.cluster();
         thread::spawn(move || {
-            cfn(i, stop_clone, tx, rafts, storage);
+            cfn(i, stop_clone, tx, cluster, storage);
         });
         nrec.push(rx);
     }
@@ -931,7 +1997,7 @@ fn internal_churn(unreliable: bool) {
 
         if (random.gen::<usize>() % 1000) < 500 {
             let i = random.gen::<usize>() % servers;
-            if cfg.rafts.lock().unwrap().get(i).unwrap().is_none() {
+            if cfg.raft(i).is_none() {
                 cfg.start1(i);
             }
             cfg.connect(i);
@@ -939,7 +2005,7 @@ fn internal_churn(unreliable: bool) {
 
         if (random.gen::<usize>() % 1000) < 200 {
             let i = random.gen::<usize>() % servers;
-            if cfg.rafts.lock().unwrap().get(i).unwrap().is_some() {
+            if cfg.raft(i).is_some() {
                 cfg.crash1(i);
             }
         }
@@ -954,7 +2020,7 @@ fn internal_churn(unreliable: bool) {
     thread::sleep(RAFT_ELECTION_TIMEOUT);
     cfg.net.set_reliable(true);
     for i in 0..servers {
-        if cfg.rafts.lock().unwrap().get(i).unwrap().is_none() {
+        if cfg.raft(i).is_none() {
             cfg.start1(i);
         }
         cfg.connect(i);
@@ -1000,3 +2066,1081 @@ fn test_reliable_churn_2c() {
 fn test_unreliable_churn_2c() {
     internal_churn(true);
 }
+
+/// exercise a empty `RaftLog` by 20 entries, the term of entry `i` is `i / 5 + 1`.
+fn check_raft_log(log: &mut dyn RaftLog) {
+    let entries = (1..=20u64)
+        .map(|i| LogEntry::new(vec![i as u8; 10], i / 5 + 1))
+        .collect();
+    log.append(entries);
+    assert_eq!(log.last_index(), 20);
+    assert_eq!(log.last_term(), 5);
+    assert_eq!(log.term_at(7), 2);
+    assert_eq!(log.term_at(21), 0);
+    assert_eq!(log.entry(7).unwrap().data, vec![7; 10]);
+    assert!(log.entry(21).is_none());
+    assert_eq!(log.entries(3, 5, usize::max_value()).len(), 5);
+    assert_eq!(log.entries(3, 5, 25).len(), 2);
+    // at least one entry, even if it's larger than `max_bytes`.
+    assert_eq!(log.entries(3, 5, 1).len(), 1);
+    assert_eq!(log.entries(20, 5, usize::max_value()).len(), 1);
+
+    log.truncate(16);
+    assert_eq!(log.last_index(), 15);
+    assert_eq!(log.term_at(16), 0);
+    log.append(vec![LogEntry::new(vec![16; 10], 9)]);
+    assert_eq!(log.last_index(), 16);
+    assert_eq!(log.last_term(), 9);
+
+    let term = log.term_at(10);
+    log.compact(10, term);
+    assert!(log.is_in_snapshot(10));
+    assert_eq!(log.last_included_index(), 10);
+    assert_eq!(log.term_at(10), 3);
+    assert_eq!(log.term_at(5), 3);
+    assert!(log.entry(10).is_none());
+    assert_eq!(log.entry(11).unwrap().data, vec![11; 10]);
+    assert_eq!(
+        log.entries(11, usize::max_value(), usize::max_value())
+            .len(),
+        6
+    );
+
+    // compacting at or before the snapshot changes nothing.
+    log.compact(10, 9);
+    log.compact(5, 9);
+    assert_eq!(log.last_included_index(), 10);
+    assert_eq!(log.last_included_term(), 3);
+    assert_eq!(log.entry(11).unwrap().data, vec![11; 10]);
+}
+
+#[test]
+fn test_memory_log_2c() {
+    let mut log = MemoryLog::default();
+    check_raft_log(&mut log);
+
+    log.reset(30, 7);
+    assert_eq!(log.last_index(), 30);
+    assert_eq!(log.last_term(), 7);
+    assert!(log.entry(16).is_none());
+}
+
+#[test]
+fn test_segmented_log_2c() {
+    let dir = std::env::temp_dir().join(format!("raft-segmented-log-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    // small segments, so the log spans many of them.
+    let mut log = SegmentedLog::open(&dir, 64).unwrap();
+    check_raft_log(&mut log);
+    log.sync();
+
+    // the term of the snapshot isn't stored by the log, raft restores it.
+    let mut log = SegmentedLog::open(&dir, 64).unwrap();
+    assert_eq!(log.last_index(), 16);
+    log.restore_snapshot(10, 3);
+    assert_eq!(log.last_included_index(), 10);
+    assert_eq!(log.term_at(10), 3);
+    assert_eq!(log.term_at(16), 9);
+    assert_eq!(log.entry(12).unwrap().data, vec![12; 10]);
+
+    // a torn record at the end is dropped.
+    log.append(vec![LogEntry::new(vec![17; 10], 9)]);
+    log.sync();
+    drop(log);
+    let last_segment = fs::read_dir(&dir)
+        .unwrap()
+        .map(|file| file.unwrap().path())
+        .max()
+        .unwrap();
+    OpenOptions::new()
+        .append(true)
+        .open(&last_segment)
+        .unwrap()
+        .write_all(&[1, 2, 3])
+        .unwrap();
+    let mut log = SegmentedLog::open(&dir, 64).unwrap();
+    assert_eq!(log.last_index(), 17);
+    assert_eq!(log.entry(17).unwrap().data, vec![17; 10]);
+
+    // after reset, the log restarts after the snapshot.
+    log.reset(30, 7);
+    log.append(vec![LogEntry::new(vec![31; 10], 7)]);
+    log.sync();
+    let log = SegmentedLog::open(&dir, 64).unwrap();
+    assert_eq!(log.last_included_index(), 30);
+    assert_eq!(log.last_index(), 31);
+    assert_eq!(log.term_at(31), 7);
+    drop(log);
+
+    // a damaged record isn't dropped like a torn one, opening fails.
+    let last_segment = fs::read_dir(&dir)
+        .unwrap()
+        .map(|file| file.unwrap().path())
+        .max()
+        .unwrap();
+    let mut data = fs::read(&last_segment).unwrap();
+    *data.last_mut().unwrap() ^= 1;
+    fs::write(&last_segment, data).unwrap();
+    match SegmentedLog::open(&dir, 64) {
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {}
+        Err(e) => panic!("opening a damaged log failed with {}", e),
+        Ok(_) => panic!("a damaged log is opened"),
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A persister that takes `delay` to save, the delay can be changed at any time.
+#[derive(Clone, Default)]
+struct SlowPersister {
+    inner: Arc<SimplePersister>,
+    delay: Arc<Mutex<Duration>>,
+}
+
+impl Persister for SlowPersister {
+    fn raft_state(&self) -> Vec<u8> {
+        self.inner.raft_state()
+    }
+    fn save_raft_state(&self, state: Vec<u8>) {
+        thread::sleep(*self.delay.lock().unwrap());
+        self.inner.save_raft_state(state)
+    }
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) {
+        thread::sleep(*self.delay.lock().unwrap());
+        self.inner.save_state_and_snapshot(state, snapshot)
+    }
+    fn snapshot(&self) -> Vec<u8> {
+        self.inner.snapshot()
+    }
+}
+
+/// A log that takes `delay` to sync, the delay can be changed at any time.
+struct SlowLog {
+    inner: Box<dyn RaftLog>,
+    delay: Arc<Mutex<Duration>>,
+}
+
+impl RaftLog for SlowLog {
+    fn last_included_index(&self) -> u64 {
+        self.inner.last_included_index()
+    }
+    fn last_included_term(&self) -> u64 {
+        self.inner.last_included_term()
+    }
+    fn last_index(&self) -> u64 {
+        self.inner.last_index()
+    }
+    fn term_at(&self, index: u64) -> u64 {
+        self.inner.term_at(index)
+    }
+    fn entry(&self, index: u64) -> Option<LogEntry> {
+        self.inner.entry(index)
+    }
+    fn entries(&self, start: u64, max_entries: usize, max_bytes: usize) -> Vec<LogEntry> {
+        self.inner.entries(start, max_entries, max_bytes)
+    }
+    fn append(&mut self, entries: Vec<LogEntry>) {
+        self.inner.append(entries)
+    }
+    fn truncate(&mut self, index: u64) {
+        self.inner.truncate(index)
+    }
+    fn compact(&mut self, index: u64, term: u64) {
+        self.inner.compact(index, term)
+    }
+    fn restore_snapshot(&mut self, index: u64, term: u64) {
+        self.inner.restore_snapshot(index, term)
+    }
+    fn reset(&mut self, index: u64, term: u64) {
+        self.inner.reset(index, term)
+    }
+    fn is_durable(&self) -> bool {
+        self.inner.is_durable()
+    }
+    fn stored_bytes(&self) -> usize {
+        self.inner.stored_bytes()
+    }
+    fn sync(&mut self) {
+        thread::sleep(*self.delay.lock().unwrap());
+        self.inner.sync()
+    }
+    fn sync_job(&mut self) -> Option<Box<dyn FnOnce() + Send>> {
+        let delay = *self.delay.lock().unwrap();
+        let job = self.inner.sync_job();
+        Some(Box::new(move || {
+            thread::sleep(delay);
+            if let Some(job) = job {
+                job();
+            }
+        }))
+    }
+}
+
+/// start `servers` peers made by `new_raft(i, clients, apply_ch)`, and make the writes of the leader
+/// take `delay` by `slow_down(leader, delay)`. Checks the leader commits without waiting for its
+/// own write, but counts itself in the quorum only after the write, when a follower is killed.
+/// returns the peers, the leader and the index it committed last.
+fn check_async_persist(
+    servers: usize,
+    delay: Duration,
+    new_raft: impl Fn(usize, Vec<RaftClient>, UnboundedSender<ApplyMsg>) -> Raft,
+    slow_down: impl Fn(usize, Duration),
+) -> (Vec<Node>, usize, u64) {
+    let net = labrpc::Network::new();
+    let mut apply_chs = vec![];
+    let nodes = (0..servers)
+        .map(|i| {
+            let clients = (0..servers)
+                .map(|j| {
+                    let name = format!("{}-to-{}", i, j);
+                    let client = net.create_client(name.clone());
+                    net.connect(&name, &format!("{}", j));
+                    net.enable(&name, true);
+                    RaftClient::new(client)
+                })
+                .collect();
+            let (tx, apply_ch) = unbounded();
+            apply_chs.push(apply_ch);
+            let node = Node::new(new_raft(i, clients, tx));
+            let mut builder = labrpc::ServerBuilder::new(format!("{}", i));
+            add_raft_service(node.clone(), &mut builder).unwrap();
+            net.add_server(builder.build());
+            node
+        })
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let leader = loop {
+        assert!(
+            start.elapsed() < RAFT_ELECTION_TIMEOUT * 5,
+            "failed to reach agreement"
+        );
+        let leader = nodes.iter().position(Node::is_leader);
+        let committed = leader
+            .and_then(|leader| nodes[leader].propose(&Entry { x: 1 }).ok())
+            .map_or(false, |proposal| proposal.wait().is_ok());
+        if committed {
+            break leader.unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    // the followers save the entry while the leader is saving it,
+    // so the commit doesn't wait for the slow leader.
+    slow_down(leader, delay);
+    let start = Instant::now();
+    let (index, _) = nodes[leader]
+        .propose(&Entry { x: 2 })
+        .unwrap()
+        .wait()
+        .expect("the leader failed to commit with a slow write");
+    if start.elapsed() >= delay / 2 {
+        panic!(
+            "committing took {:?}, the leader waited for its own write of {:?}",
+            start.elapsed(),
+            delay
+        );
+    }
+
+    // without a follower, the leader must count itself only after its write is saved.
+    nodes[(leader + 1) % servers].kill();
+    let start = Instant::now();
+    let (index, _) = nodes[leader]
+        .propose(&Entry { x: 3 })
+        .unwrap()
+        .wait()
+        .unwrap_or_else(|e| panic!("failed to commit the entry after {}: {:?}", index, e));
+    if start.elapsed() < delay {
+        panic!(
+            "committed in {:?} by a single follower, before the leader saved it",
+            start.elapsed()
+        );
+    }
+    (nodes, leader, index)
+}
+
+#[test]
+fn test_async_persist_2c() {
+    let servers = 3;
+    let persisters = (0..servers)
+        .map(|_| SlowPersister::default())
+        .collect::<Vec<_>>();
+    let (nodes, leader, index) = check_async_persist(
+        servers,
+        Duration::from_millis(500),
+        |i, clients, tx| Raft::new(clients, i, Box::new(persisters[i].clone()), tx),
+        |leader, delay| *persisters[leader].delay.lock().unwrap() = delay,
+    );
+    let state: PersistedStatus = labcodec::decode(&persisters[leader].raft_state()).unwrap();
+    if (state.logs.len() as u64) < index {
+        panic!(
+            "the leader committed {}, but has saved only {} entries",
+            index,
+            state.logs.len()
+        );
+    }
+
+    for node in &nodes {
+        node.kill();
+    }
+}
+
+#[test]
+fn test_async_persist_segmented_2c() {
+    let servers = 3;
+    let dirs = (0..servers)
+        .map(|i| {
+            let dir = std::env::temp_dir().join(format!(
+                "raft-async-persist-{}-{}",
+                std::process::id(),
+                i
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            dir
+        })
+        .collect::<Vec<_>>();
+    let delays = (0..servers)
+        .map(|_| Arc::new(Mutex::new(Duration::from_millis(0))))
+        .collect::<Vec<_>>();
+    // the raft state is saved at once, only the log is slow to sync.
+    let (nodes, leader, index) = check_async_persist(
+        servers,
+        Duration::from_millis(500),
+        |i, clients, tx| {
+            let log = SlowLog {
+                inner: Box::new(SegmentedLog::open(&dirs[i], 1024).unwrap()),
+                delay: delays[i].clone(),
+            };
+            Raft::with_log(
+                clients,
+                i,
+                Box::new(SimplePersister::new()),
+                tx,
+                RaftConfig::default(),
+                Box::new(log),
+            )
+        },
+        |leader, delay| *delays[leader].lock().unwrap() = delay,
+    );
+    let log = SegmentedLog::open(&dirs[leader], 1024).unwrap();
+    if log.last_index() < index {
+        panic!(
+            "the leader committed {}, but has synced only {} entries",
+            index,
+            log.last_index()
+        );
+    }
+
+    for node in &nodes {
+        node.kill();
+    }
+    for dir in &dirs {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+#[test]
+fn test_persist_faults_2c() {
+    let servers = 5;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2C): crashes during writes, and failed writes");
+    for i in 0..servers {
+        cfg.persister(i).set_write_delay(Duration::from_millis(10));
+    }
+
+    // a server crashes while it may be writing, and restarts with what it has saved.
+    let mut rng = rand::thread_rng();
+    for iters in 0..10 {
+        cfg.one(Entry { x: 10 + iters }, servers, true);
+        let i = rng.gen_range(0, servers);
+        cfg.start1(i);
+        cfg.connect(i);
+    }
+
+    // a server stops on a failed write, and catches up after restarting.
+    let leader = cfg.check_one_leader();
+    let follower = (leader + 1) % servers;
+    cfg.persister(follower).fail_writes(1);
+    cfg.one(Entry { x: 100 }, servers - 1, true);
+    cfg.start1(follower);
+    cfg.connect(follower);
+    cfg.one(Entry { x: 101 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_chunked_snapshot_2c() {
+    // two peers that have saved the snapshot to 4, in terms 2 and 3, with nothing following it.
+    let commands = vec![vec![1; 20], vec![2; 20], vec![3; 20]];
+    let save = |term: u64| {
+        let state = PersistedStatus {
+            current_term: term,
+            voted_for: vec![],
+            logs: vec![],
+            last_included_index: 4,
+            last_included_term: 2,
+        };
+        let mut snapshot = Snapshot {
+            state_machine_state: commands.clone(),
+            last_term_of_snapshot: 2,
+            last_index_of_snapshot: 4,
+            checksum: 0,
+        };
+        snapshot.checksum = snapshot.compute_checksum();
+        let (mut state_buf, mut snapshot_buf) = (vec![], vec![]);
+        labcodec::encode(&state, &mut state_buf).unwrap();
+        labcodec::encode(&snapshot, &mut snapshot_buf).unwrap();
+        let persister = SimplePersister::new();
+        persister.save_state_and_snapshot(state_buf, snapshot_buf);
+        Box::new(persister)
+    };
+    let config = || {
+        RaftConfig::builder()
+            .snapshot_chunk_bytes(16)
+            .build()
+            .unwrap()
+    };
+    let (mut old_leader, old_sent, _old_apply_ch) = recording_leader(2, save(2), config());
+    let (tx, apply_ch) = unbounded();
+    let mut follower = Raft::new(vec![], 1, Box::new(SimplePersister::new()), tx);
+
+    // the old leader finds the follower has nothing, and sends it the snapshot.
+    old_leader.tick();
+    let probe = old_sent.lock().unwrap().take_append_entries(1).remove(0);
+    let reply = follower.do_append_entries(probe.clone());
+    reply_append_entries(&mut old_leader, 1, probe, reply);
+    let mut in_flight = None;
+    for _ in 0..3 {
+        let chunk = old_sent.lock().unwrap().take_install_snapshot(1).remove(0);
+        let reply = follower.do_install_snapshot(chunk.clone());
+        assert!(!reply.done);
+        assert_eq!(reply.next_offset, chunk.offset + chunk.data.len() as u64);
+        if chunk.offset == 16 {
+            // the old leader is deposed, while its next chunk is in flight.
+            reply_install_snapshot(&mut old_leader, 1, chunk, reply);
+            in_flight = old_sent.lock().unwrap().take_install_snapshot(1).pop();
+            break;
+        }
+        reply_install_snapshot(&mut old_leader, 1, chunk, reply);
+    }
+    let received = follower.pending_snapshot.as_ref().unwrap().data.len() as u64;
+    assert_eq!(received, 32);
+
+    // the new leader sends the same snapshot from the beginning,
+    // and the follower asks it to resume from where the old leader stopped.
+    let (mut leader, sent, _leader_apply_ch) = recording_leader(2, save(3), config());
+    leader.tick();
+    let probe = sent.lock().unwrap().take_append_entries(1).remove(0);
+    let reply = follower.do_append_entries(probe.clone());
+    reply_append_entries(&mut leader, 1, probe, reply);
+    let chunk = sent.lock().unwrap().take_install_snapshot(1).remove(0);
+    assert_eq!(chunk.offset, 0);
+    let reply = follower.do_install_snapshot(chunk.clone());
+    assert_eq!((reply.next_offset, reply.done), (received, false));
+    reply_install_snapshot(&mut leader, 1, chunk, reply);
+    let resumed = sent.lock().unwrap().take_install_snapshot(1).remove(0);
+    assert_eq!(resumed.offset, received);
+
+    // the chunk of the old leader is stale, and a chunk beyond the received ones is out of order,
+    // neither of them is taken.
+    let stale = in_flight.expect("the old leader sent no chunk after the second one");
+    let reply = follower.do_install_snapshot(stale);
+    assert!(reply.term > old_leader.term && !reply.done);
+    let ahead = leader.make_install_snapshot_args(received + 16);
+    let reply = follower.do_install_snapshot(ahead);
+    assert_eq!((reply.next_offset, reply.done), (received, false));
+    let pending = follower.pending_snapshot.as_ref().unwrap();
+    assert_eq!(pending.data.len() as u64, received);
+
+    // the follower installs the snapshot after the last chunk.
+    let mut chunk = resumed;
+    loop {
+        let reply = follower.do_install_snapshot(chunk.clone());
+        let done = reply.done;
+        reply_install_snapshot(&mut leader, 1, chunk, reply);
+        if done {
+            break;
+        }
+        chunk = sent.lock().unwrap().take_install_snapshot(1).remove(0);
+    }
+    assert_eq!(follower.log.last_included_index(), 4);
+    assert_eq!(follower.log.last_included_term(), 2);
+    assert!(follower.pending_snapshot.is_none());
+    let progress = &leader.leader_state.as_ref().unwrap().progress[1];
+    assert_eq!(progress.match_index, 4);
+    drop(follower);
+    let installed = apply_ch
+        .wait()
+        .filter_map(Result::ok)
+        .find_map(|msg| match msg {
+            ApplyMsg::InstallSnapshot {
+                last_included_index: 4,
+                commands,
+                ..
+            } => Some(commands),
+            _ => None,
+        });
+    assert_eq!(installed, Some(commands));
+
+    // a snapshot damaged in transit mismatches the checksum, and isn't installed.
+    let (tx, _apply_ch) = unbounded();
+    let mut follower = Raft::new(vec![], 1, Box::new(SimplePersister::new()), tx);
+    let mut offset = 0;
+    loop {
+        let mut chunk = leader.make_install_snapshot_args(offset);
+        if offset == 16 {
+            chunk.data[0] ^= 1;
+        }
+        let reply = follower.do_install_snapshot(chunk.clone());
+        if chunk.done {
+            assert_eq!((reply.next_offset, reply.done), (0, false));
+            break;
+        }
+        offset = reply.next_offset;
+    }
+    assert_eq!(follower.log.last_included_index(), 0);
+    assert!(follower.pending_snapshot.is_none());
+}
+
+/// A persister counting how many times the snapshot is saved.
+#[derive(Clone, Default)]
+struct CountingPersister {
+    inner: Arc<SimplePersister>,
+    snapshots: Arc<AtomicUsize>,
+}
+
+impl Persister for CountingPersister {
+    fn raft_state(&self) -> Vec<u8> {
+        self.inner.raft_state()
+    }
+    fn save_raft_state(&self, state: Vec<u8>) {
+        self.inner.save_raft_state(state)
+    }
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) {
+        self.snapshots.fetch_add(1, Ordering::SeqCst);
+        self.inner.save_state_and_snapshot(state, snapshot)
+    }
+    fn snapshot(&self) -> Vec<u8> {
+        self.inner.snapshot()
+    }
+}
+
+#[test]
+fn test_encoded_snapshot_2c() {
+    // the chunks are encoded as they're written, into a `Snapshot` decoded as usual.
+    let chunks = vec![b"first".to_vec(), vec![], b"third".to_vec()];
+    let mut encoder = SnapshotEncoder::new(7, 3);
+    for chunk in chunks.clone() {
+        encoder.write(chunk);
+    }
+    let snapshot: Snapshot = labcodec::decode(&encoder.finish().data).unwrap();
+    assert!(snapshot.is_intact());
+    assert_eq!(snapshot.last_index_of_snapshot, 7);
+    assert_eq!(snapshot.last_term_of_snapshot, 3);
+    assert_eq!(snapshot.state_machine_state, chunks);
+
+    // the snapshot is saved once after it's taken, not along with every write of the raft state.
+    let persister = CountingPersister::default();
+    let (mut raft, _sent, _apply_ch) =
+        recording_leader(1, Box::new(persister.clone()), RaftConfig::default());
+    let write = |raft: &mut Raft, x: u8| {
+        raft.start(vec![x]).unwrap();
+        raft.flush_proposals();
+        settle(raft);
+    };
+    for x in 0..3 {
+        write(&mut raft, x);
+    }
+    let saved = persister.snapshots.load(Ordering::SeqCst);
+    let index = raft.last_applied;
+    assert!(index >= 3, "the entries aren't applied");
+    let mut encoder = SnapshotEncoder::new(index, raft.log.term_at(index));
+    encoder.write(b"state".to_vec());
+    raft.take_encoded_snapshot(encoder);
+    settle(&mut raft);
+    assert_eq!(raft.log.last_included_index(), index);
+    assert_eq!(persister.snapshots.load(Ordering::SeqCst), saved + 1);
+    for x in 3..6 {
+        write(&mut raft, x);
+    }
+    assert_eq!(persister.snapshots.load(Ordering::SeqCst), saved + 1);
+
+    // a snapshot of another term is refused.
+    let mut encoder = SnapshotEncoder::new(raft.last_applied, raft.term + 1);
+    encoder.write(b"forged".to_vec());
+    raft.take_encoded_snapshot(encoder);
+    assert_eq!(raft.log.last_included_index(), index);
+
+    // a restarted peer restores the snapshot that was saved.
+    drop(raft);
+    let (tx, apply_ch) = unbounded();
+    let raft = Raft::new(vec![], 0, Box::new(persister), tx);
+    assert_eq!(raft.log.last_included_index(), index);
+    drop(raft);
+    match apply_ch.wait().next() {
+        Some(Ok(ApplyMsg::InstallSnapshot {
+            last_included_index,
+            commands,
+            ..
+        })) => {
+            assert_eq!(last_included_index, index);
+            assert_eq!(commands, vec![b"state".to_vec()]);
+        }
+        msg => panic!("the snapshot isn't applied, but {:?}", msg),
+    }
+}
+
+#[test]
+fn test_torn_persist_2c() {
+    // the entries 1..=6, saved with no snapshot.
+    let state = PersistedStatus {
+        current_term: 2,
+        voted_for: vec![],
+        logs: (1..=6).map(|i| LogEntry::new(vec![i], 2).into()).collect(),
+        last_included_index: 0,
+        last_included_term: 0,
+    };
+    let mut snapshot = Snapshot {
+        state_machine_state: vec![vec![4]],
+        last_term_of_snapshot: 2,
+        last_index_of_snapshot: 4,
+        checksum: 0,
+    };
+    snapshot.checksum = snapshot.compute_checksum();
+    let save = |state: &PersistedStatus, snapshot: &Snapshot| {
+        let (mut state_buf, mut snapshot_buf) = (vec![], vec![]);
+        labcodec::encode(state, &mut state_buf).unwrap();
+        labcodec::encode(snapshot, &mut snapshot_buf).unwrap();
+        let persister = FaultyPersister::new();
+        persister.save_state_and_snapshot(state_buf, snapshot_buf);
+        persister
+    };
+
+    // the snapshot to 4 is saved, but the raft state saved with it is torn,
+    // the peer restores the snapshot, and the entries following it.
+    let (tx, apply_ch) = unbounded();
+    let raft = Raft::new(vec![], 0, Box::new(save(&state, &snapshot)), tx);
+    assert_eq!(raft.log.last_included_index(), 4);
+    assert_eq!(raft.log.last_index(), 6);
+    assert_eq!(raft.log.entry(5).unwrap().data, vec![5]);
+    drop(raft);
+    match apply_ch.wait().next() {
+        Some(Ok(ApplyMsg::InstallSnapshot {
+            last_included_index: 4,
+            ..
+        })) => {}
+        msg => panic!("the snapshot isn't applied, but {:?}", msg),
+    }
+
+    // the raft state is never saved before the snapshot it follows.
+    let mut newer = state.clone();
+    newer.last_included_index = 5;
+    newer.last_included_term = 2;
+    newer.logs.truncate(1);
+    match Raft::verify_persisted(&save(&newer, &snapshot)) {
+        Err(Error::Corrupted(_)) => {}
+        result => panic!(
+            "a state newer than the snapshot is verified as {:?}",
+            result
+        ),
+    }
+}
+
+#[test]
+fn test_checksum_2c() {
+    let entries: Vec<ProtoEntry> = (1..=3)
+        .map(|i| LogEntry::new(vec![i; 8], 1).into())
+        .collect();
+    let state = PersistedStatus {
+        current_term: 1,
+        voted_for: vec![],
+        logs: entries.clone(),
+        last_included_index: 0,
+        last_included_term: 0,
+    };
+    let mut snapshot = Snapshot {
+        state_machine_state: vec![],
+        last_term_of_snapshot: 0,
+        last_index_of_snapshot: 0,
+        checksum: 0,
+    };
+    snapshot.checksum = snapshot.compute_checksum();
+    let save = |state: &PersistedStatus, snapshot: &Snapshot| {
+        let (mut state_buf, mut snapshot_buf) = (vec![], vec![]);
+        labcodec::encode(state, &mut state_buf).unwrap();
+        labcodec::encode(snapshot, &mut snapshot_buf).unwrap();
+        let persister = SimplePersister::new();
+        persister.save_state_and_snapshot(state_buf, snapshot_buf);
+        persister
+    };
+    let is_corrupted = |persister: &SimplePersister| match Raft::verify_persisted(persister) {
+        Err(Error::Corrupted(_)) => true,
+        Err(e) => panic!("verifying failed with {:?}", e),
+        Ok(()) => false,
+    };
+    assert!(!is_corrupted(&save(&state, &snapshot)));
+
+    // a damaged entry, or a damaged snapshot.
+    let mut damaged = state.clone();
+    damaged.logs[1].command[0] ^= 1;
+    assert!(is_corrupted(&save(&damaged, &snapshot)));
+    let mut damaged_snapshot = snapshot.clone();
+    damaged_snapshot.last_index_of_snapshot = 1;
+    assert!(is_corrupted(&save(&state, &damaged_snapshot)));
+
+    // a peer refuses to start with the damaged state.
+    let persister = save(&damaged, &snapshot);
+    let started = panic::catch_unwind(AssertUnwindSafe(move || {
+        Raft::new(vec![], 0, Box::new(persister), unbounded().0)
+    }));
+    assert!(started.is_err(), "a peer starts with the damaged state");
+
+    // a follower rejects damaged entries, and accepts them sent again.
+    let (tx, _apply_ch) = unbounded();
+    let mut raft = Raft::new(vec![], 0, Box::new(SimplePersister::new()), tx);
+    let mut args = AppendEntriesArgs {
+        term: 1,
+        leader_id: 1,
+        prev_log_index: 0,
+        prev_log_term: 0,
+        entries: entries.clone(),
+        leader_commit: 0,
+        timeout_now: false,
+        up_to_date_peers: vec![],
+    };
+    args.entries[2].command[0] ^= 1;
+    assert!(!raft.do_append_entries(args.clone()).success);
+    assert_eq!(raft.log.last_index(), 0);
+    args.entries = entries;
+    assert!(raft.do_append_entries(args).success);
+    assert_eq!(raft.log.last_index(), 3);
+}
+
+#[test]
+fn test_dump_2c() {
+    let mut damaged: ProtoEntry = LogEntry::new(vec![3; 20], 2).into();
+    damaged.command[0] = 4;
+    let state = PersistedStatus {
+        current_term: 2,
+        voted_for: vec![1],
+        logs: vec![LogEntry::new(vec![1, 2], 2).into(), damaged],
+        last_included_index: 10,
+        last_included_term: 1,
+    };
+    let mut snapshot = Snapshot {
+        state_machine_state: vec![vec![]],
+        last_term_of_snapshot: 1,
+        last_index_of_snapshot: 10,
+        checksum: 0,
+    };
+    snapshot.checksum = snapshot.compute_checksum();
+    let (mut state_buf, mut snapshot_buf) = (vec![], vec![]);
+    labcodec::encode(&state, &mut state_buf).unwrap();
+    labcodec::encode(&snapshot, &mut snapshot_buf).unwrap();
+
+    let text = dump::dump(&state_buf, &snapshot_buf, &dump::Raw, dump::Format::Text).unwrap();
+    assert!(text.contains("term: 2"), "{}", text);
+    assert!(text.contains("voted for: 1"), "{}", text);
+    assert!(text.contains("11 (term 2): 2 bytes: 01 02\n"), "{}", text);
+    assert!(
+        text.contains("12 (term 2): 20 bytes: 04 03 03 03 03 03 03 03 03 03 03 03 03 03 03 03 .. [CHECKSUM MISMATCH]"),
+        "{}",
+        text
+    );
+    assert!(
+        text.contains("snapshot: to index 10 (term 1), 1 chunks"),
+        "{}",
+        text
+    );
+
+    let json = dump::dump(&state_buf, &[], &dump::Raw, dump::Format::Json).unwrap();
+    assert!(
+        json.starts_with(r#"{"raft_state":{"term":2,"voted_for":1,"entries":[{"index":1,"term":2,"checksum_ok":true"#),
+        "{}",
+        json
+    );
+    assert!(json.ends_with(r#""snapshot":null}"#), "{}", json);
+    assert!(dump::dump(&[1, 2, 3], &[], &dump::Raw, dump::Format::Text).is_err());
+}
+
+#[test]
+fn test_invariant_checker_2c() {
+    let entry = |term| LogEntry::new(vec![], term);
+    let mut checker = InvariantChecker::new(3);
+    checker.observe(0, &RaftEvent::Elected { term: 1 });
+    for peer in 0..2 {
+        checker.observe(
+            peer,
+            &RaftEvent::Appended {
+                index: 1,
+                entries: vec![entry(1), entry(1)],
+            },
+        );
+    }
+    checker.observe(0, &RaftEvent::Committed { index: 2, term: 1 });
+    checker.observe(0, &RaftEvent::Applied { index: 2 });
+    // another leader in term 1.
+    checker.observe(2, &RaftEvent::Elected { term: 1 });
+    assert!(checker.violation().unwrap().contains("Election Safety"));
+
+    let mut checker = InvariantChecker::new(3);
+    checker.observe(
+        0,
+        &RaftEvent::Appended {
+            index: 1,
+            entries: vec![entry(1), entry(1)],
+        },
+    );
+    checker.observe(0, &RaftEvent::Committed { index: 2, term: 1 });
+    checker.observe(2, &RaftEvent::Elected { term: 2 });
+    assert!(checker.violation().unwrap().contains("Leader Completeness"));
+
+    let mut checker = InvariantChecker::new(3);
+    checker.observe(
+        0,
+        &RaftEvent::Appended {
+            index: 1,
+            entries: vec![entry(1), entry(2)],
+        },
+    );
+    checker.observe(
+        1,
+        &RaftEvent::Appended {
+            index: 1,
+            entries: vec![entry(3), entry(2)],
+        },
+    );
+    assert!(checker.violation().unwrap().contains("Log Matching"));
+
+    let mut checker = InvariantChecker::new(3);
+    checker.observe(
+        0,
+        &RaftEvent::Appended {
+            index: 1,
+            entries: vec![entry(1)],
+        },
+    );
+    checker.observe(0, &RaftEvent::Committed { index: 1, term: 1 });
+    checker.observe(0, &RaftEvent::Truncated { index: 1 });
+    assert!(checker
+        .violation()
+        .unwrap()
+        .contains("State Machine Safety"));
+}
+
+#[test]
+fn test_nemesis_2c() {
+    nemesis::check(Duration::from_secs(10), || {
+        let mut cfg = Config::new(5, false);
+        cfg.begin("Test (2C): nemesis");
+        cfg
+    });
+}
+
+#[test]
+fn test_cluster_harness_2c() {
+    let servers = 3;
+    let net = labrpc::Network::new();
+    // the commands applied by each server, by their indices.
+    let applied = Arc::new(Mutex::new(vec![HashMap::new(); servers]));
+    let cluster = {
+        let (net, applied) = (net.clone(), applied.clone());
+        Cluster::new(net.clone(), servers, move |i, clients, persister| {
+            let (tx, apply_ch) = unbounded();
+            let applied = applied.clone();
+            net.spawn_poller(apply_ch.for_each(move |msg| {
+                if let ApplyMsg::Command { index, data, .. } = msg {
+                    let entry: Entry = labcodec::decode(&data).unwrap();
+                    applied.lock().unwrap()[i].insert(index, entry.x);
+                }
+                Ok(())
+            }));
+            Node::new(Raft::new(clients, i, persister, tx))
+        })
+    };
+    // start `x` on the leader of the newest term, retrying in case it has just stepped down.
+    let propose = |x: u64| loop {
+        let started = cluster
+            .leader()
+            .and_then(|leader| cluster.raft(leader))
+            .map(|rf| rf.start(&Entry { x }));
+        if let Some(Ok((index, _))) = started {
+            return index;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let wait_applied = |index: u64, x: u64, servers: &[usize]| {
+        let t0 = Instant::now();
+        while servers
+            .iter()
+            .any(|i| applied.lock().unwrap()[*i].get(&index) != Some(&x))
+        {
+            if t0.elapsed() > Duration::from_secs(5) {
+                panic!("{:?} haven't applied {} at {}", servers, x, index);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    };
+
+    let leader1 = cluster.check_one_leader();
+    wait_applied(propose(1), 1, &cluster.all());
+
+    // the leader crashes, and catches up after restarting.
+    cluster.crash(leader1);
+    let others = (0..servers).filter(|i| *i != leader1).collect::<Vec<_>>();
+    let leader2 = cluster.check_one_leader();
+    assert_ne!(leader2, leader1);
+    wait_applied(propose(2), 2, &others);
+    cluster.start(leader1);
+    cluster.connect(leader1, &cluster.all());
+    wait_applied(1, 1, &[leader1]);
+
+    // the leader is partitioned into the minority, the majority elects a new one.
+    let majority = (0..servers).filter(|i| *i != leader2).collect::<Vec<_>>();
+    cluster.partition(&[leader2], &majority);
+    let t0 = Instant::now();
+    loop {
+        match cluster.leader() {
+            Some(leader) if leader != leader2 => break,
+            _ if t0.elapsed() > RAFT_ELECTION_TIMEOUT * 2 => panic!("no leader in the majority"),
+            _ => thread::sleep(Duration::from_millis(50)),
+        }
+    }
+    let index = propose(3);
+    wait_applied(index, 3, &majority);
+    cluster.connect_all();
+    wait_applied(index, 3, &cluster.all());
+}
+
+/// A counter adding up the `x` of the entries, publishing its value to `published`.
+struct Counter {
+    value: u64,
+    snapshot: Option<u64>,
+    published: Arc<AtomicU64>,
+}
+
+impl StateMachine for Counter {
+    type Command = Entry;
+    type Output = u64;
+
+    fn apply(&mut self, command: Entry) -> u64 {
+        self.value += command.x;
+        self.published.store(self.value, Ordering::Release);
+        self.value
+    }
+
+    fn begin_snapshot(&mut self) {
+        self.snapshot = Some(self.value);
+    }
+
+    fn snapshot_chunk(&mut self, writer: &mut dyn SnapshotWriter) -> bool {
+        match self.snapshot.take() {
+            Some(x) => {
+                let mut chunk = vec![];
+                labcodec::encode(&Entry { x }, &mut chunk).unwrap();
+                writer.write(chunk);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn restore(&mut self, reader: &mut SnapshotReader<'_>) {
+        self.snapshot = None;
+        self.value = reader
+            .next()
+            .map_or(0, |chunk| labcodec::decode::<Entry>(&chunk).unwrap().x);
+        self.published.store(self.value, Ordering::Release);
+    }
+}
+
+impl testing::Server for Driver<Counter> {
+    fn raft(&self) -> Node {
+        Driver::raft(self).clone()
+    }
+
+    fn add_services(&self, builder: &mut labrpc::ServerBuilder) {
+        add_raft_service(Driver::raft(self).clone(), builder).unwrap();
+    }
+
+    fn kill(&self) {
+        self.shutdown();
+        Driver::raft(self).kill();
+    }
+}
+
+#[test]
+fn test_state_machine_driver_2c() {
+    let servers = 3;
+    let (clients, proposals) = (4, 25);
+    let net = labrpc::Network::new();
+    let published = Arc::new(
+        (0..servers)
+            .map(|_| Arc::new(AtomicU64::new(0)))
+            .collect::<Vec<_>>(),
+    );
+    let cluster = {
+        let published = published.clone();
+        Cluster::new(net, servers, move |i, peers, persister| {
+            let (tx, apply_ch) = unbounded();
+            let config = RaftConfig::builder()
+                .snapshot_threshold(Some(200))
+                .build()
+                .unwrap();
+            let rf = Node::new(Raft::with_config(peers, i, persister, tx, config));
+            let counter = Counter {
+                value: 0,
+                snapshot: None,
+                published: published[i].clone(),
+            };
+            Driver::new(counter, rf, apply_ch)
+        })
+    };
+
+    let leader = cluster.check_one_leader();
+    let lagging = (leader + 1) % servers;
+    cluster.crash(lagging);
+
+    // the clients propose concurrently, each proposal gets its own value of the counter.
+    let driver = cluster.server(leader).unwrap();
+    let handles = (0..clients)
+        .map(|_| {
+            let driver = driver.clone();
+            thread::spawn(move || {
+                (0..proposals)
+                    .filter_map(|_| driver.propose(&Entry { x: 1 }).wait().ok())
+                    .map(|output| output.expect("a command without session is never a retry"))
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut outputs = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect::<Vec<_>>();
+    outputs.sort();
+    outputs.dedup();
+    assert_eq!(
+        outputs.len(),
+        clients * proposals,
+        "some proposals failed or got the same value"
+    );
+    assert!(cluster.snapshot_size() > 0, "no snapshot has been taken");
+
+    // the lagging server catches up by the snapshot.
+    cluster.start(lagging);
+    cluster.connect(lagging, &cluster.all());
+    let value = driver.propose(&Entry { x: 1 }).wait().unwrap().unwrap();
+    assert_eq!(value, (clients * proposals) as u64 + 1);
+    let t0 = Instant::now();
+    while published
+        .iter()
+        .any(|counter| counter.load(Ordering::Acquire) != value)
+    {
+        if t0.elapsed() > Duration::from_secs(5) {
+            panic!("the counters haven't reached {}", value);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,51 @@
+//! How a raft peer talks with the other peers of its group.
+use futures::Future;
+use labrpc::RpcFuture;
+
+use crate::proto::raftpb::*;
+
+/// The rpc endpoints of all peers of a raft group, including this one.
+pub trait Transport: Send {
+    /// the number of peers, including this one.
+    fn peer_count(&self) -> usize;
+
+    fn request_vote(&self, to: usize, args: &RequestVoteArgs) -> RpcFuture<RequestVoteReply>;
+
+    fn append_entries(&self, to: usize, args: &AppendEntriesArgs) -> RpcFuture<AppendEntriesReply>;
+
+    fn install_snapshot(
+        &self,
+        to: usize,
+        args: &InstallSnapshotArgs,
+    ) -> RpcFuture<InstallSnapshotReply>;
+
+    /// spawn the future that waits for a rpc.
+    fn spawn(&self, f: Box<dyn Future<Item = (), Error = ()> + Send + 'static>);
+}
+
+/// A client to each peer, the transport of a raft that runs alone (see `Node::new`).
+impl Transport for Vec<RaftClient> {
+    fn peer_count(&self) -> usize {
+        self.len()
+    }
+
+    fn request_vote(&self, to: usize, args: &RequestVoteArgs) -> RpcFuture<RequestVoteReply> {
+        self[to].request_vote(args)
+    }
+
+    fn append_entries(&self, to: usize, args: &AppendEntriesArgs) -> RpcFuture<AppendEntriesReply> {
+        self[to].append_entries(args)
+    }
+
+    fn install_snapshot(
+        &self,
+        to: usize,
+        args: &InstallSnapshotArgs,
+    ) -> RpcFuture<InstallSnapshotReply> {
+        self[to].install_snapshot(args)
+    }
+
+    fn spawn(&self, f: Box<dyn Future<Item = (), Error = ()> + Send + 'static>) {
+        self[0].spawn(f)
+    }
+}